@@ -1,7 +1,7 @@
 use anyhow::Result;
 use clap::Parser;
 use clap_verbosity_flag::{InfoLevel, Verbosity};
-use frontend::serve_postgres;
+use frontend::{DEFAULT_SCAN_BATCH_ROWS, serve_postgres};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -10,6 +10,14 @@ struct Args {
     #[arg(long, default_value_t = 5432, value_parser = clap::value_parser!(u16).range(1024..))]
     port: u16,
 
+    /// max rows per arrow batch when scanning the tree
+    #[arg(
+        long,
+        default_value_t = DEFAULT_SCAN_BATCH_ROWS,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+    )]
+    scan_batch_rows: usize,
+
     /// logging verbosity
     #[command(flatten)]
     verbosity: Verbosity<InfoLevel>,
@@ -25,5 +33,5 @@ async fn main() -> Result<()> {
 
     log::info!("serving on port: {}", args.port);
 
-    serve_postgres(args.port).await
+    serve_postgres(args.port, args.scan_batch_rows).await
 }
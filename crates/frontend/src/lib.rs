@@ -1,23 +1,201 @@
 use anyhow::Result;
+use datafusion::execution::SessionStateBuilder;
 use datafusion::prelude::SessionContext;
 use datafusion_postgres::{ServerOptions, auth::AuthManager, serve};
+use lsm::LSMTree;
 
 use async_trait::async_trait;
-use std::{any::Any, sync::Arc};
+use std::{
+    any::Any,
+    collections::BTreeSet,
+    fmt,
+    sync::{Arc, RwLock},
+};
 
-use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::{
+    array::{
+        ArrayRef, AsArray, Int64Array, RecordBatch, RecordBatchOptions, StringArray, UInt64Array,
+    },
+    compute::{SortOptions, cast},
+    datatypes::{DataType, Field, Schema, SchemaRef},
+};
 use datafusion::{
     catalog::{Session, TableProvider},
-    datasource::TableType,
+    common::ScalarValue,
+    datasource::{
+        TableType, memory::MemorySourceConfig, source::DataSourceExec, source_as_provider,
+    },
     error::DataFusionError,
-    physical_plan::ExecutionPlan,
+    execution::{SessionState, TaskContext, context::QueryPlanner},
+    logical_expr::{
+        BinaryExpr, DmlStatement, LogicalPlan, Operator, TableProviderFilterPushDown, WriteOp,
+        expr::InList,
+    },
+    physical_expr::{EquivalenceProperties, LexOrdering, PhysicalSortExpr, expressions::col},
+    physical_plan::{
+        DisplayAs, DisplayFormatType, ExecutionPlan, Partitioning, PlanProperties,
+        SendableRecordBatchStream, collect,
+        execution_plan::{Boundedness, EmissionType},
+        stream::RecordBatchStreamAdapter,
+    },
+    physical_planner::{DefaultPhysicalPlanner, PhysicalPlanner},
     prelude::Expr,
 };
 
-#[derive(Debug, Default)]
-pub struct LSMTableProvider {}
+// memtable size for the tree backing the postgres server
+const MEMTABLE_FLUSH_THRESHOLD: usize = 1024;
+
+/// rows per arrow batch in a scan, unless the provider is told otherwise
+pub const DEFAULT_SCAN_BATCH_ROWS: usize = 8192;
+
+pub struct LSMTableProvider {
+    tree: Arc<RwLock<LSMTree>>,
+
+    // max rows in each batch a scan produces
+    batch_rows: usize,
+
+    // emit `key` as a dictionary array instead of plain strings
+    dictionary_keys: bool,
+
+    // add a `seq` column with the sequence number of each row's write
+    seq_column: bool,
+}
+
+impl LSMTableProvider {
+    #[must_use]
+    pub fn new(tree: Arc<RwLock<LSMTree>>) -> Self {
+        LSMTableProvider {
+            tree,
+            batch_rows: DEFAULT_SCAN_BATCH_ROWS,
+            dictionary_keys: false,
+            seq_column: false,
+        }
+    }
 
-impl LSMTableProvider {}
+    /// cap scans at `n` rows per batch. smaller batches mean less memory per batch and the first
+    /// rows show up sooner, bigger ones mean less per-batch overhead. 0 is treated as 1
+    #[must_use]
+    pub fn with_batch_rows(mut self, n: usize) -> Self {
+        self.batch_rows = n.max(1);
+        self
+    }
+
+    /// emit the `key` column as a `Dictionary(Int32, Utf8)` instead of plain `Utf8`
+    ///
+    /// the column type is part of the table's schema, so it has to be decided up front rather
+    /// than per batch. worth turning on when there are few distinct keys compared to the rows
+    /// that carry them downstream (joins fanning out, repeated unions, ...)
+    #[must_use]
+    pub fn with_dictionary_keys(mut self) -> Self {
+        self.dictionary_keys = true;
+        self
+    }
+
+    /// add a `seq` (`BIGINT`) column after `key` and `value`, with the sequence number of the
+    /// write that produced each row. a later write to a key always has a higher seq, so change
+    /// feed consumers can poll with `WHERE seq > <last seen>`
+    #[must_use]
+    pub fn with_seq_column(mut self) -> Self {
+        self.seq_column = true;
+        self
+    }
+
+    fn key_type(&self) -> DataType {
+        if self.dictionary_keys {
+            DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8))
+        } else {
+            DataType::Utf8
+        }
+    }
+
+    /// materializes every live row in the tree into a single batch
+    fn record_batch(&self) -> Result<RecordBatch, DataFusionError> {
+        let tree = self.tree.read().expect("lsm tree lock poisoned");
+        self.to_batch(tree.iter_live_with_seq())
+    }
+
+    /// a scan that needs no columns at all, like the one under `SELECT COUNT(*)`. the tree counts
+    /// its live keys instead of every row being materialized just to be counted. deleted keys
+    /// aren't counted
+    fn count_scan(&self) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
+        let rows = self
+            .tree
+            .read()
+            .expect("lsm tree lock poisoned")
+            .count_live();
+        let schema = Arc::new(Schema::empty());
+        let batch = RecordBatch::try_new_with_options(
+            Arc::clone(&schema),
+            vec![],
+            &RecordBatchOptions::new().with_row_count(Some(rows)),
+        )?;
+        let source = MemorySourceConfig::try_new(&[vec![batch]], schema, None)?;
+        Ok(DataSourceExec::from_data_source(source))
+    }
+
+    /// point lookups for just `keys`, skipping the ones that aren't in the tree. `keys` is a
+    /// sorted set, so the rows still come out in key order
+    fn lookup_batch(&self, keys: &BTreeSet<String>) -> Result<RecordBatch, DataFusionError> {
+        let keys: Vec<Vec<u8>> = keys.iter().map(|k| k.as_bytes().to_vec()).collect();
+        let tree = self.tree.read().expect("lsm tree lock poisoned");
+
+        // `get_with_seq` skips the backing store, so only go through it when the seq is needed
+        if self.seq_column {
+            return self.to_batch(
+                keys.into_iter()
+                    .filter_map(|k| tree.get_with_seq(&k).map(|(v, seq)| (k, v, seq))),
+            );
+        }
+        let values = tree.get_many(&keys);
+        self.to_batch(
+            keys.into_iter()
+                .zip(values)
+                .filter_map(|(k, v)| Some((k, v?, 0))),
+        )
+    }
+
+    /// builds a batch out of `(key, value, seq)` rows. the seq is dropped unless there's a `seq`
+    /// column
+    ///
+    /// a key that isn't valid utf8 fails the whole batch. the `key` column is a string, and a
+    /// lossy conversion would hand back a different key - one a `DELETE` would then tombstone
+    /// instead of the real one
+    fn to_batch(
+        &self,
+        rows: impl Iterator<Item = (Vec<u8>, Vec<u8>, u64)>,
+    ) -> Result<RecordBatch, DataFusionError> {
+        let mut keys = vec![];
+        let mut values = vec![];
+        let mut seqs = vec![];
+        for (k, v, seq) in rows {
+            let key = String::from_utf8(k).map_err(|err| {
+                DataFusionError::Execution(format!(
+                    "key {:?} is not valid utf8",
+                    String::from_utf8_lossy(err.as_bytes())
+                ))
+            })?;
+            keys.push(key);
+            values.push(String::from_utf8_lossy(&v).into_owned());
+            seqs.push(i64::try_from(seq).map_err(|e| DataFusionError::External(Box::new(e)))?);
+        }
+
+        let keys: ArrayRef = Arc::new(StringArray::from(keys));
+        let mut columns: Vec<ArrayRef> = vec![
+            cast(&keys, &self.key_type())?,
+            Arc::new(StringArray::from(values)),
+        ];
+        if self.seq_column {
+            columns.push(Arc::new(Int64Array::from(seqs)));
+        }
+        Ok(RecordBatch::try_new(self.schema(), columns)?)
+    }
+}
+
+impl fmt::Debug for LSMTableProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LSMTableProvider").finish_non_exhaustive()
+    }
+}
 
 // https://datafusion.apache.org/library-user-guide/custom-table-providers.html
 #[async_trait]
@@ -27,31 +205,297 @@ impl TableProvider for LSMTableProvider {
     }
 
     fn schema(&self) -> SchemaRef {
-        Arc::new(Schema::new(vec![
-            Field::new("key", DataType::Utf8, false),
+        let mut fields = vec![
+            Field::new("key", self.key_type(), false),
             Field::new("value", DataType::Utf8, true),
-        ]))
+        ];
+        if self.seq_column {
+            fields.push(Field::new("seq", DataType::Int64, false));
+        }
+        Arc::new(Schema::new(fields))
     }
 
     fn table_type(&self) -> TableType {
         TableType::Base
     }
 
+    /// `key = '...'` and `key IN (...)` (and ORs of them) are answered with point lookups, so
+    /// they're applied exactly. everything else is left for datafusion to filter
+    fn supports_filters_pushdown(
+        &self,
+        filters: &[&Expr],
+    ) -> Result<Vec<TableProviderFilterPushDown>, DataFusionError> {
+        Ok(filters
+            .iter()
+            .map(|filter| {
+                if point_keys(filter).is_some() {
+                    TableProviderFilterPushDown::Exact
+                } else {
+                    TableProviderFilterPushDown::Unsupported
+                }
+            })
+            .collect())
+    }
+
     async fn scan(
         &self,
         _state: &dyn Session,
-        _projection: Option<&Vec<usize>>,
-        _filters: &[Expr],
+        projection: Option<&Vec<usize>>,
+        filters: &[Expr],
         _limit: Option<usize>,
     ) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
-        todo!()
+        // every filter we get has to hold, so a row has to be in all of the key sets
+        let keys = filters
+            .iter()
+            .filter_map(point_keys)
+            .reduce(|a, b| a.intersection(&b).cloned().collect());
+        if keys.is_none() && projection.is_some_and(Vec::is_empty) {
+            return self.count_scan();
+        }
+        let batch = match keys {
+            Some(keys) => self.lookup_batch(&keys)?,
+            None => self.record_batch()?,
+        };
+        let schema = self.schema();
+
+        // slices share the underlying buffers, so this doesn't copy any rows
+        let batches: Vec<RecordBatch> = (0..batch.num_rows())
+            .step_by(self.batch_rows)
+            .map(|offset| batch.slice(offset, self.batch_rows.min(batch.num_rows() - offset)))
+            .collect();
+
+        // the tree hands rows back in key order, so tell datafusion the scan is already sorted on
+        // `key` and an `ORDER BY key` doesn't need a sort. byte order and utf8 string order are
+        // the same thing, and every key in a batch is valid utf8 (see `to_batch`)
+        let ordering = LexOrdering::new([PhysicalSortExpr::new(
+            col("key", &schema)?,
+            SortOptions {
+                descending: false,
+                nulls_first: false,
+            },
+        )]);
+
+        let source = MemorySourceConfig::try_new(&[batches], schema, projection.cloned())?
+            .try_with_sort_information(ordering.into_iter().collect())?;
+        Ok(DataSourceExec::from_data_source(source))
+    }
+}
+
+/// the keys a filter matches, if it only matches specific keys - `key = 'a'`,
+/// `key IN ('a', 'b')`, or any OR of those. datafusion rewrites short IN lists into ORs of
+/// equalities, so both shapes show up
+fn point_keys(filter: &Expr) -> Option<BTreeSet<String>> {
+    let is_key = |expr: &Expr| matches!(expr, Expr::Column(c) if c.name == "key");
+    let literal = |expr: &Expr| match expr {
+        Expr::Literal(
+            ScalarValue::Utf8(Some(s))
+            | ScalarValue::LargeUtf8(Some(s))
+            | ScalarValue::Utf8View(Some(s)),
+            _,
+        ) => Some(s.clone()),
+        _ => None,
+    };
+
+    match filter {
+        Expr::InList(InList {
+            expr,
+            list,
+            negated: false,
+        }) if is_key(expr) => list.iter().map(literal).collect(),
+        Expr::BinaryExpr(BinaryExpr {
+            left,
+            op: Operator::Eq,
+            right,
+        }) => {
+            let key = if is_key(left) {
+                literal(right)
+            } else if is_key(right) {
+                literal(left)
+            } else {
+                None
+            };
+            key.map(|k| BTreeSet::from([k]))
+        }
+        Expr::BinaryExpr(BinaryExpr {
+            left,
+            op: Operator::Or,
+            right,
+        }) => {
+            let mut keys = point_keys(left)?;
+            keys.extend(point_keys(right)?);
+            Some(keys)
+        }
+        _ => None,
+    }
+}
+
+/// plans DML against `LSMTableProvider`s, and hands everything else to the default planner
+///
+/// datafusion parses `DELETE` into a `LogicalPlan::Dml`, but the default physical planner only
+/// knows how to execute inserts. the input of a delete is a scan + filter that yields exactly the
+/// rows to remove, so we plan that normally and tombstone whatever it returns
+#[derive(Debug)]
+struct LSMQueryPlanner;
+
+#[async_trait]
+impl QueryPlanner for LSMQueryPlanner {
+    async fn create_physical_plan(
+        &self,
+        logical_plan: &LogicalPlan,
+        session_state: &SessionState,
+    ) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
+        let planner = DefaultPhysicalPlanner::default();
+
+        if let LogicalPlan::Dml(DmlStatement {
+            op: WriteOp::Delete,
+            target,
+            input,
+            ..
+        }) = logical_plan
+        {
+            let provider = source_as_provider(target)?;
+            if let Some(provider) = provider.as_any().downcast_ref::<LSMTableProvider>() {
+                let input = planner.create_physical_plan(input, session_state).await?;
+                return Ok(Arc::new(DeleteExec::new(Arc::clone(&provider.tree), input)));
+            }
+        }
+
+        planner
+            .create_physical_plan(logical_plan, session_state)
+            .await
     }
 }
 
-pub async fn serve_postgres(port: u16) -> Result<()> {
-    let session_context = Arc::new(SessionContext::new());
-    let provider = LSMTableProvider {};
+/// writes a tombstone for every row produced by `input`, and returns the number of rows deleted
+struct DeleteExec {
+    tree: Arc<RwLock<LSMTree>>,
+    input: Arc<dyn ExecutionPlan>,
+    properties: PlanProperties,
+}
+
+impl DeleteExec {
+    fn new(tree: Arc<RwLock<LSMTree>>, input: Arc<dyn ExecutionPlan>) -> Self {
+        let properties = PlanProperties::new(
+            EquivalenceProperties::new(count_schema()),
+            Partitioning::UnknownPartitioning(1),
+            EmissionType::Final,
+            Boundedness::Bounded,
+        );
+        DeleteExec {
+            tree,
+            input,
+            properties,
+        }
+    }
+}
+
+/// same shape datafusion uses for the result of an `INSERT`
+fn count_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![Field::new(
+        "count",
+        DataType::UInt64,
+        false,
+    )]))
+}
+
+impl fmt::Debug for DeleteExec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeleteExec")
+            .field("input", &self.input)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DisplayAs for DeleteExec {
+    fn fmt_as(&self, _t: DisplayFormatType, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DeleteExec")
+    }
+}
+
+impl ExecutionPlan for DeleteExec {
+    fn name(&self) -> &str {
+        "DeleteExec"
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn properties(&self) -> &PlanProperties {
+        &self.properties
+    }
+
+    fn children(&self) -> Vec<&Arc<dyn ExecutionPlan>> {
+        vec![&self.input]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
+        Ok(Arc::new(DeleteExec::new(
+            Arc::clone(&self.tree),
+            Arc::clone(&children[0]),
+        )))
+    }
+
+    fn execute(
+        &self,
+        _partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream, DataFusionError> {
+        let tree = Arc::clone(&self.tree);
+        let input = Arc::clone(&self.input);
+
+        let stream = futures::stream::once(async move {
+            let batches = collect(input, context).await?;
+
+            // no awaits past this point, so the (sync) lock is never held across a yield
+            let mut tree = tree.write().expect("lsm tree lock poisoned");
+            let mut count: u64 = 0;
+            for batch in &batches {
+                let Some(keys) = batch.column_by_name("key") else {
+                    continue;
+                };
+
+                // could be dictionary encoded (see `with_dictionary_keys`)
+                let keys = cast(keys, &DataType::Utf8)?;
+                for key in keys.as_string::<i32>().iter().flatten() {
+                    tree.delete(key.as_bytes().to_vec());
+                    count += 1;
+                }
+            }
+
+            Ok(RecordBatch::try_new(
+                count_schema(),
+                vec![Arc::new(UInt64Array::from(vec![count]))],
+            )?)
+        });
+
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            count_schema(),
+            stream,
+        )))
+    }
+}
+
+/// builds a session with `provider` registered as the `database` table
+pub fn session_context(provider: LSMTableProvider) -> Result<SessionContext> {
+    let state = SessionStateBuilder::new()
+        .with_default_features()
+        .with_query_planner(Arc::new(LSMQueryPlanner))
+        .build();
+    let session_context = SessionContext::new_with_state(state);
     session_context.register_table("database", Arc::new(provider))?;
+    Ok(session_context)
+}
+
+/// serves the tree over the postgres wire protocol, with scans producing batches of at most
+/// `scan_batch_rows` rows
+pub async fn serve_postgres(port: u16, scan_batch_rows: usize) -> Result<()> {
+    let tree = Arc::new(RwLock::new(LSMTree::new(MEMTABLE_FLUSH_THRESHOLD)));
+    let provider = LSMTableProvider::new(tree).with_batch_rows(scan_batch_rows);
+    let session_context = Arc::new(session_context(provider)?);
     let server_options = ServerOptions::new()
         .with_host("127.0.0.1".to_string())
         .with_port(port);
@@ -59,3 +503,336 @@ pub async fn serve_postgres(port: u16) -> Result<()> {
     serve(session_context, &server_options, auth_manager).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::datatypes::{Int64Type, UInt64Type};
+    use datafusion::physical_plan::displayable;
+
+    fn tree_with(rows: &[(&str, &str)]) -> Arc<RwLock<LSMTree>> {
+        let mut tree = LSMTree::new(2);
+        for (k, v) in rows {
+            tree.insert(k.as_bytes().to_vec(), Some(v.as_bytes().to_vec()));
+        }
+        Arc::new(RwLock::new(tree))
+    }
+
+    async fn keys(ctx: &SessionContext, sql: &str) -> Vec<String> {
+        let batches = ctx.sql(sql).await.unwrap().collect().await.unwrap();
+        batches
+            .iter()
+            .flat_map(|b| {
+                b.column(0)
+                    .as_string::<i32>()
+                    .iter()
+                    .flatten()
+                    .map(str::to_string)
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let tree = tree_with(&[("a", "1"), ("b", "2"), ("c", "3")]);
+        let ctx = session_context(LSMTableProvider::new(Arc::clone(&tree))).unwrap();
+
+        let batches = ctx
+            .sql("DELETE FROM database WHERE key = 'b'")
+            .await
+            .unwrap()
+            .collect()
+            .await
+            .unwrap();
+        assert_eq!(
+            batches[0].column(0).as_primitive::<UInt64Type>().value(0),
+            1
+        );
+
+        assert!(
+            keys(&ctx, "SELECT key FROM database WHERE key = 'b'")
+                .await
+                .is_empty()
+        );
+        assert_eq!(keys(&ctx, "SELECT key FROM database").await, ["a", "c"]);
+        assert_eq!(tree.read().unwrap().get(b"b"), None);
+    }
+
+    #[tokio::test]
+    async fn test_non_utf8_key() {
+        let tree = tree_with(&[("a", "1")]);
+        tree.write()
+            .unwrap()
+            .insert(vec![0xff], Some(b"2".to_vec()));
+        let ctx = session_context(LSMTableProvider::new(Arc::clone(&tree))).unwrap();
+
+        // rather than showing up as U+FFFD, which isn't the key
+        let err = ctx
+            .sql("SELECT key FROM database")
+            .await
+            .unwrap()
+            .collect()
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("not valid utf8"), "{err}");
+
+        // a delete that would have to go through it fails before anything is tombstoned
+        let result = ctx
+            .sql("DELETE FROM database WHERE key >= 'a'")
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        assert!(result.is_err());
+        assert_eq!(tree.read().unwrap().get(&[0xff]), Some(b"2".to_vec()));
+        assert_eq!(tree.read().unwrap().get(b"a"), Some(b"1".to_vec()));
+
+        // point lookups for other keys never see it
+        assert_eq!(
+            keys(&ctx, "SELECT key FROM database WHERE key = 'a'").await,
+            ["a"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_range() {
+        let tree = tree_with(&[("a", "1"), ("b", "2"), ("c", "3"), ("d", "4")]);
+        let ctx = session_context(LSMTableProvider::new(tree)).unwrap();
+
+        ctx.sql("DELETE FROM database WHERE key >= 'b' AND key < 'd'")
+            .await
+            .unwrap()
+            .collect()
+            .await
+            .unwrap();
+
+        assert_eq!(keys(&ctx, "SELECT key FROM database").await, ["a", "d"]);
+    }
+
+    #[tokio::test]
+    async fn test_order_by_key_skips_sort() {
+        let tree = tree_with(&[("c", "3"), ("a", "1"), ("d", "4"), ("b", "2"), ("e", "5")]);
+        let ctx = session_context(LSMTableProvider::new(tree)).unwrap();
+
+        let sql = "SELECT key, value FROM database ORDER BY key";
+        let plan = ctx
+            .sql(sql)
+            .await
+            .unwrap()
+            .create_physical_plan()
+            .await
+            .unwrap();
+        let plan = displayable(plan.as_ref()).indent(true).to_string();
+        assert!(!plan.contains("SortExec"), "{plan}");
+
+        assert_eq!(keys(&ctx, sql).await, ["a", "b", "c", "d", "e"]);
+    }
+
+    #[tokio::test]
+    async fn test_in_list_point_lookups() {
+        let rows: Vec<(String, String)> = (0..100)
+            .map(|i| (format!("k{i:03}"), format!("v{i}")))
+            .collect();
+        let rows: Vec<(&str, &str)> = rows.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        let tree = tree_with(&rows);
+        let ctx = session_context(LSMTableProvider::new(Arc::clone(&tree))).unwrap();
+
+        for sql in [
+            "SELECT key FROM database WHERE key IN ('k042', 'k007', 'missing', 'k099', 'k042')",
+            "SELECT key FROM database WHERE key IN ('k042', 'k007', 'missing', 'k099') \
+             ORDER BY key",
+            "SELECT key FROM database WHERE key IN ('k099', 'k007') OR key = 'k042'",
+        ] {
+            let mut found = keys(&ctx, sql).await;
+            found.sort();
+            assert_eq!(found, ["k007", "k042", "k099"], "{sql}");
+        }
+
+        // the scan itself only returns the matching rows, nothing else is filtered afterwards
+        let provider = LSMTableProvider::new(tree);
+        let filter = datafusion::prelude::col("key").in_list(
+            vec![
+                datafusion::prelude::lit("k001"),
+                datafusion::prelude::lit("k002"),
+                datafusion::prelude::lit("k003"),
+                datafusion::prelude::lit("k004"),
+                datafusion::prelude::lit("nope"),
+            ],
+            false,
+        );
+        assert_eq!(
+            provider.supports_filters_pushdown(&[&filter]).unwrap(),
+            vec![TableProviderFilterPushDown::Exact]
+        );
+        let plan = provider
+            .scan(&ctx.state(), None, &[filter], None)
+            .await
+            .unwrap();
+        let batches = collect(plan, ctx.task_ctx()).await.unwrap();
+        assert_eq!(batches.iter().map(RecordBatch::num_rows).sum::<usize>(), 4);
+
+        // anything else still goes through a full scan
+        let filter = datafusion::prelude::col("value").eq(datafusion::prelude::lit("v1"));
+        assert_eq!(
+            provider.supports_filters_pushdown(&[&filter]).unwrap(),
+            vec![TableProviderFilterPushDown::Unsupported]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scan_batch_rows() {
+        let rows: Vec<(String, String)> = (0..10)
+            .map(|i| (format!("k{i:02}"), format!("v{i}")))
+            .collect();
+        let rows: Vec<(&str, &str)> = rows.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        let tree = tree_with(&rows);
+
+        let provider = LSMTableProvider::new(Arc::clone(&tree)).with_batch_rows(3);
+        let ctx = session_context(LSMTableProvider::new(Arc::clone(&tree))).unwrap();
+        let plan = provider.scan(&ctx.state(), None, &[], None).await.unwrap();
+        let batches = collect(plan, ctx.task_ctx()).await.unwrap();
+        let sizes: Vec<usize> = batches.iter().map(RecordBatch::num_rows).collect();
+        assert_eq!(sizes, [3, 3, 3, 1]);
+
+        // still every row, in key order
+        let found: Vec<&str> = batches
+            .iter()
+            .flat_map(|b| b.column(0).as_string::<i32>().iter().flatten())
+            .collect();
+        let expected: Vec<&str> = rows.iter().map(|(k, _)| *k).collect();
+        assert_eq!(found, expected);
+
+        // and through sql, end to end
+        let ctx = session_context(LSMTableProvider::new(tree).with_batch_rows(4)).unwrap();
+        let batches = ctx
+            .sql("SELECT key FROM database")
+            .await
+            .unwrap()
+            .collect()
+            .await
+            .unwrap();
+        assert!(batches.iter().all(|b| b.num_rows() <= 4));
+        assert_eq!(batches.iter().map(RecordBatch::num_rows).sum::<usize>(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_dictionary_keys() {
+        let tree = tree_with(&[("a", "1"), ("b", "2"), ("c", "3"), ("d", "4")]);
+        let ctx = session_context(LSMTableProvider::new(Arc::clone(&tree)).with_dictionary_keys())
+            .unwrap();
+
+        let batches = ctx
+            .sql("SELECT key FROM database WHERE key <> 'c' ORDER BY key")
+            .await
+            .unwrap()
+            .collect()
+            .await
+            .unwrap();
+        let mut found = vec![];
+        for batch in &batches {
+            let column = batch.column(0);
+            assert_eq!(
+                column.data_type(),
+                &DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8))
+            );
+            let decoded = cast(column, &DataType::Utf8).unwrap();
+            found.extend(
+                decoded
+                    .as_string::<i32>()
+                    .iter()
+                    .flatten()
+                    .map(str::to_string),
+            );
+        }
+        assert_eq!(found, ["a", "b", "d"]);
+
+        // point lookups and deletes still work on the encoded column
+        ctx.sql("DELETE FROM database WHERE key IN ('a', 'b')")
+            .await
+            .unwrap()
+            .collect()
+            .await
+            .unwrap();
+        let tree = tree.read().unwrap();
+        assert_eq!(tree.iter_live().count(), 2);
+        assert_eq!(tree.get(b"a"), None);
+    }
+
+    #[tokio::test]
+    async fn test_seq_column() {
+        let tree = tree_with(&[("a", "1"), ("b", "2"), ("c", "3"), ("d", "4")]);
+        tree.write()
+            .unwrap()
+            .insert(b"b".to_vec(), Some(b"20".to_vec()));
+        let ctx =
+            session_context(LSMTableProvider::new(Arc::clone(&tree)).with_seq_column()).unwrap();
+
+        let rows = |batches: Vec<RecordBatch>| -> Vec<(String, i64)> {
+            batches
+                .iter()
+                .flat_map(|b| {
+                    let keys = b.column(0).as_string::<i32>();
+                    let seqs = b.column(1).as_primitive::<Int64Type>();
+                    (0..b.num_rows())
+                        .map(|i| (keys.value(i).to_string(), seqs.value(i)))
+                        .collect::<Vec<_>>()
+                })
+                .collect()
+        };
+
+        // the overwritten b comes out with the newest seq
+        let batches = ctx
+            .sql("SELECT key, seq FROM database ORDER BY seq")
+            .await
+            .unwrap()
+            .collect()
+            .await
+            .unwrap();
+        let found = rows(batches);
+        let keys: Vec<&str> = found.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, ["a", "c", "d", "b"]);
+        assert!(found.windows(2).all(|w| w[0].1 < w[1].1), "{found:?}");
+
+        // point lookups carry it too
+        let batches = ctx
+            .sql("SELECT key, seq FROM database WHERE key IN ('b', 'c')")
+            .await
+            .unwrap()
+            .collect()
+            .await
+            .unwrap();
+        assert_eq!(rows(batches), [("b".to_string(), 4), ("c".to_string(), 2)]);
+    }
+
+    #[tokio::test]
+    async fn test_count_excludes_deleted() {
+        let tree = tree_with(&[("a", "1"), ("b", "2"), ("c", "3"), ("d", "4"), ("e", "5")]);
+        tree.write().unwrap().delete(b"a".to_vec());
+        let ctx = session_context(LSMTableProvider::new(Arc::clone(&tree))).unwrap();
+        ctx.sql("DELETE FROM database WHERE key = 'c'")
+            .await
+            .unwrap()
+            .collect()
+            .await
+            .unwrap();
+
+        let count = |sql: &'static str| {
+            let ctx = ctx.clone();
+            async move {
+                let batches = ctx.sql(sql).await.unwrap().collect().await.unwrap();
+                batches[0].column(0).as_primitive::<Int64Type>().value(0)
+            }
+        };
+        assert_eq!(count("SELECT COUNT(*) FROM database").await, 3);
+        assert_eq!(
+            count("SELECT COUNT(*) FROM database WHERE key IN ('a', 'b')").await,
+            1
+        );
+
+        // the tombstones are down in the levels now, and still not counted
+        tree.write().unwrap().flush();
+        assert_eq!(count("SELECT COUNT(*) FROM database").await, 3);
+        assert_eq!(count("SELECT COUNT(key) FROM database").await, 3);
+    }
+}
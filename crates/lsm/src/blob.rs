@@ -0,0 +1,69 @@
+//! out-of-line storage for big values
+//!
+//! compaction copies every entry it merges, so a big value gets copied again every time its key
+//! moves down a level. with a blob threshold set, values over it are written once into a blob
+//! file when they're flushed, and the levels only carry a `BlobRef` pointing at them
+//!
+//! blob files are append-only and shared between clones of the tree. space for a value that's
+//! since been overwritten or deleted isn't reclaimed
+//!
+//! <https://www.usenix.org/system/files/conference/fast16/fast16-papers-lu.pdf>
+
+use std::borrow::Cow;
+use std::sync::Arc;
+
+use crate::Record;
+
+/// where a value lives - which blob file, and the byte range in it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct BlobRef {
+    pub(crate) id: usize,
+    pub(crate) offset: usize,
+    pub(crate) len: usize,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct BlobStore {
+    // blob id -> file contents. a file is never written to again once it's pushed
+    files: Vec<Arc<Vec<u8>>>,
+
+    // total bytes ever written, across every file
+    pub(crate) written: usize,
+}
+
+impl BlobStore {
+    /// moves the values of `records` into a single new blob file, leaving a `BlobRef` behind in
+    /// each one
+    pub(crate) fn write<'a>(&mut self, records: impl Iterator<Item = &'a mut Record>) {
+        let id = self.files.len();
+        let mut file = vec![];
+        for record in records {
+            let Some(value) = record.value.take() else {
+                continue;
+            };
+            record.blob = Some(BlobRef {
+                id,
+                offset: file.len(),
+                len: value.len(),
+            });
+            record.value = Some(vec![]);
+            file.extend_from_slice(&value);
+        }
+
+        if !file.is_empty() {
+            self.written += file.len();
+            self.files.push(Arc::new(file));
+        }
+    }
+
+    /// the record with its value read back in, if it was moved out
+    pub(crate) fn inline<'a>(&self, record: &'a Record) -> Cow<'a, Record> {
+        let Some(blob) = record.blob else {
+            return Cow::Borrowed(record);
+        };
+        let mut record = record.clone();
+        record.value = Some(self.files[blob.id][blob.offset..blob.offset + blob.len].to_vec());
+        record.blob = None;
+        Cow::Owned(record)
+    }
+}
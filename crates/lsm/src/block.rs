@@ -0,0 +1,291 @@
+//! prefix-compressed blocks
+//!
+//! a standalone encoder - the levels keep their entries as plain sorted vectors and never go
+//! through here. it's for packing a sorted run into as few bytes as possible, and for
+//! `LSMTree::estimate_compression_ratio` to size up what that would save on the tree's own data
+//!
+//! sorted keys tend to share long prefixes (think paths: `/usr/local/...`), so instead of storing
+//! every key in full we store each one as `(shared_prefix_len, suffix)` relative to the key right
+//! before it
+//!
+//! every `restart_interval` entries we store a "restart point" - a key written in full (shared
+//! prefix of 0). the offsets of the restart points are kept on the side, which lets us binary
+//! search the restart points and only decode a handful of entries linearly
+//!
+//! each entry is laid out as
+//!
+//! ```text
+//! shared (varint) | unshared (varint) | value tag (varint) | key suffix | value
+//! ```
+//!
+//! the value tag is 0 for a tombstone, which then has no value bytes at all. otherwise it's the
+//! value length plus one. deletes only cost a single byte on top of their key
+//!
+//! <https://github.com/google/leveldb/blob/main/table/block_builder.cc>
+
+use crate::Entry;
+
+/// value tag for a tombstone. anything else is the value length + 1
+const TOMBSTONE: usize = 0;
+
+pub struct PrefixBlock {
+    // encoded entries, back to back
+    buf: Vec<u8>,
+
+    // byte offset into `buf` of each restart point
+    restarts: Vec<u32>,
+
+    // number of entries in the block
+    len: usize,
+}
+
+impl PrefixBlock {
+    /// encode sorted entries into a block, writing a full key every `restart_interval` entries
+    ///
+    /// # Panics
+    ///
+    /// panics if `restart_interval` is 0, or if the block grows past 4GiB
+    #[must_use]
+    pub fn encode(entries: &[Entry], restart_interval: usize) -> Self {
+        assert!(restart_interval > 0, "restart_interval must be at least 1");
+
+        let mut buf = vec![];
+        let mut restarts = vec![];
+        let mut prev: &[u8] = &[];
+
+        for (i, (key, value)) in entries.iter().enumerate() {
+            let shared = if i % restart_interval == 0 {
+                restarts.push(u32::try_from(buf.len()).expect("block too large"));
+                0
+            } else {
+                shared_prefix_len(prev, key)
+            };
+
+            put_varint(&mut buf, shared);
+            put_varint(&mut buf, key.len() - shared);
+            put_varint(&mut buf, value.as_ref().map_or(TOMBSTONE, |v| v.len() + 1));
+            buf.extend_from_slice(&key[shared..]);
+            if let Some(value) = value {
+                buf.extend_from_slice(value);
+            }
+
+            prev = key;
+        }
+
+        PrefixBlock {
+            buf,
+            restarts,
+            len: entries.len(),
+        }
+    }
+
+    /// number of entries in the block
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// size of the block in bytes, including the restart point offsets
+    #[must_use]
+    pub fn encoded_len(&self) -> usize {
+        self.buf.len() + self.restarts.len() * size_of::<u32>()
+    }
+
+    /// look up a key. the outer `Option` is whether the key is in the block at all, the inner is
+    /// the value or a tombstone
+    ///
+    /// binary searches the restart points for the last one whose key is <= `key`, then decodes
+    /// forward from there until the next restart point
+    #[must_use]
+    pub fn get(&self, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        // restart keys are stored in full, so we can compare against them without any context
+        let idx = self
+            .restarts
+            .partition_point(|&offset| self.decode_at(offset as usize, &[]).0.as_slice() <= key);
+        if idx == 0 {
+            return None;
+        }
+
+        let mut offset = self.restarts[idx - 1] as usize;
+        let end = self
+            .restarts
+            .get(idx)
+            .map_or(self.buf.len(), |&o| o as usize);
+        let mut prev = vec![];
+
+        while offset < end {
+            let (k, value, next) = self.decode_at(offset, &prev);
+            match k.as_slice().cmp(key) {
+                std::cmp::Ordering::Less => {}
+                std::cmp::Ordering::Equal => return Some(value),
+                std::cmp::Ordering::Greater => return None,
+            }
+            prev = k;
+            offset = next;
+        }
+
+        None
+    }
+
+    /// decode every entry in the block, in order
+    #[must_use]
+    pub fn entries(&self) -> Vec<Entry> {
+        let mut entries = Vec::with_capacity(self.len);
+        let mut offset = 0;
+        let mut prev = vec![];
+
+        while offset < self.buf.len() {
+            let (key, value, next) = self.decode_at(offset, &prev);
+            prev.clone_from(&key);
+            entries.push((key, value));
+            offset = next;
+        }
+
+        entries
+    }
+
+    /// decode the entry starting at `offset`, given the previous key. returns the key, the value,
+    /// and the offset of the next entry
+    fn decode_at(&self, offset: usize, prev: &[u8]) -> (Vec<u8>, Option<Vec<u8>>, usize) {
+        let (shared, offset) = get_varint(&self.buf, offset);
+        let (unshared, offset) = get_varint(&self.buf, offset);
+        let (tag, offset) = get_varint(&self.buf, offset);
+
+        let mut key = Vec::with_capacity(shared + unshared);
+        key.extend_from_slice(&prev[..shared]);
+        key.extend_from_slice(&self.buf[offset..offset + unshared]);
+        let offset = offset + unshared;
+
+        if tag == TOMBSTONE {
+            return (key, None, offset);
+        }
+
+        let value_len = tag - 1;
+        let value = self.buf[offset..offset + value_len].to_vec();
+        (key, Some(value), offset + value_len)
+    }
+}
+
+fn shared_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+/// LEB128 - 7 bits per byte, high bit set means more bytes follow
+fn put_varint(buf: &mut Vec<u8>, mut n: usize) {
+    while n >= 0x80 {
+        // truncation is the point here, we only want the low 7 bits
+        #[allow(clippy::cast_possible_truncation)]
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    buf.push(n as u8);
+}
+
+fn get_varint(buf: &[u8], mut offset: usize) -> (usize, usize) {
+    let mut n = 0;
+    let mut shift = 0;
+    loop {
+        let byte = buf[offset];
+        offset += 1;
+        n |= usize::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return (n, offset);
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path_entries() -> Vec<Entry> {
+        (0..100)
+            .map(|i| {
+                let key = format!("/usr/local/share/doc/some-package/file-{i:04}").into_bytes();
+                let value = if i % 10 == 0 {
+                    None
+                } else {
+                    Some(format!("v{i}").into_bytes())
+                };
+                (key, value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_prefix_block_round_trip() {
+        let entries = path_entries();
+        let block = PrefixBlock::encode(&entries, 16);
+
+        assert_eq!(block.len(), entries.len());
+        assert_eq!(block.entries(), entries);
+
+        for (key, value) in &entries {
+            assert_eq!(block.get(key), Some(value.clone()));
+        }
+        assert_eq!(block.get(b"/usr"), None);
+        assert_eq!(
+            block.get(b"/usr/local/share/doc/some-package/file-0050x"),
+            None
+        );
+        assert_eq!(block.get(b"/zzz"), None);
+    }
+
+    #[test]
+    fn test_prefix_block_smaller_than_raw() {
+        let entries = path_entries();
+        let raw_len: usize = entries
+            .iter()
+            .map(|(k, v)| k.len() + v.as_ref().map_or(0, Vec::len))
+            .sum();
+
+        let block = PrefixBlock::encode(&entries, 16);
+        assert!(
+            block.encoded_len() < raw_len / 2,
+            "encoded {} raw {raw_len}",
+            block.encoded_len()
+        );
+
+        // restart_interval of 1 means every key is stored in full, so no savings on keys
+        let uncompressed = PrefixBlock::encode(&entries, 1);
+        assert!(block.encoded_len() < uncompressed.encoded_len());
+        assert_eq!(uncompressed.entries(), entries);
+    }
+
+    #[test]
+    fn test_prefix_block_tombstones() {
+        let keys: Vec<Vec<u8>> = (0..100)
+            .map(|i| format!("key-{i:04}").into_bytes())
+            .collect();
+        let values: Vec<Entry> = keys
+            .iter()
+            .map(|k| (k.clone(), Some(vec![7; 32])))
+            .collect();
+        let tombstones: Vec<Entry> = keys.iter().map(|k| (k.clone(), None)).collect();
+
+        let values = PrefixBlock::encode(&values, 16);
+        let block = PrefixBlock::encode(&tombstones, 16);
+        assert!(
+            block.encoded_len() * 4 < values.encoded_len(),
+            "tombstones {} values {}",
+            block.encoded_len(),
+            values.encoded_len()
+        );
+
+        // key lengths, the key itself, and a single byte for the tombstone
+        let uncompressed = PrefixBlock::encode(&tombstones, 1);
+        let expected: usize = keys.iter().map(|k| 2 + k.len() + 1 + 4).sum();
+        assert_eq!(uncompressed.encoded_len(), expected);
+
+        assert_eq!(block.entries(), tombstones);
+        assert_eq!(uncompressed.entries(), tombstones);
+        assert_eq!(block.get(b"key-0042"), Some(None));
+    }
+}
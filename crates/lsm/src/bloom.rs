@@ -0,0 +1,145 @@
+//! bloom filters - lets `get` skip a level without binary searching it
+//!
+//! a bloom filter answers "is this key in the set?" with either "definitely not" or "maybe". with
+//! `bits_per_key` bits per key and k = `bits_per_key` × ln 2 hash functions, the false positive
+//! rate is roughly 0.6185 ^ `bits_per_key` (~1% at 10 bits per key)
+//!
+//! <https://en.wikipedia.org/wiki/Bloom_filter>
+
+// filters never get smaller than this, so tiny levels don't have absurd false positive rates
+const MIN_BITS: usize = 64;
+
+/// seed for the key hash unless `with_seed` says otherwise. fixed, so the same keys always set
+/// the same bits and false positives are reproducible from run to run
+pub const DEFAULT_SEED: u64 = 0xbc9f_1d34;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: usize,
+    num_hashes: u32,
+    seed: u64,
+}
+
+impl BloomFilter {
+    /// builds a filter over `keys` with `bits_per_key` bits for each one
+    pub fn new<'a>(keys: impl ExactSizeIterator<Item = &'a [u8]>, bits_per_key: usize) -> Self {
+        Self::with_seed(keys, bits_per_key, DEFAULT_SEED)
+    }
+
+    /// `new`, hashing with `seed` instead of `DEFAULT_SEED`. anyone who knows the seed can pick
+    /// keys that collide, so a secret one keeps untrusted keys from flooding the filter with
+    /// false positives
+    pub fn with_seed<'a>(
+        keys: impl ExactSizeIterator<Item = &'a [u8]>,
+        bits_per_key: usize,
+        seed: u64,
+    ) -> Self {
+        let num_bits = (keys.len() * bits_per_key).max(MIN_BITS);
+
+        // k = bits_per_key × ln 2 minimizes false positives. 0.69 ≈ ln 2
+        let num_hashes = u32::try_from(bits_per_key * 69 / 100)
+            .unwrap_or(u32::MAX)
+            .clamp(1, 30);
+
+        let mut filter = BloomFilter {
+            bits: vec![0; num_bits.div_ceil(64)],
+            num_bits,
+            num_hashes,
+            seed,
+        };
+        for key in keys {
+            for bit in filter.bit_positions(key) {
+                filter.bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        filter
+    }
+
+    /// `false` means the key is definitely not in the set, `true` means it might be
+    #[must_use]
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.bit_positions(key)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// size of the filter in bits
+    #[must_use]
+    pub fn num_bits(&self) -> usize {
+        self.num_bits
+    }
+
+    /// double hashing - derive all k positions from one 64 bit hash, h1 + i × h2
+    ///
+    /// <https://www.eecs.harvard.edu/~michaelm/postscripts/rsa2008.pdf>
+    fn bit_positions(&self, key: &[u8]) -> impl Iterator<Item = usize> + use<> {
+        let hash = hash(key, self.seed);
+        let h1 = hash & 0xffff_ffff;
+        let h2 = hash >> 32;
+        let num_bits = self.num_bits as u64;
+
+        // the modulo keeps it below num_bits, which is a usize
+        #[allow(clippy::cast_possible_truncation)]
+        (0..u64::from(self.num_hashes))
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+}
+
+/// 64 bit FNV-1a, with a final avalanche step so nearby keys spread across the filter
+///
+/// written out by hand rather than using `std::hash` so the filter bits are stable across rust
+/// versions
+fn hash(key: &[u8], seed: u64) -> u64 {
+    let mut h = 0xcbf2_9ce4_8422_2325 ^ seed;
+    for &byte in key {
+        h ^= u64::from(byte);
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    // murmur3 fmix64
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter() {
+        let keys: Vec<Vec<u8>> = (0..1000).map(|i| format!("key-{i}").into_bytes()).collect();
+        let filter = BloomFilter::new(keys.iter().map(Vec::as_slice), 10);
+
+        assert_eq!(filter.num_bits(), 10_000);
+
+        // no false negatives
+        assert!(keys.iter().all(|k| filter.may_contain(k)));
+
+        // ~1% false positives at 10 bits per key, leave plenty of headroom
+        let false_positives = (0..1000)
+            .filter(|i| filter.may_contain(format!("other-{i}").as_bytes()))
+            .count();
+        assert!(false_positives < 50, "{false_positives} false positives");
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let keys: Vec<Vec<u8>> = (0..100).map(|i| format!("key-{i}").into_bytes()).collect();
+        let build = |seed| BloomFilter::with_seed(keys.iter().map(Vec::as_slice), 10, seed);
+
+        let filter = BloomFilter::new(keys.iter().map(Vec::as_slice), 10);
+        assert_eq!(filter, build(DEFAULT_SEED));
+
+        // pinned, so a change to the hash that would move the bits shows up here
+        assert_eq!(filter.bits[0], 0x6202_06c8_0805_c183);
+
+        // another seed sets other bits, but still has no false negatives
+        let seeded = build(42);
+        assert_eq!(seeded, build(42));
+        assert_ne!(seeded.bits, filter.bits);
+        assert!(keys.iter().all(|k| seeded.may_contain(k)));
+    }
+}
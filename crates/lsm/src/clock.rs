@@ -0,0 +1,28 @@
+//! time, behind a trait so tests can move it forward by hand
+
+use std::time::{Duration, Instant};
+
+pub trait Clock: Send + Sync {
+    /// time since some fixed starting point. only the difference between two readings means
+    /// anything, and it never goes backwards
+    fn now(&self) -> Duration;
+}
+
+/// the real, monotonic clock
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
@@ -0,0 +1,465 @@
+//! a tree that can be shared between threads
+
+#[cfg(feature = "async")]
+use std::sync::Arc;
+#[cfg(test)]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+
+use crate::{Clock, LSMTree, LsmError};
+
+/// an `LSMTree` behind a `RwLock` - any number of readers, or a single writer
+///
+/// every read holds the lock for its whole duration, so batching reads through `get_batch` is
+/// cheaper than calling `get` in a loop when writers are contending for the lock
+///
+/// flushes and compactions run start to finish under the write lock. a merge takes its level out
+/// of the tree while it builds the new one, but no read can get in until it's been put back, so
+/// a read never sees a level missing
+pub struct ConcurrentLSMTree {
+    tree: RwLock<LSMTree>,
+
+    // how many times the read lock has been taken, so tests can check batching actually batches
+    #[cfg(test)]
+    read_locks: AtomicUsize,
+
+    idle: Option<IdleCompaction>,
+
+    scheduled: Option<ScheduledCompaction>,
+}
+
+/// when a tree with a deferred cascade counts as idle (see `with_idle_compaction`)
+struct IdleCompaction {
+    interval: Duration,
+    clock: Box<dyn Clock>,
+
+    // clock reading at the last write
+    last_write: Mutex<Duration>,
+}
+
+/// how often to compact regardless of writes (see `with_scheduled_compaction`)
+struct ScheduledCompaction {
+    interval: Duration,
+    clock: Box<dyn Clock>,
+
+    // clock reading at the last scheduled compaction
+    last_run: Mutex<Duration>,
+}
+
+impl ConcurrentLSMTree {
+    #[must_use]
+    pub fn new(tree: LSMTree) -> Self {
+        ConcurrentLSMTree {
+            tree: RwLock::new(tree),
+            #[cfg(test)]
+            read_locks: AtomicUsize::new(0),
+            idle: None,
+            scheduled: None,
+        }
+    }
+
+    /// put off compaction until the tree has gone `interval` without a write, going by `clock`
+    ///
+    /// writes during a burst only flush into L0 (see `LSMTree::with_deferred_cascade`), so they
+    /// hold the write lock for as little as possible. the cascade into the deeper levels happens
+    /// in `compact_if_idle`, which is meant to be polled from a maintenance loop or timer
+    ///
+    /// # Panics
+    ///
+    /// if a writer panicked while holding the lock
+    #[must_use]
+    pub fn with_idle_compaction(mut self, interval: Duration, clock: impl Clock + 'static) -> Self {
+        let tree = self.tree.get_mut().expect("lsm tree lock poisoned");
+        tree.defer_cascade = true;
+        self.idle = Some(IdleCompaction {
+            interval,
+            last_write: Mutex::new(clock.now()),
+            clock: Box::new(clock),
+        });
+        self
+    }
+
+    /// runs the deferred compaction if nothing has been written for the idle interval. returns
+    /// whether the tree was idle. always `false` without `with_idle_compaction`
+    ///
+    /// # Panics
+    ///
+    /// if a writer panicked while holding the lock, same as every other method here
+    pub fn compact_if_idle(&self) -> bool {
+        let Some(idle) = &self.idle else {
+            return false;
+        };
+        let last_write = *idle.last_write.lock().expect("idle clock lock poisoned");
+        if idle.clock.now().saturating_sub(last_write) < idle.interval {
+            return false;
+        }
+        self.write().compact();
+        true
+    }
+
+    /// compact every `interval`, going by `clock`, whether or not the tree is being written to
+    ///
+    /// the compaction itself happens in `compact_if_due`, which `spawn_scheduled_compaction`
+    /// (with the `async` feature) polls from a Tokio task, or which can be polled by hand
+    #[must_use]
+    pub fn with_scheduled_compaction(
+        mut self,
+        interval: Duration,
+        clock: impl Clock + 'static,
+    ) -> Self {
+        self.scheduled = Some(ScheduledCompaction {
+            interval,
+            last_run: Mutex::new(clock.now()),
+            clock: Box::new(clock),
+        });
+        self
+    }
+
+    /// runs `LSMTree::compact` under the write lock if a full interval has passed since the last
+    /// scheduled run. returns whether it ran. always `false` without `with_scheduled_compaction`
+    ///
+    /// # Panics
+    ///
+    /// if a writer panicked while holding the lock, same as every other method here
+    pub fn compact_if_due(&self) -> bool {
+        let Some(scheduled) = &self.scheduled else {
+            return false;
+        };
+        let now = scheduled.clock.now();
+        let mut last_run = scheduled.last_run.lock().expect("schedule lock poisoned");
+        if now.saturating_sub(*last_run) < scheduled.interval {
+            return false;
+        }
+        self.write().compact();
+        *last_run = now;
+        true
+    }
+
+    /// spawns a task on the current Tokio runtime that calls `compact_if_due` once per interval,
+    /// for as long as the returned handle isn't aborted. the task keeps the tree alive
+    ///
+    /// the compaction runs on Tokio's blocking pool, holding the write lock for its duration, so
+    /// the runtime's own threads carry on with other tasks meanwhile. does nothing useful without
+    /// `with_scheduled_compaction`
+    ///
+    /// # Panics
+    ///
+    /// if called outside a Tokio runtime
+    #[cfg(feature = "async")]
+    pub fn spawn_scheduled_compaction(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let tree = Arc::clone(self);
+        tokio::spawn(async move {
+            let Some(interval) = tree.scheduled.as_ref().map(|s| s.interval) else {
+                return;
+            };
+            loop {
+                tokio::time::sleep(interval).await;
+                let tree = Arc::clone(&tree);
+                if let Err(err) = tokio::task::spawn_blocking(move || tree.compact_if_due()).await
+                    && err.is_panic()
+                {
+                    std::panic::resume_unwind(err.into_panic());
+                }
+            }
+        })
+    }
+
+    pub fn insert(&self, key: Vec<u8>, value: Option<Vec<u8>>) {
+        self.write().insert(key, value);
+        self.touch();
+    }
+
+    /// see `LSMTree::try_insert`. a write that fails doesn't count as one for
+    /// `compact_if_idle`
+    ///
+    /// # Errors
+    ///
+    /// same as `LSMTree::try_insert`
+    pub fn try_insert(&self, key: Vec<u8>, value: Option<Vec<u8>>) -> Result<(), LsmError> {
+        self.write().try_insert(key, value)?;
+        self.touch();
+        Ok(())
+    }
+
+    pub fn delete(&self, key: Vec<u8>) {
+        self.write().delete(key);
+        self.touch();
+    }
+
+    /// records a write, for `compact_if_idle`
+    fn touch(&self) {
+        if let Some(idle) = &self.idle {
+            *idle.last_write.lock().expect("idle clock lock poisoned") = idle.clock.now();
+        }
+    }
+
+    #[must_use]
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.read().get(key)
+    }
+
+    /// looks up every key under a single read lock, with results in the same order as `keys`
+    ///
+    /// see `LSMTree::get_many`
+    #[must_use]
+    pub fn get_batch(&self, keys: &[Vec<u8>]) -> Vec<Option<Vec<u8>>> {
+        self.read().get_many(keys)
+    }
+
+    /// gives back the tree, once nothing else is using it
+    ///
+    /// # Panics
+    ///
+    /// if a writer panicked while holding the lock, same as every other method here
+    #[must_use]
+    pub fn into_inner(self) -> LSMTree {
+        self.tree.into_inner().expect("lsm tree lock poisoned")
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, LSMTree> {
+        #[cfg(test)]
+        self.read_locks.fetch_add(1, Ordering::Relaxed);
+        self.tree.read().expect("lsm tree lock poisoned")
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, LSMTree> {
+        self.tree.write().expect("lsm tree lock poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StallMode;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicU64;
+
+    /// only moves when it's told to
+    #[derive(Clone, Default)]
+    struct MockClock(Arc<AtomicU64>);
+
+    impl MockClock {
+        fn advance(&self, ms: u64) {
+            self.0.fetch_add(ms, Ordering::Relaxed);
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Duration {
+            Duration::from_millis(self.0.load(Ordering::Relaxed))
+        }
+    }
+
+    #[test]
+    fn test_idle_compaction() {
+        let clock = MockClock::default();
+        let tree = ConcurrentLSMTree::new(LSMTree::new(2))
+            .with_idle_compaction(Duration::from_millis(100), clock.clone());
+
+        // a burst - everything piles up in L0, well past its capacity of 2
+        for i in 0..10u32 {
+            tree.insert(i.to_be_bytes().to_vec(), Some(vec![1]));
+            clock.advance(10);
+        }
+        assert_eq!(tree.read().populated, vec![0]);
+        assert_eq!(tree.read().levels[0].as_ref().unwrap().len(), 10);
+
+        // not idle for long enough yet
+        clock.advance(50);
+        assert!(!tree.compact_if_idle());
+        assert_eq!(tree.read().populated, vec![0]);
+
+        // a write resets the wait
+        tree.delete(3u32.to_be_bytes().to_vec());
+        clock.advance(99);
+        assert!(!tree.compact_if_idle());
+
+        clock.advance(1);
+        assert!(tree.compact_if_idle());
+        let populated = tree.read().populated.clone();
+        assert!(!populated.contains(&0), "{populated:?}");
+        assert_eq!(tree.read().iter_live().count(), 9);
+        assert_eq!(tree.get(&3u32.to_be_bytes()), None);
+
+        // and flushes still stop at L0 afterwards
+        for i in 10..14u32 {
+            tree.insert(i.to_be_bytes().to_vec(), Some(vec![1]));
+        }
+        assert_eq!(tree.read().levels[0].as_ref().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_write_stall() {
+        let clock = MockClock::default();
+        let tree = ConcurrentLSMTree::new(LSMTree::new(2).with_write_stall(8, StallMode::Error))
+            .with_idle_compaction(Duration::from_millis(100), clock.clone());
+
+        // a flood of writes never lets the tree go idle, so nothing compacts and L0 hits the
+        // limit
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        let mut written = 0;
+        while tree.try_insert(key(written), Some(vec![1])).is_ok() {
+            written += 1;
+            assert!(written < 100, "never stalled");
+        }
+        assert!(matches!(
+            tree.try_insert(key(written), Some(vec![1])),
+            Err(LsmError::WouldStall)
+        ));
+        assert_eq!(tree.read().populated, vec![0]);
+
+        // once the writer backs off, idle compaction catches up and writes go through again
+        clock.advance(100);
+        assert!(tree.compact_if_idle());
+        tree.try_insert(key(written), Some(vec![1])).unwrap();
+        assert_eq!(tree.read().iter_live().count(), 9);
+    }
+
+    #[test]
+    fn test_scheduled_compaction() {
+        let clock = MockClock::default();
+        let tree = ConcurrentLSMTree::new(LSMTree::new(2).with_deferred_cascade())
+            .with_scheduled_compaction(Duration::from_millis(100), clock.clone());
+
+        for round in 0..3u32 {
+            // writes pile up in L0 past its capacity, and nothing moves them until the tick
+            for i in 0..10u32 {
+                tree.insert((round * 10 + i).to_be_bytes().to_vec(), Some(vec![1]));
+            }
+            assert!(tree.read().populated.contains(&0));
+
+            clock.advance(50);
+            assert!(!tree.compact_if_due());
+            clock.advance(49);
+            assert!(!tree.compact_if_due());
+            assert!(tree.read().populated.contains(&0));
+
+            // unlike idle compaction, writes don't push the tick back
+            tree.insert(vec![0xff], Some(vec![1]));
+            clock.advance(1);
+            assert!(tree.compact_if_due());
+            let populated = tree.read().populated.clone();
+            assert!(!populated.contains(&0), "{populated:?}");
+
+            // the next tick is a full interval after this one
+            assert!(!tree.compact_if_due());
+        }
+        assert_eq!(tree.read().iter_live().count(), 31);
+
+        let unscheduled = ConcurrentLSMTree::new(LSMTree::new(2));
+        clock.advance(1000);
+        assert!(!unscheduled.compact_if_due());
+    }
+
+    /// the system clock, noting which threads read it
+    #[cfg(feature = "async")]
+    struct Watched {
+        clock: crate::SystemClock,
+        readers: Arc<std::sync::Mutex<Vec<std::thread::ThreadId>>>,
+    }
+
+    #[cfg(feature = "async")]
+    impl Clock for Watched {
+        fn now(&self) -> Duration {
+            self.readers
+                .lock()
+                .unwrap()
+                .push(std::thread::current().id());
+            self.clock.now()
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_spawn_scheduled_compaction() {
+        let readers = Arc::default();
+        let clock = Watched {
+            clock: crate::SystemClock::default(),
+            readers: Arc::clone(&readers),
+        };
+        let tree = Arc::new(
+            ConcurrentLSMTree::new(LSMTree::new(2).with_deferred_cascade())
+                .with_scheduled_compaction(Duration::from_millis(10), clock),
+        );
+        readers.lock().unwrap().clear();
+        for i in 0..10u32 {
+            tree.insert(i.to_be_bytes().to_vec(), Some(vec![1]));
+        }
+        assert!(tree.read().populated.contains(&0));
+
+        let handle = tree.spawn_scheduled_compaction();
+        for _ in 0..200 {
+            if !tree.read().populated.contains(&0) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        handle.abort();
+        assert!(!tree.read().populated.contains(&0));
+        assert_eq!(tree.read().iter_live().count(), 10);
+
+        // the test's runtime only has this thread, and none of the compactions ran on it
+        let readers = readers.lock().unwrap();
+        assert!(!readers.is_empty());
+        assert!(!readers.contains(&std::thread::current().id()));
+    }
+
+    #[test]
+    fn test_reads_during_compaction() {
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        let tree = ConcurrentLSMTree::new(LSMTree::new(4).with_deferred_cascade());
+        for i in 0..200u32 {
+            tree.insert(key(i), Some(vec![1]));
+        }
+        let done = std::sync::atomic::AtomicBool::new(false);
+
+        std::thread::scope(|s| {
+            // every flush and cascade takes levels apart and puts them back while these look
+            for _ in 0..4 {
+                s.spawn(|| {
+                    while !done.load(Ordering::Relaxed) {
+                        for i in 0..200u32 {
+                            assert_eq!(tree.get(&key(i)), Some(vec![1]), "missed {i}");
+                        }
+                    }
+                });
+            }
+
+            for i in 200..1200u32 {
+                tree.insert(key(i), Some(vec![1]));
+                if i % 50 == 0 {
+                    tree.write().compact();
+                }
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+
+        assert_eq!(tree.read().iter_live().count(), 1200);
+    }
+
+    #[test]
+    fn test_get_batch() {
+        let tree = ConcurrentLSMTree::new(LSMTree::new(4));
+        for i in 0..20u32 {
+            tree.insert(i.to_be_bytes().to_vec(), Some(i.to_le_bytes().to_vec()));
+        }
+        tree.delete(3u32.to_be_bytes().to_vec());
+
+        let keys: Vec<Vec<u8>> = [7u32, 3, 100, 0, 19, 7]
+            .iter()
+            .map(|i| i.to_be_bytes().to_vec())
+            .collect();
+        let expected: Vec<_> = keys.iter().map(|k| tree.get(k)).collect();
+
+        let before = tree.read_locks.load(Ordering::Relaxed);
+        let results = tree.get_batch(&keys);
+        assert_eq!(tree.read_locks.load(Ordering::Relaxed), before + 1);
+
+        assert_eq!(results, expected);
+        assert_eq!(results[0], Some(7u32.to_le_bytes().to_vec()));
+        assert_eq!(results[1], None);
+        assert_eq!(results[2], None);
+        assert_eq!(results[5], results[0]);
+    }
+}
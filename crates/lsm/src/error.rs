@@ -0,0 +1,103 @@
+use std::{error, fmt, io};
+
+/// everything that can go wrong in the tree
+///
+/// the in-memory API stays infallible (`insert`, `get`, ...). this is for the paths that can
+/// actually fail - validating config, and anything touching I/O
+#[derive(Debug)]
+pub enum LsmError {
+    Io(io::Error),
+
+    /// data we read back doesn't match what we expect to have written
+    Corruption(String),
+
+    /// a file was written in a format version this build doesn't know how to read
+    UnsupportedVersion(u8),
+
+    /// a caller-supplied argument or config value is out of range
+    InvalidArgument(String),
+
+    /// a write was attempted on a tree that only allows reads (see `LSMTree::set_read_only`)
+    ReadOnly,
+
+    /// a write was rejected because compaction is too far behind
+    WouldStall,
+
+    /// a compaction panicked part way through. the levels were rolled back, but the tree won't
+    /// take any more writes
+    Poisoned,
+}
+
+impl fmt::Display for LsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LsmError::Io(err) => write!(f, "io error: {err}"),
+            LsmError::Corruption(msg) => write!(f, "corruption: {msg}"),
+            LsmError::UnsupportedVersion(v) => write!(f, "unsupported format version {v}"),
+            LsmError::InvalidArgument(msg) => write!(f, "invalid argument: {msg}"),
+            LsmError::ReadOnly => write!(f, "tree is read-only"),
+            LsmError::WouldStall => write!(f, "write would stall, compaction is behind"),
+            LsmError::Poisoned => write!(f, "tree is poisoned, a compaction panicked"),
+        }
+    }
+}
+
+impl error::Error for LsmError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            LsmError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LsmError {
+    fn from(err: io::Error) -> Self {
+        LsmError::Io(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn test_io_error_conversion() {
+        fn read() -> Result<(), LsmError> {
+            Err(io::Error::new(io::ErrorKind::NotFound, "missing level"))?;
+            Ok(())
+        }
+
+        let err = read().unwrap_err();
+        assert!(matches!(&err, LsmError::Io(e) if e.kind() == io::ErrorKind::NotFound));
+        assert!(err.source().is_some());
+        assert_eq!(err.to_string(), "io error: missing level");
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            LsmError::Corruption("bad checksum".to_string()).to_string(),
+            "corruption: bad checksum"
+        );
+        assert_eq!(
+            LsmError::InvalidArgument("threshold".to_string()).to_string(),
+            "invalid argument: threshold"
+        );
+        assert_eq!(
+            LsmError::UnsupportedVersion(9).to_string(),
+            "unsupported format version 9"
+        );
+        assert_eq!(LsmError::ReadOnly.to_string(), "tree is read-only");
+        assert_eq!(
+            LsmError::WouldStall.to_string(),
+            "write would stall, compaction is behind"
+        );
+        assert_eq!(
+            LsmError::Poisoned.to_string(),
+            "tree is poisoned, a compaction panicked"
+        );
+        assert!(LsmError::ReadOnly.source().is_none());
+    }
+}
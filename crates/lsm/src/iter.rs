@@ -0,0 +1,191 @@
+//! k-way merge over the memtable and levels
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::iter::Peekable;
+
+use crate::blob::BlobStore;
+use crate::{Comparator, Record, resolve};
+
+/// a key and its record, borrowed from wherever it's stored
+pub(crate) type EntryRef<'a> = (&'a [u8], &'a Record);
+
+type Source<'a> = Box<dyn Iterator<Item = EntryRef<'a>> + Send + 'a>;
+
+/// the next entry from one of `MergeIter`'s sources
+struct Head<'a> {
+    key: &'a [u8],
+    record: &'a Record,
+    source: usize,
+    cmp: &'a dyn Comparator,
+}
+
+// `BinaryHeap` pops the biggest, so the smallest key is the biggest here. among versions of a key
+// the highest seq comes first, then the newest source - the same order `LSMTree::versions` puts
+// them in
+impl Ord for Head<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.cmp
+            .compare(other.key, self.key)
+            .then(self.record.seq.cmp(&other.record.seq))
+            .then(other.source.cmp(&self.source))
+    }
+}
+
+impl PartialOrd for Head<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Head<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Head<'_> {}
+
+/// merges several sorted sources into a single sorted stream
+///
+/// sources are ordered newest first (memtable, then L0, L1, ...), and within a source a key's
+/// versions are newest first too. the version with the highest seq wins and the older ones are
+/// skipped, so every key comes out exactly once, even if two sources overlap in time. tombstones
+/// are passed through - it's up to the caller whether to drop them
+///
+/// when the newest version is an append operand, the older versions get folded into it instead
+/// of skipped, so what comes out is always the full value. values in blob files are read back in
+pub(crate) struct MergeIter<'a> {
+    sources: Vec<Source<'a>>,
+
+    // each source's next entry, so the top is the next version to come out
+    heap: BinaryHeap<Head<'a>>,
+    cmp: &'a dyn Comparator,
+    blobs: &'a BlobStore,
+}
+
+impl<'a> MergeIter<'a> {
+    pub(crate) fn new(
+        sources: Vec<Box<dyn Iterator<Item = EntryRef<'a>> + Send + 'a>>,
+        cmp: &'a dyn Comparator,
+        blobs: &'a BlobStore,
+    ) -> Self {
+        let mut iter = MergeIter {
+            heap: BinaryHeap::with_capacity(sources.len()),
+            sources,
+            cmp,
+            blobs,
+        };
+        for source in 0..iter.sources.len() {
+            iter.advance(source);
+        }
+        iter
+    }
+
+    /// moves `source`'s next entry onto the heap
+    fn advance(&mut self, source: usize) {
+        if let Some((key, record)) = self.sources[source].next() {
+            self.heap.push(Head {
+                key,
+                record,
+                source,
+                cmp: self.cmp,
+            });
+        }
+    }
+
+    /// the next version of `key`, if there are any left
+    fn pop_version(&mut self, key: &[u8]) -> Option<&'a Record> {
+        if self.heap.peek()?.key != key {
+            return None;
+        }
+        let head = self.heap.pop()?;
+        self.advance(head.source);
+        Some(head.record)
+    }
+}
+
+impl<'a> Iterator for MergeIter<'a> {
+    type Item = (&'a [u8], Cow<'a, Record>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let key = self.heap.peek()?.key;
+        let blobs = self.blobs;
+
+        // the key's versions come off the heap newest first. `resolve` only takes as many as it
+        // needs, the rest are shadowed and get skipped
+        let record = resolve(std::iter::from_fn(|| self.pop_version(key)), blobs);
+        while self.pop_version(key).is_some() {}
+        Some((key, record?))
+    }
+}
+
+/// where a key turned up when merging the tree with another sorted stream (see
+/// `LSMTree::merge_with`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeSide {
+    /// only in the tree - key, value
+    Left(Vec<u8>, Vec<u8>),
+
+    /// only in the other stream - key, value
+    Right(Vec<u8>, Vec<u8>),
+
+    /// in both - key, tree value, other value
+    Both(Vec<u8>, Vec<u8>, Vec<u8>),
+}
+
+/// merge-join of two sorted streams of pairs
+pub(crate) struct MergeJoin<'a, L: Iterator, R: Iterator> {
+    left: Peekable<L>,
+    right: Peekable<R>,
+    cmp: &'a dyn Comparator,
+}
+
+impl<'a, L, R> MergeJoin<'a, L, R>
+where
+    L: Iterator<Item = (Vec<u8>, Vec<u8>)>,
+    R: Iterator<Item = (Vec<u8>, Vec<u8>)>,
+{
+    pub(crate) fn new(left: L, right: R, cmp: &'a dyn Comparator) -> Self {
+        MergeJoin {
+            left: left.peekable(),
+            right: right.peekable(),
+            cmp,
+        }
+    }
+}
+
+impl<L, R> Iterator for MergeJoin<'_, L, R>
+where
+    L: Iterator<Item = (Vec<u8>, Vec<u8>)>,
+    R: Iterator<Item = (Vec<u8>, Vec<u8>)>,
+{
+    type Item = MergeSide;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let order = match (self.left.peek(), self.right.peek()) {
+            (None, None) => return None,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((l, _)), Some((r, _))) => self.cmp.compare(l, r),
+        };
+
+        // can't be `None`, we just peeked at whichever side we take from
+        Some(match order {
+            Ordering::Less => {
+                let (key, value) = self.left.next()?;
+                MergeSide::Left(key, value)
+            }
+            Ordering::Greater => {
+                let (key, value) = self.right.next()?;
+                MergeSide::Right(key, value)
+            }
+            Ordering::Equal => {
+                let (key, left) = self.left.next()?;
+                let (_, right) = self.right.next()?;
+                MergeSide::Both(key, left, right)
+            }
+        })
+    }
+}
@@ -1,263 +1,6054 @@
 #![warn(clippy::pedantic)]
 
-use std::collections::BTreeMap;
+mod blob;
+pub mod block;
+pub mod bloom;
+mod clock;
+mod concurrent;
+mod error;
+mod iter;
+mod memtable;
+mod pool;
+mod snapshot;
+mod sstable;
+mod stats;
+#[cfg(feature = "async")]
+mod stream;
+#[cfg(feature = "serde")]
+mod typed;
 
+use std::borrow::Cow;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BTreeSet};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::Duration;
+
+use blob::{BlobRef, BlobStore};
+use block::PrefixBlock;
+use bloom::BloomFilter;
+pub use clock::{Clock, SystemClock};
+pub use concurrent::ConcurrentLSMTree;
+pub use error::LsmError;
+pub use iter::MergeSide;
+use iter::{EntryRef, MergeIter, MergeJoin};
+use memtable::Memtable;
+use pool::BufferPool;
+use sha2::{Digest, Sha256};
+pub use snapshot::Snapshot;
+use snapshot::Snapshots;
+pub use sstable::{read_sstable, write_sstable};
+pub use stats::{LevelStat, SizeDistribution, SizeStats, Stats};
+#[cfg(feature = "serde")]
+pub use typed::TypedLSM;
+
+// bloom filter size unless `with_bloom_bits_per_key` says otherwise, ~1% false positives
+const DEFAULT_BLOOM_BITS_PER_KEY: usize = 10;
+
+// restart interval for the blocks `estimate_compression_ratio` builds, leveldb's default
+const SAMPLE_RESTART_INTERVAL: usize = 16;
+
+/// the index `scan_by_level` reports memtable entries under, frozen memtables included
+pub const MEMTABLE_SOURCE: usize = usize::MAX;
+
+// the level every flush, load and absorb lands in (see `older_level`)
+const NEWEST_LEVEL: usize = 0;
+
+/// the level `level` cascades into
+///
+/// level slots go newest to oldest by index. data only ever enters at `NEWEST_LEVEL` and moves
+/// down through here, so for any key, the version in the lower slot always shadows the one in the
+/// higher slot. reads go through `levels_newest_first` instead of walking the slots themselves
+fn older_level(level: usize) -> usize {
+    level + 1
+}
+
+/// a key and its value, or `None` for a tombstone
+pub type Entry = (Vec<u8>, Option<Vec<u8>>);
+
+/// a key's value, and the live keys either side of it - (value, previous, next)
+pub type Neighbors = (Option<Vec<u8>>, Option<Vec<u8>>, Option<Vec<u8>>);
+
+/// a value (or `None` for a tombstone), tagged with the sequence number of the write that produced
+/// it. sequence numbers only ever go up, so a higher seq is always the newer version of a key
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Record {
+    pub(crate) value: Option<Vec<u8>>,
+    pub(crate) seq: u64,
+
+    // `value` is a suffix to tack onto whatever the older version of the key holds, rather than
+    // the whole value (see `LSMTree::append`)
+    pub(crate) append: bool,
+
+    // the value was moved out to a blob file (see `with_blob_threshold`), `value` is left empty
+    pub(crate) blob: Option<BlobRef>,
+
+    // crc32 of the value, computed when it's written and checked by `try_get`
+    #[cfg(feature = "checksum")]
+    pub(crate) crc: u32,
+}
+
+impl Record {
+    pub(crate) fn new(value: Option<Vec<u8>>, seq: u64) -> Self {
+        Record {
+            #[cfg(feature = "checksum")]
+            crc: value.as_deref().map_or(0, crc32fast::hash),
+            value,
+            seq,
+            append: false,
+            blob: None,
+        }
+    }
+
+    /// how long the value is, wherever it's kept - a value moved out to a blob file is left
+    /// empty here. `None` for a tombstone
+    fn value_len(&self) -> Option<usize> {
+        let value = self.value.as_ref()?;
+        Some(self.blob.map_or(value.len(), |blob| blob.len))
+    }
+
+    /// folds an append operand onto the version of its key right below it. with nothing below,
+    /// the suffix becomes the whole value
+    fn apply(&self, base: Option<&Record>) -> Record {
+        debug_assert!(self.append, "only append operands get applied");
+        let mut value = base.and_then(|b| b.value.clone()).unwrap_or_default();
+        value.extend_from_slice(self.value.as_deref().unwrap_or_default());
+
+        // still an operand if what it was folded onto is one too
+        let mut record = Record::new(Some(value), self.seq);
+        record.append = base.is_some_and(|b| b.append);
+        record
+    }
+
+    /// checks the value still matches the checksum it was written with. always passes without
+    /// the `checksum` feature
+    #[cfg_attr(
+        not(feature = "checksum"),
+        allow(clippy::unused_self, clippy::unnecessary_wraps)
+    )]
+    fn verify(&self, key: &[u8]) -> Result<(), LsmError> {
+        #[cfg(feature = "checksum")]
+        if self.value.as_deref().map_or(0, crc32fast::hash) != self.crc {
+            return Err(LsmError::Corruption(format!(
+                "checksum mismatch for key {:?}",
+                String::from_utf8_lossy(key)
+            )));
+        }
+        #[cfg(not(feature = "checksum"))]
+        let _ = key;
+        Ok(())
+    }
+}
+
+/// the record `get` sees given a key's versions, newest first. that's the newest version, unless
+/// it's an append operand - then it's the operands down to the first full value (or tombstone),
+/// applied oldest first
+pub(crate) fn resolve<'a>(
+    mut versions: impl Iterator<Item = &'a Record>,
+    blobs: &BlobStore,
+) -> Option<Cow<'a, Record>> {
+    let newest = versions.next()?;
+    if !newest.append {
+        return Some(blobs.inline(newest));
+    }
+
+    let mut operands = vec![newest];
+    let base = versions.find(|record| {
+        if record.append {
+            operands.push(record);
+        }
+        !record.append
+    });
+
+    let base = base.map(|base| blobs.inline(base));
+    let mut record = operands.pop()?.apply(base.as_deref());
+    while let Some(operand) = operands.pop() {
+        record = operand.apply(Some(&record));
+    }
+    Some(Cow::Owned(record))
+}
+
+/// an `Entry` with its sequence number, the way it's stored in the memtable and levels
+type VersionedEntry = (Vec<u8>, Record);
+
+/// normalizes keys before they're stored or looked up, so logically-equal keys (e.g. different
+/// casing) land on the same entry
+///
+/// the tree only ever sees normalized keys, so everything that's ordered - levels, flushes, and
+/// any range scans - follows the order of the *normalized* keys, not the originals
+pub trait KeyTransform: Send + Sync {
+    fn normalize(&self, key: &[u8]) -> Vec<u8>;
+}
+
+/// orders keys. every comparison the tree makes - levels, flushes, lookups, scans - goes through
+/// it, so it's free to sort keys however it likes
+///
+/// it has to be a total order, and `Equal` has to mean the bytes are equal. the memtable and
+/// bloom filters go by bytes, so a comparator that calls different bytes equal (say, ignoring
+/// trailing whitespace) only works paired with a `KeyTransform` that maps them to the same bytes
+/// first
+pub trait Comparator: Send + Sync {
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+}
+
+/// plain byte order, the default
+pub(crate) struct Bytewise;
+
+impl Comparator for Bytewise {
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// another comparator's order backwards, for merging sources from the end
+struct Reversed<'a>(&'a dyn Comparator);
+
+impl Comparator for Reversed<'_> {
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self.0.compare(b, a)
+    }
+}
+
+/// orders 8-byte keys as big-endian `u64`s, the encoding `insert_u64` and `get_u64` use
+///
+/// big-endian already sorts numerically in byte order, so this agrees with the default - it's
+/// here to say what the keys mean. keys of any other length fall back to byte order
+pub struct BigEndianU64Comparator;
+
+impl Comparator for BigEndianU64Comparator {
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        match (<[u8; 8]>::try_from(a), <[u8; 8]>::try_from(b)) {
+            (Ok(a), Ok(b)) => u64::from_be_bytes(a).cmp(&u64::from_be_bytes(b)),
+            _ => a.cmp(b),
+        }
+    }
+}
+
+/// what `write_batch` does with a batch too big for the memtable
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OversizedBatch {
+    /// the whole batch goes into the memtable before anything is flushed, however far past the
+    /// threshold that takes it - as with `LSMTree::with_manual_flush`, overwriting a version a
+    /// snapshot can see freezes the memtable rather than flushing it. if the flush at the end
+    /// fails, the whole batch is still readable from the memtables
+    #[default]
+    Wholesale,
+
+    /// flush whenever the memtable fills, same as inserting the pairs one at a time. memory stays
+    /// bounded, but the batch isn't atomic - a flush failing part way through leaves the writes
+    /// before it in the tree and drops the rest
+    Incremental,
+}
+
+/// what a write does when L0 is past the limit set by `LSMTree::with_write_stall`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StallMode {
+    /// run the compaction that's behind right there, then carry on with the write
+    Block,
+
+    /// fail the write with `LsmError::WouldStall`, and leave the compaction to whoever calls
+    /// `compact`
+    Error,
+}
+
+/// a slower store sitting behind the tree, consulted when a key isn't in the memtable or any level
+///
+/// the tree acts as a cache over it: a tombstone in the tree still hides the key, so deleted keys
+/// never fall through to a stale copy in the store
+pub trait BackingStore: Send + Sync {
+    fn load(&self, key: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// what `bulk_load` does when its input has the same key more than once
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// the earliest occurrence in the input wins
+    KeepFirst,
+
+    /// the latest occurrence in the input wins, as if each pair had been inserted in order
+    KeepLast,
+
+    /// reject the whole load, so accidental duplicates don't silently drop data
+    #[default]
+    Error,
+}
+
+/// what `get_status` found for a key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GetStatus {
+    Present(Vec<u8>),
+
+    /// the newest version is a tombstone
+    Deleted,
+
+    /// the tree has no version of the key at all
+    Absent,
+}
+
+/// what `repair_level` threw away, and what that did to reads
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RepairReport {
+    pub level: usize,
+
+    // entries in the dropped level, shadowed versions included
+    pub dropped: usize,
+
+    // keys that now read an older value from a deeper level
+    pub regressed: Vec<Vec<u8>>,
+
+    // keys with no other value anywhere - no older version, or a tombstone - which are gone now
+    pub lost: Vec<Vec<u8>>,
+}
+
+/// cloning is cheap - levels are immutable once built and shared between clones, so only the
+/// memtable gets copied. compaction always builds new levels rather than touching shared ones, so a
+/// clone works as a snapshot
+#[derive(Clone)]
+// the flags are independent switches, not states of one thing
+#[allow(clippy::struct_excessive_bools)]
 pub struct LSMTree {
     // memtable - keys get written here first, and its the first place we start lookups
     // BTreeMap is a sorted map
-    memtable: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    memtable: Memtable,
+
+    // every key in the memtable was written after all the ones before it in key order, in both
+    // byte order and the comparator's - so the memtable iterates in the order the levels need
+    memtable_in_order: bool,
+
+    // memtables handed out by `freeze_memtable`, or frozen to keep a version a snapshot can see
+    // (see `with_manual_flush`), and not flushed yet, oldest first. searched after the memtable
+    // and before the levels
+    frozen: Vec<Arc<Memtable>>,
 
     // levels - mock "disk" layout
-    levels: Vec<Option<LSMLevel>>,
+    levels: Vec<Option<Arc<LSMLevel>>>,
+
+    // indices of the `Some` levels, ascending, so lookups can skip the empty slots. only ever
+    // changed through `take_level` and `set_level`
+    populated: Vec<usize>,
+
+    // threshold for flushing memtable to disk
+    memtable_flush_threshold: usize,
+
+    // only flush when `flush` is called
+    manual_flush: bool,
+
+    // applied to every key on the way in. `None` is the identity, and skips the copy
+    key_transform: Option<Arc<dyn KeyTransform>>,
+
+    // key order. `None` is byte order
+    comparator: Option<Arc<dyn Comparator>>,
+
+    // sequence number handed to the next write
+    next_seq: u64,
+
+    // consulted on a miss
+    backing_store: Option<Arc<dyn BackingStore>>,
+
+    // level -> bloom filter bits per key
+    bloom_bits_per_key: Arc<dyn Fn(usize) -> usize + Send + Sync>,
+    bloom_seed: u64,
+
+    // how many key ranges each level is split into
+    partitions_per_level: usize,
+
+    // cap on live entries, past which the oldest writes get evicted
+    max_entries: Option<usize>,
+
+    // hot keys -> their newest version, checked before anything else. `None` if the key isn't in
+    // the tree. kept in sync on every write, so the memtable and levels still have everything
+    pinned: BTreeMap<Vec<u8>, Option<Record>>,
+
+    // live snapshots, whose versions compaction has to keep around
+    snapshots: Snapshots,
+
+    // a compaction panicked. set by `guarded`, and never cleared
+    poisoned: bool,
+
+    // writes are rejected with `LsmError::ReadOnly`
+    read_only: bool,
+
+    // values longer than this go out to `blobs` when they're flushed
+    blob_threshold: Option<usize>,
+    blobs: BlobStore,
+
+    // flushes stop at L0, however big it gets, until `compact` is called
+    defer_cascade: bool,
+
+    // L0 entries past which writes stall, and what they do about it
+    write_stall: Option<(usize, StallMode)>,
+
+    // writes that have stalled so far, and how long the blocked ones waited, going by `clock`
+    stall_count: u64,
+    total_stall_duration: Duration,
+    clock: Arc<dyn Clock>,
+
+    // recycles key and value buffers between merges
+    buffer_pool: Option<BufferPool>,
+}
+
+/// a memtable swapped out by `LSMTree::freeze_memtable`. the tree keeps reading from it until
+/// it's handed back to `LSMTree::flush_frozen`
+pub struct FrozenMemtable {
+    entries: Arc<Memtable>,
+}
+
+impl FrozenMemtable {
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[derive(Clone)]
+pub struct LSMLevel {
+    // disjoint key ranges, in key order. a single partition unless the tree was built
+    // `with_partitions`
+    partitions: Vec<Partition>,
+
+    // highest seq of anything in the level, so a lookup that already has a newer value can
+    // skip it
+    max_seq: u64,
+}
+
+#[derive(Clone)]
+struct Partition {
+    data: Vec<VersionedEntry>,
+
+    // lets `get` skip partitions that definitely don't have the key
+    bloom: BloomFilter,
+}
+
+impl LSMLevel {
+    /// number of entries across all partitions
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.partitions.iter().map(|p| p.data.len()).sum()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.partitions.is_empty()
+    }
+
+    /// every entry in the level, in key order
+    fn entries(&self) -> impl Iterator<Item = &VersionedEntry> {
+        self.partitions.iter().flat_map(|p| &p.data)
+    }
+
+    /// the level's entries in key order, starting from the first one at or after `start`
+    ///
+    /// partitions hold disjoint key ranges, so the seek skips whole partitions before binary
+    /// searching the one `start` falls in
+    fn entries_from<'a>(
+        &'a self,
+        start: &[u8],
+        cmp: &dyn Comparator,
+    ) -> impl Iterator<Item = &'a VersionedEntry> + use<'a> {
+        let first = self.partitions.partition_point(|p| {
+            p.data
+                .last()
+                .is_some_and(|(k, _)| cmp.compare(k, start).is_lt())
+        });
+        let (head, rest): (&[VersionedEntry], _) = match self.partitions[first..].split_first() {
+            Some((p, rest)) => {
+                let skip = p
+                    .data
+                    .partition_point(|(k, _)| cmp.compare(k, start).is_lt());
+                (&p.data[skip..], rest)
+            }
+            None => (&[], &[]),
+        };
+        head.iter().chain(rest.iter().flat_map(|p| &p.data))
+    }
+
+    /// the level's entries before `end`, in reverse key order - `entries_from` backwards
+    fn entries_before<'a>(
+        &'a self,
+        end: &[u8],
+        cmp: &dyn Comparator,
+    ) -> impl Iterator<Item = &'a VersionedEntry> + use<'a> {
+        // the partitions starting before `end`, the last of which can run past it
+        let n = self.partitions.partition_point(|p| {
+            p.data
+                .first()
+                .is_some_and(|(k, _)| cmp.compare(k, end).is_lt())
+        });
+        let (tail, rest): (&[VersionedEntry], _) = match self.partitions[..n].split_last() {
+            Some((p, rest)) => {
+                let take = p.data.partition_point(|(k, _)| cmp.compare(k, end).is_lt());
+                (&p.data[..take], rest)
+            }
+            None => (&[], &[]),
+        };
+        tail.iter()
+            .rev()
+            .chain(rest.iter().rev().flat_map(|p| p.data.iter().rev()))
+    }
+
+    /// first and last key in the level, `None` if it's empty
+    fn key_range(&self) -> Option<(&[u8], &[u8])> {
+        let first = self.partitions.first()?.data.first()?;
+        let last = self.partitions.last()?.data.last()?;
+        Some((&first.0, &last.0))
+    }
+
+    fn into_data(self) -> Vec<VersionedEntry> {
+        self.partitions.into_iter().flat_map(|p| p.data).collect()
+    }
+
+    /// the only partition that could hold `key` - the last one whose first key is <= `key`
+    fn partition_for(&self, key: &[u8], cmp: &dyn Comparator) -> Option<usize> {
+        self.partitions
+            .partition_point(|p| cmp.compare(&p.data[0].0, key).is_le())
+            .checked_sub(1)
+    }
+
+    /// whether the level might hold `key`, without searching it. `false` means the bloom filter
+    /// ruled it out
+    fn may_contain(&self, key: &[u8], cmp: &dyn Comparator) -> bool {
+        self.partition_for(key, cmp)
+            .is_some_and(|i| self.partitions[i].bloom.may_contain(key))
+    }
+
+    /// the newest version of `key` in the level
+    fn get(&self, key: &[u8], cmp: &dyn Comparator) -> Option<&Record> {
+        self.versions(key, cmp).next()
+    }
+
+    /// every version of `key` in the level, newest first. there's only more than one when a
+    /// snapshot is holding on to an older version
+    fn versions<'a>(
+        &'a self,
+        key: &[u8],
+        cmp: &dyn Comparator,
+    ) -> impl Iterator<Item = &'a Record> {
+        let partition = self
+            .partition_for(key, cmp)
+            .map(|i| &self.partitions[i])
+            .filter(|partition| partition.bloom.may_contain(key));
+        let data = partition.map_or(&[][..], |p| p.data.as_slice());
+
+        // we have a guarantee that the keys are in sorted order, because the memtable is a
+        // BTreeMap. when we flush the memtable to a level, we iterate through the keys in
+        // order. versions of the same key sit next to each other, newest first
+        //
+        // the match is whatever the comparator calls equal, same as the search that found it, so
+        // this hands back the stored entry even when its bytes differ from `key`
+        let start = data.partition_point(|(k, _)| cmp.compare(k, key).is_lt());
+        let len = data[start..].partition_point(|(k, _)| cmp.compare(k, key).is_eq());
+        data[start..start + len].iter().map(|(_, record)| record)
+    }
+}
+
+impl LSMTree {
+    /// a threshold of 1 flushes after every write. 0 is treated as 1 - with level capacities of
+    /// 0 every merge would cascade forever - so use `try_new` to reject it instead
+    #[must_use]
+    pub fn new(memtable_flush_threshold: usize) -> Self {
+        LSMTree {
+            memtable: Memtable::default(),
+            memtable_in_order: true,
+            frozen: vec![],
+            levels: vec![],
+            populated: vec![],
+            memtable_flush_threshold: memtable_flush_threshold.max(1),
+            manual_flush: false,
+            key_transform: None,
+            comparator: None,
+            next_seq: 0,
+            backing_store: None,
+            bloom_bits_per_key: Arc::new(|_| DEFAULT_BLOOM_BITS_PER_KEY),
+            bloom_seed: bloom::DEFAULT_SEED,
+            partitions_per_level: 1,
+            max_entries: None,
+            pinned: BTreeMap::new(),
+            snapshots: Snapshots::default(),
+            poisoned: false,
+            read_only: false,
+            blob_threshold: None,
+            blobs: BlobStore::default(),
+            defer_cascade: false,
+            write_stall: None,
+            stall_count: 0,
+            total_stall_duration: Duration::ZERO,
+            clock: Arc::new(SystemClock::default()),
+            buffer_pool: None,
+        }
+    }
+
+    /// like `new`, but rejects a `memtable_flush_threshold` of 0 instead of building a tree that
+    /// flushes every write into a brand new level
+    ///
+    /// # Errors
+    ///
+    /// `LsmError::InvalidArgument` if `memtable_flush_threshold` is 0
+    pub fn try_new(memtable_flush_threshold: usize) -> Result<Self, LsmError> {
+        if memtable_flush_threshold == 0 {
+            return Err(LsmError::InvalidArgument(
+                "memtable_flush_threshold must be at least 1".to_string(),
+            ));
+        }
+        Ok(Self::new(memtable_flush_threshold))
+    }
+
+    /// start handing out sequence numbers from `seq` (it never moves backwards, so a lower value
+    /// is ignored)
+    ///
+    /// trees only agree on which write is newer if they draw sequence numbers from the same space,
+    /// see `absorb`
+    #[must_use]
+    pub fn with_next_seq(mut self, seq: u64) -> Self {
+        self.next_seq = self.next_seq.max(seq);
+        self
+    }
+
+    /// the sequence number the next write will get
+    #[must_use]
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    /// how many entries the memtable holds before it's flushed
+    #[must_use]
+    pub fn flush_threshold(&self) -> usize {
+        self.memtable_flush_threshold
+    }
+
+    /// change the flush threshold on a live tree. if the memtable is already at or over the new
+    /// threshold it's flushed straight away. 0 is treated as 1
+    ///
+    /// level capacities scale with the threshold (see `level_capacity`), so existing levels that
+    /// are over their new capacity cascade the next time something is merged into them
+    pub fn set_flush_threshold(&mut self, n: usize) {
+        self.memtable_flush_threshold = n.max(1);
+        self.maybe_flush();
+    }
+
+    /// never flush on `insert` - the memtable only moves down into the levels when `flush` is
+    /// called. the flush threshold still sizes the levels
+    ///
+    /// the memtable grows without bound in this mode, and everything in it lives in memory in a
+    /// `BTreeMap`. meant for benchmarks and bulk loads that flush on their own schedule, not for
+    /// open-ended write traffic
+    ///
+    /// overwriting a version a live snapshot can still see doesn't flush either. the memtable
+    /// is frozen instead (see `freeze_memtable`), so the old version stays readable and the new
+    /// one goes into a fresh memtable
+    #[must_use]
+    pub fn with_manual_flush(mut self) -> Self {
+        self.manual_flush = true;
+        self
+    }
+
+    /// flushes only ever merge into L0, which is allowed to grow past its capacity. the cascade
+    /// into the deeper levels waits until `compact` is called
+    ///
+    /// keeps the cost of a flush down during write bursts, at the price of a bigger L0 to search
+    /// in the meantime. see `ConcurrentLSMTree::with_idle_compaction` for running the deferred
+    /// work once writes let up
+    #[must_use]
+    pub fn with_deferred_cascade(mut self) -> Self {
+        self.defer_cascade = true;
+        self
+    }
+
+    /// stall writes once L0 holds `max_l0_entries` entries, until compaction catches up. `mode`
+    /// decides whether the write runs the compaction itself or fails with `LsmError::WouldStall`
+    ///
+    /// only L0 can fall behind, and only with `with_deferred_cascade` - otherwise every flush
+    /// cascades out of it as it fills. a limit under L0's capacity counts as the capacity, since
+    /// L0 that size is where a cascade would have happened anyway
+    ///
+    /// every stall logs a warning and shows up in `Stats::stall_count`
+    #[must_use]
+    pub fn with_write_stall(mut self, max_l0_entries: usize, mode: StallMode) -> Self {
+        self.write_stall = Some((max_l0_entries, mode));
+        self
+    }
+
+    /// what the tree times stalls with (see `Stats::total_stall_duration`)
+    #[must_use]
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// run the cascade out of L0 that `with_deferred_cascade` put off, if L0 is over capacity.
+    /// then coalesce underfull levels, so `get` has fewer of them to search
+    ///
+    /// levels only ever shrink by dropping whole keys (`compact_range`, eviction, repair), which
+    /// can leave a handful of entries in each of a stack of levels. a level is merged down into
+    /// the next populated one whenever the two together still fit under the lower one's capacity
+    pub fn compact(&mut self) {
+        self.assert_not_poisoned();
+        let l0 = self.levels.first().and_then(Option::as_ref);
+        if l0.is_some_and(|l0| l0.len() >= self.level_capacity(0)) {
+            let defer = std::mem::replace(&mut self.defer_cascade, false);
+            self.guarded(|tree| {
+                if let Some(l0) = tree.take_level(0) {
+                    tree.merge_into_level(
+                        NEWEST_LEVEL,
+                        Cow::Owned(Arc::unwrap_or_clone(l0).into_data()),
+                    );
+                }
+            });
+            self.defer_cascade = defer;
+        }
+
+        self.guarded(LSMTree::coalesce_levels);
+    }
+
+    /// merges each level down into the next populated one while the pair fits in the lower
+    /// level. the slots in between are empty, so nothing ends up under an older version
+    fn coalesce_levels(&mut self) {
+        let mut k = 0;
+        while k + 1 < self.populated.len() {
+            let (upper, lower) = (self.populated[k], self.populated[k + 1]);
+            let len = |i: usize| self.levels[i].as_ref().map_or(0, |level| level.len());
+            if len(upper) + len(lower) >= self.level_capacity(lower) {
+                k += 1;
+                continue;
+            }
+
+            log::debug!("coalesce level={upper} into={lower}");
+            // `lower` is now at `k`, so the next pair to look at is it and whatever's below it
+            if let Some(level) = self.take_level(upper) {
+                self.merge_into_level(lower, Cow::Owned(Arc::unwrap_or_clone(level).into_data()));
+            }
+        }
+    }
+
+    /// flush the memtable into the levels now, whatever its size. frozen memtables are flushed
+    /// too, ahead of it
+    pub fn flush(&mut self) {
+        self.assert_not_poisoned();
+        if !self.is_memtable_empty() {
+            self.flush_memtable();
+        }
+    }
+
+    /// like `flush`, but the memtable goes straight into `level` instead of L0, merging with
+    /// whatever's there and cascading from there as usual. for setting up exact layouts in tests
+    ///
+    /// # Errors
+    ///
+    /// - `LsmError::InvalidArgument` if a level above `level` holds data. the memtable is newer
+    ///   than all of it, so it can't go underneath. or if `level` is too deep for its capacity to
+    ///   fit in a `usize`
+    /// - `LsmError::Poisoned` if the tree already was, or the flush panicked
+    pub fn flush_to_level(&mut self, level: usize) -> Result<(), LsmError> {
+        if self.poisoned {
+            return Err(LsmError::Poisoned);
+        }
+        if self.memtable_flush_threshold.leading_zeros() as usize <= level {
+            return Err(LsmError::InvalidArgument(format!(
+                "level {level} is too deep, its capacity would overflow"
+            )));
+        }
+        if let Some(&newest) = self.populated.first()
+            && newest < level
+        {
+            return Err(LsmError::InvalidArgument(format!(
+                "level {level} is under level {newest}, which holds older data"
+            )));
+        }
+
+        if !self.is_memtable_empty() {
+            self.flush_memtable_into(level);
+        }
+        if self.poisoned {
+            return Err(LsmError::Poisoned);
+        }
+        Ok(())
+    }
+
+    /// swap the memtable for an empty one and hand back what was in it, so the caller can flush
+    /// it whenever suits (say, on a background task) with `flush_frozen`. `None` if the memtable
+    /// is empty
+    ///
+    /// reads keep seeing the frozen entries in the meantime. any flush of the tree - `flush`, or
+    /// the memtable filling up - takes the frozen memtables down with it first, oldest first, so
+    /// they always end up below newer writes
+    pub fn freeze_memtable(&mut self) -> Option<FrozenMemtable> {
+        if self.memtable.is_empty() {
+            return None;
+        }
+        let entries = Arc::new(std::mem::take(&mut self.memtable));
+        self.frozen.push(Arc::clone(&entries));
+        Some(FrozenMemtable { entries })
+    }
+
+    /// flush a memtable from `freeze_memtable` into the levels, along with any frozen before it.
+    /// a no-op if it's already been flushed
+    ///
+    /// # Panics
+    ///
+    /// if the tree is poisoned, or this flush poisons it
+    // `frozen` is taken by value on purpose - it's spent once flushed
+    #[allow(clippy::needless_pass_by_value)]
+    pub fn flush_frozen(&mut self, frozen: FrozenMemtable) {
+        self.assert_not_poisoned();
+        let Some(i) = self
+            .frozen
+            .iter()
+            .position(|f| Arc::ptr_eq(f, &frozen.entries))
+        else {
+            return;
+        };
+        drop(frozen);
+
+        self.flush_frozen_through(i);
+        self.assert_not_poisoned();
+    }
+
+    /// flushes the frozen memtables up to and including the `i`th, oldest first
+    fn flush_frozen_through(&mut self, i: usize) {
+        let flushed = self
+            .frozen
+            .drain(..=i)
+            .map(|entries| (Arc::unwrap_or_clone(entries), false))
+            .collect();
+        if let Err(unflushed) = self.flush_memtables(flushed, NEWEST_LEVEL) {
+            self.frozen.splice(..0, unflushed.into_iter().map(Arc::new));
+        }
+    }
+
+    /// whether there's nothing waiting to be flushed, frozen memtables included
+    fn is_memtable_empty(&self) -> bool {
+        self.memtable.is_empty() && self.frozen.is_empty()
+    }
+
+    /// make room for `additional` more entries in the memtable before a bulk write phase
+    ///
+    /// the memtable is a `BTreeMap`, which allocates node by node and has nothing to reserve, so
+    /// this is a no-op for now. it's here so callers don't have to change if the memtable ever
+    /// moves to something array-backed
+    pub fn reserve_memtable(&mut self, additional: usize) {
+        let _ = additional;
+    }
+
+    /// drop every key. the level slots stay allocated (empty), so a tree that's refilled to the
+    /// same size doesn't have to grow `levels` again. settings, pins and sequence numbers are
+    /// kept
+    pub fn clear(&mut self) {
+        self.memtable.clear();
+        self.frozen.clear();
+        for i in 0..self.levels.len() {
+            self.take_level(i);
+        }
+        for record in self.pinned.values_mut() {
+            *record = None;
+        }
+    }
+
+    /// normalize every key with `transform` before it's stored or looked up (see `KeyTransform`)
+    #[must_use]
+    pub fn with_key_transform(mut self, transform: impl KeyTransform + 'static) -> Self {
+        self.key_transform = Some(Arc::new(transform));
+        self
+    }
+
+    /// order keys with `comparator` instead of byte order (see `Comparator`). has to be set
+    /// before anything is written
+    ///
+    /// the memtable is still a `BTreeMap` in byte order, so with a custom comparator scans and
+    /// flushes sort it first
+    #[must_use]
+    pub fn with_comparator(mut self, comparator: impl Comparator + 'static) -> Self {
+        self.comparator = Some(Arc::new(comparator));
+        self
+    }
+
+    fn comparator(&self) -> &dyn Comparator {
+        self.comparator.as_deref().unwrap_or(&Bytewise)
+    }
+
+    /// fall back to `store` on a miss (see `BackingStore`)
+    #[must_use]
+    pub fn with_backing_store(mut self, store: impl BackingStore + 'static) -> Self {
+        self.backing_store = Some(Arc::new(store));
+        self
+    }
+
+    /// size each level's bloom filter with `bits_per_key(level)` bits per key
+    ///
+    /// deeper levels are read more often than they're rewritten, so it can be worth giving them
+    /// bigger (more accurate) filters than L0. more bits means fewer false positives but more
+    /// memory: ~10 bits is 1%, ~15 is 0.1%
+    #[must_use]
+    pub fn with_bloom_bits_per_key(
+        mut self,
+        bits_per_key: impl Fn(usize) -> usize + Send + Sync + 'static,
+    ) -> Self {
+        self.bloom_bits_per_key = Arc::new(bits_per_key);
+        self
+    }
+
+    /// hash keys into the bloom filters with `seed` (see `BloomFilter::with_seed`). the default
+    /// is fixed, so which lookups hit false positives is the same every run
+    #[must_use]
+    pub fn with_bloom_seed(mut self, seed: u64) -> Self {
+        self.bloom_seed = seed;
+        self
+    }
+
+    /// split every level into `n` partitions with disjoint key ranges, so each one can later be
+    /// compacted independently (and in parallel)
+    ///
+    /// boundaries are sampled at even intervals through the level's sorted keys, so partitions
+    /// hold roughly the same number of entries. `get` binary searches the partition boundaries and
+    /// only ever looks inside one partition per level
+    #[must_use]
+    pub fn with_partitions(mut self, n: usize) -> Self {
+        self.partitions_per_level = n.max(1);
+        self
+    }
+
+    /// cap the number of live entries, turning the tree into an approximate FIFO cache
+    ///
+    /// when a flush leaves more than `n` live keys in the levels, the keys with the oldest writes
+    /// (lowest sequence numbers) are evicted until `n` remain. eviction only happens at compaction
+    /// time, so the tree can go over the cap by up to a memtable's worth of entries in between
+    ///
+    /// a key a live snapshot can still see isn't evicted until the snapshot is dropped, the next
+    /// oldest key goes instead
+    #[must_use]
+    pub fn with_max_entries(mut self, n: usize) -> Self {
+        self.max_entries = Some(n);
+        self
+    }
+
+    /// store values longer than `bytes` out of line, in blob files, so compaction only has to
+    /// copy a small pointer around instead of the whole value (see `blob`)
+    ///
+    /// values are moved out when they're flushed, so the memtable always holds them in full.
+    /// appends aren't moved out, whatever their size
+    #[must_use]
+    pub fn with_blob_threshold(mut self, bytes: usize) -> Self {
+        self.blob_threshold = Some(bytes);
+        self
+    }
+
+    /// keep up to `max_buffers` key and value buffers from the levels that flushes and cascades
+    /// merge away, and copy the merged entries into those instead of allocating new ones (see
+    /// `pool`). cuts allocator traffic under heavy churn, at the cost of holding on to that much
+    /// memory between merges
+    #[must_use]
+    pub fn with_buffer_pool(mut self, max_buffers: usize) -> Self {
+        self.buffer_pool = Some(BufferPool::new(max_buffers));
+        self
+    }
+
+    /// reserve room for `n` levels up front, so a tree that's known to grow deep doesn't
+    /// reallocate `levels` on the way down
+    #[must_use]
+    pub fn with_expected_levels(mut self, n: usize) -> Self {
+        self.levels.reserve(n);
+        self
+    }
+
+    fn normalize<'a>(&self, key: &'a [u8]) -> Cow<'a, [u8]> {
+        match &self.key_transform {
+            Some(transform) => Cow::Owned(transform.normalize(key)),
+            None => Cow::Borrowed(key),
+        }
+    }
+
+    /// returns the capacity for a given level
+    ///
+    /// each level can hold threshold × 2 ^ level
+    /// this balances write amplification with read performance and space usage
+    ///
+    /// examples:
+    /// 1. if each level had the same capacity, on every write, we would flush through every level
+    /// 2. if this was a large ratio (for example 10^level), we do compaction less frequently, but:
+    ///   - waste more space - arent getting rid of stale values
+    ///   - reads are slower - more in a level, slower binary search is
+    fn level_capacity(&self, level: usize) -> usize {
+        self.memtable_flush_threshold << level
+    }
+
+    /// insert the key-value pair into self.memtable (it's a `BTreeMap`)
+    /// check if memtable size has reached `self.memtable_threshold`
+    /// if threshold reached, call `self.flush_memtable()` to write it to level 0
+    ///
+    /// keys that only ever go up (timestamps, log offsets) take a fast path - a key past the end
+    /// of the memtable can't have an older version there, so the lookups for one are skipped,
+    /// and the flush doesn't have to sort. any other key just falls back to the usual path until
+    /// the next flush, no worse off than before
+    ///
+    /// # Panics
+    ///
+    /// if the tree is poisoned, or the flush this triggers poisons it (see `try_insert`)
+    pub fn insert(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) {
+        self.write(key, Record::new(value, self.next_seq));
+    }
+
+    /// `insert` a live value, returning what `get` would have returned for the key just before.
+    /// `None` if it was absent or deleted
+    ///
+    /// # Panics
+    ///
+    /// same as `insert`
+    pub fn insert_and_get_prev(&mut self, key: Vec<u8>, value: Vec<u8>) -> Option<Vec<u8>> {
+        let prev = self.get(&key);
+        self.insert(key, Some(value));
+        prev
+    }
+
+    /// like `insert`, but returns an error instead of panicking when the tree is poisoned or
+    /// read-only
+    ///
+    /// # Errors
+    ///
+    /// `LsmError::ReadOnly` if the tree is (see `set_read_only`), and `LsmError::WouldStall` if
+    /// L0 is past the limit set by `with_write_stall`. nothing is written in either case.
+    /// `LsmError::Poisoned` if the tree already was, or if the flush this triggered panicked. in
+    /// the second case the write itself is in the memtable, but the levels are left as they were
+    /// before the flush started
+    pub fn try_insert(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) -> Result<(), LsmError> {
+        self.try_write(key, Record::new(value, self.next_seq))
+    }
+
+    /// writes every pair in `batch` in order, `None` deleting the key. `oversized` decides whether
+    /// a batch bigger than the flush threshold is flushed as it goes or all at the end
+    ///
+    /// # Errors
+    ///
+    /// `LsmError::Poisoned` if the tree already was, or a flush during the batch panicked. what's
+    /// left of the batch then depends on `oversized`
+    pub fn write_batch(
+        &mut self,
+        batch: Vec<Entry>,
+        oversized: OversizedBatch,
+    ) -> Result<(), LsmError> {
+        match oversized {
+            OversizedBatch::Incremental => batch.into_iter().try_for_each(|(key, value)| {
+                self.try_write(key, Record::new(value, self.next_seq))
+            }),
+            OversizedBatch::Wholesale => {
+                let manual = std::mem::replace(&mut self.manual_flush, true);
+                let written = batch.into_iter().try_for_each(|(key, value)| {
+                    self.try_write(key, Record::new(value, self.next_seq))
+                });
+                self.manual_flush = manual;
+                written?;
+
+                self.maybe_flush();
+                if self.poisoned {
+                    return Err(LsmError::Poisoned);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// whether a compaction has panicked. a poisoned tree can still be read, but every write
+    /// fails - `try_insert` and `bulk_load` with `LsmError::Poisoned`, the rest by panicking
+    #[must_use]
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    /// stop taking writes, or start again. while it's set `try_insert`, `write_batch` and
+    /// `bulk_load` fail with `LsmError::ReadOnly` and the other writes panic with it. reads go on
+    /// as usual, so it's a way to hold the contents still while something copies them out
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    #[must_use]
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    #[track_caller]
+    fn assert_not_poisoned(&self) {
+        assert!(!self.poisoned, "{}", LsmError::Poisoned);
+    }
+
+    /// appends `suffix` to the value at `key`, or sets it to `suffix` if there isn't one
+    ///
+    /// doesn't read the current value - the suffix is written as a merge operand and folded into
+    /// the older versions of the key when it's read or compacted. handy for logs and counters
+    /// that only ever grow. a key the tree doesn't have (even if the backing store does) starts
+    /// out empty
+    pub fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) {
+        let mut record = Record::new(Some(suffix), self.next_seq);
+        record.append = true;
+        self.write(key, record);
+    }
+
+    fn write(&mut self, key: Vec<u8>, record: Record) {
+        if let Err(err) = self.try_write(key, record) {
+            panic!("{err}");
+        }
+    }
+
+    /// stores `record` under `key` in the memtable. `record.seq` has to be `next_seq`
+    fn try_write(&mut self, key: Vec<u8>, mut record: Record) -> Result<(), LsmError> {
+        if self.poisoned {
+            return Err(LsmError::Poisoned);
+        }
+        if self.read_only {
+            return Err(LsmError::ReadOnly);
+        }
+        self.maybe_stall()?;
+        let key = match self.normalize(&key) {
+            Cow::Borrowed(_) => key,
+            Cow::Owned(normalized) => normalized,
+        };
+        debug_assert_eq!(record.seq, self.next_seq);
+        self.next_seq += 1;
+
+        let appended = self.memtable_appended(&key);
+        self.memtable_in_order = self.memtable.is_empty() || (self.memtable_in_order && appended);
+
+        // the memtable only holds one version per key, so if a snapshot can still see the one
+        // we're about to overwrite, it has to go somewhere first - into a level, or with manual
+        // flushing into a frozen memtable, where reads still find it and the next flush takes it
+        // down
+        if !appended
+            && let Some(old) = self.memtable.get(&key)
+            && snapshot::is_visible(&snapshot::live(&self.snapshots), old.seq, record.seq)
+        {
+            if self.manual_flush {
+                let memtable = std::mem::take(&mut self.memtable);
+                self.frozen.push(Arc::new(memtable));
+            } else {
+                self.flush_memtable();
+                if self.poisoned {
+                    return Err(LsmError::Poisoned);
+                }
+            }
+        }
+
+        if let Some(pinned) = self.pinned.get_mut(&key) {
+            *pinned = Some(if record.append {
+                record.apply(pinned.as_ref())
+            } else {
+                record.clone()
+            });
+        }
+
+        // an operand on top of a version in the memtable can be folded right away
+        if record.append
+            && !appended
+            && let Some(old) = self.memtable.get(&key)
+        {
+            record = record.apply(Some(old));
+        }
+        self.memtable.insert(key, record);
+        self.maybe_flush();
+        if self.poisoned {
+            return Err(LsmError::Poisoned);
+        }
+        Ok(())
+    }
+
+    /// if L0 is past the `with_write_stall` limit, compact it or reject the write
+    fn maybe_stall(&mut self) -> Result<(), LsmError> {
+        let Some((limit, mode)) = self.write_stall else {
+            return Ok(());
+        };
+        let limit = limit.max(self.level_capacity(0));
+        let l0 = self.l0_len();
+        if l0 < limit {
+            return Ok(());
+        }
+
+        let stalled = match mode {
+            StallMode::Error => Duration::ZERO,
+            StallMode::Block => {
+                let start = self.clock.now();
+                self.compact();
+                self.clock.now().saturating_sub(start)
+            }
+        };
+        self.stall_count += 1;
+        self.total_stall_duration += stalled;
+        log::warn!("write stall l0={l0} limit={limit} mode={mode:?} stalled={stalled:?}");
+
+        if mode == StallMode::Error {
+            return Err(LsmError::WouldStall);
+        }
+        if self.poisoned {
+            return Err(LsmError::Poisoned);
+        }
+        Ok(())
+    }
+
+    fn l0_len(&self) -> usize {
+        self.levels
+            .first()
+            .and_then(Option::as_ref)
+            .map_or(0, |l0| l0.len())
+    }
+
+    /// whether `key` sorts after everything in the memtable, by bytes and by the comparator
+    fn memtable_appended(&self, key: &[u8]) -> bool {
+        let Some((last, _)) = self.memtable.last_key_value() else {
+            return true;
+        };
+        key > last.as_slice()
+            && self
+                .comparator
+                .as_ref()
+                .is_none_or(|cmp| cmp.compare(key, last).is_gt())
+    }
+
+    fn maybe_flush(&mut self) {
+        if !self.manual_flush && self.memtable.len() >= self.memtable_flush_threshold {
+            self.flush_memtable();
+        }
+    }
+
+    /// deletes a key by inserting a tombstone (`None`) for that key
+    pub fn delete(&mut self, key: Vec<u8>) {
+        self.insert(key, None);
+    }
+
+    /// keep `key` in a separate in-memory map that `get` checks first, so it never has to go past
+    /// the memtable to find it, however many flushes happen
+    ///
+    /// writes to a pinned key still go through the memtable and get flushed like any other, so
+    /// unpinning it doesn't lose anything. pinned keys are never evicted by `with_max_entries`
+    pub fn pin(&mut self, key: Vec<u8>) {
+        let key = match self.normalize(&key) {
+            Cow::Borrowed(_) => key,
+            Cow::Owned(normalized) => normalized,
+        };
+        let record = self.lookup_tree(&key).map(Cow::into_owned);
+        self.pinned.insert(key, record);
+    }
+
+    pub fn unpin(&mut self, key: &[u8]) {
+        let key = self.normalize(key);
+        self.pinned.remove(key.as_ref());
+    }
+
+    /// get a given key
+    ///
+    /// first checks memtable, then iterates through levels newest-to-oldest, binary searching each
+    /// level
+    ///
+    /// with the `checksum` feature the value is checked like in `try_get`, and one that fails the
+    /// check is logged and comes back as `None` rather than handed out corrupted
+    ///
+    // https://corrode.dev/blog/defensive-programming/#pattern-use-must-use-on-important-types
+    #[must_use]
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.try_get(key) {
+            Ok(value) => value,
+            Err(err) => {
+                log::error!("get failed: {err}");
+                None
+            }
+        }
+    }
+
+    /// `insert` with `n` encoded as a big-endian key, so numeric keys scan in numeric order (see
+    /// `BigEndianU64Comparator`)
+    ///
+    /// # Panics
+    ///
+    /// same as `insert`
+    pub fn insert_u64(&mut self, n: u64, value: Option<Vec<u8>>) {
+        self.insert(n.to_be_bytes().to_vec(), value);
+    }
+
+    /// `get` for a key written with `insert_u64`
+    #[must_use]
+    pub fn get_u64(&self, n: u64) -> Option<Vec<u8>> {
+        self.get(&n.to_be_bytes())
+    }
+
+    /// like `get`, but tells a deleted key apart from one that was never written. `get` returns
+    /// `None` for both
+    ///
+    /// the backing store isn't consulted, a key only it has is `Absent`
+    #[must_use]
+    pub fn get_status(&self, key: &[u8]) -> GetStatus {
+        let key = self.normalize(key);
+        match self.lookup(&key) {
+            Some(record) => match &record.value {
+                Some(value) => GetStatus::Present(value.clone()),
+                None => GetStatus::Deleted,
+            },
+            None => GetStatus::Absent,
+        }
+    }
+
+    /// like `get`, but with the sequence number of the write that produced the value. for change
+    /// feeds that need to order what they've seen
+    ///
+    /// the backing store isn't consulted, since its values don't have a sequence number
+    #[must_use]
+    pub fn get_with_seq(&self, key: &[u8]) -> Option<(Vec<u8>, u64)> {
+        let key = self.normalize(key);
+        let record = self.lookup(&key)?;
+        Some((record.value.clone()?, record.seq))
+    }
+
+    /// like `get`, but checks the value against the checksum it was written with first
+    ///
+    /// only does anything with the `checksum` feature, which keeps a crc32 of every value from
+    /// the moment it's inserted. for long-running processes where a flipped bit in memory is a
+    /// real risk
+    ///
+    /// # Errors
+    ///
+    /// `LsmError::Corruption` if the stored value no longer matches its checksum
+    pub fn try_get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, LsmError> {
+        let key = self.normalize(key);
+
+        // appends are folded together on the way out, so check each piece before they are
+        #[cfg(feature = "checksum")]
+        if !self.pinned.contains_key(key.as_ref()) {
+            for record in self.versions(&key) {
+                self.blobs.inline(record).verify(&key)?;
+                if !record.append {
+                    break;
+                }
+            }
+        }
+
+        match self.lookup(&key) {
+            Some(record) => {
+                record.verify(&key)?;
+                Ok(record.value.clone())
+            }
+            None => Ok(self
+                .backing_store
+                .as_ref()
+                .and_then(|store| store.load(&key))),
+        }
+    }
+
+    /// like `get`, but a value loaded from the backing store is also written into the memtable, so
+    /// the next read for it doesn't go back to the store
+    pub fn get_or_load(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let key = self.normalize(key).into_owned();
+        if let Some(record) = self.lookup(&key) {
+            return record.value.clone();
+        }
+
+        let value = self.backing_store.as_ref()?.load(&key)?;
+        self.insert(key, Some(value.clone()));
+        Some(value)
+    }
+
+    /// take a snapshot of the tree as it is right now. reads through `get_at` with it only see
+    /// writes made before it was taken, however much is written or compacted afterwards
+    ///
+    /// the snapshot doesn't borrow the tree, so writes carry on as normal. compaction keeps
+    /// whatever versions it needs until it's dropped
+    #[must_use]
+    pub fn acquire_snapshot(&self) -> Snapshot {
+        Snapshot::new(self.next_seq, &self.snapshots)
+    }
+
+    /// `get` as of `snapshot`
+    #[must_use]
+    pub fn get_at(&self, key: &[u8], snapshot: &Snapshot) -> Option<Vec<u8>> {
+        let key = self.normalize(key);
+        let (visible, _) = self.versions_before(&key, snapshot.seq());
+        match resolve(visible.into_iter(), &self.blobs) {
+            Some(record) => record.value.clone(),
+            None => self.backing_store.as_ref()?.load(&key),
+        }
+    }
+
+    /// `get` for a batch of keys, with the results in the same order as `keys`
+    ///
+    /// the lookups themselves run in key order, so neighbouring keys hit the same partitions back
+    /// to back, and a key that's asked for more than once is only looked up once
+    #[must_use]
+    pub fn get_many(&self, keys: &[Vec<u8>]) -> Vec<Option<Vec<u8>>> {
+        let mut sorted: Vec<(Cow<[u8]>, usize)> = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| (self.normalize(key), i))
+            .collect();
+        sorted.sort_unstable_by(|(a, _), (b, _)| self.comparator().compare(a, b));
+
+        let mut results = vec![None; keys.len()];
+        let mut prev: Option<(&[u8], usize)> = None;
+        for (key, i) in &sorted {
+            results[*i] = match prev {
+                Some((prev_key, prev_i)) if prev_key == key.as_ref() => results[prev_i].clone(),
+                _ => match self.lookup(key) {
+                    Some(record) => record.value.clone(),
+                    None => self
+                        .backing_store
+                        .as_ref()
+                        .and_then(|store| store.load(key)),
+                },
+            };
+            prev = Some((key, *i));
+        }
+        results
+    }
+
+    /// newest version of an already-normalized key, tombstones included
+    fn lookup(&self, key: &[u8]) -> Option<Cow<'_, Record>> {
+        match self.pinned.get(key) {
+            Some(pinned) => pinned.as_ref().map(Cow::Borrowed),
+            None => self.lookup_tree(key),
+        }
+    }
+
+    /// `lookup` without the pinned keys
+    fn lookup_tree(&self, key: &[u8]) -> Option<Cow<'_, Record>> {
+        resolve(self.versions(key), &self.blobs)
+    }
+
+    /// every version of an already-normalized key that can decide a lookup, newest first
+    fn versions<'a>(&'a self, key: &[u8]) -> impl Iterator<Item = &'a Record> {
+        self.versions_before(key, u64::MAX).0.into_iter()
+    }
+
+    /// the versions of an already-normalized key written before `seq`, highest seq first - the
+    /// same order `MergeIter` resolves a key in. between equal seqs the memtable's come first,
+    /// then each frozen memtable's, then each level's. also returns how many levels it searched
+    ///
+    /// a level is only searched if it might hold something newer than the newest full value found
+    /// so far. levels are normally older than the ones above them, so levels past the one that
+    /// answers a lookup are skipped
+    fn versions_before<'a>(&'a self, key: &[u8], seq: u64) -> (Vec<&'a Record>, usize) {
+        let before = |record: &&Record| record.seq < seq;
+        let mut versions: Vec<&Record> = self.memtable_versions(key).filter(before).collect();
+        let mut searched = 0;
+        for level in self.populated_levels() {
+            let newest_full = versions
+                .iter()
+                .filter(|record| !record.append)
+                .map(|record| record.seq)
+                .max();
+            if newest_full.is_some_and(|full| full > level.max_seq)
+                || !level.may_contain(key, self.comparator())
+            {
+                continue;
+            }
+            searched += 1;
+            versions.extend(level.versions(key, self.comparator()).filter(before));
+        }
+
+        // stable, so equal seqs stay in the order they were found in
+        versions.sort_by_key(|record| Reverse(record.seq));
+        (versions, searched)
+    }
+
+    /// the memtable's version of an already-normalized key, then each frozen memtable's, newest
+    /// first
+    fn memtable_versions<'a>(&'a self, key: &[u8]) -> impl Iterator<Item = &'a Record> {
+        self.memtable
+            .get(key)
+            .into_iter()
+            .chain(self.frozen.iter().rev().filter_map(move |f| f.get(key)))
+    }
+
+    /// how many levels `get` would search for `key` before resolving it - the read amplification
+    /// for that one key
+    ///
+    /// levels whose bloom filter rules the key out aren't counted, since they never get searched,
+    /// and neither are levels older than a full value that's already been found (appends keep
+    /// the search going). a key answered by the memtable, a frozen memtable or a pin costs 0
+    #[must_use]
+    pub fn read_cost(&self, key: &[u8]) -> usize {
+        let key = self.normalize(key);
+        if self.pinned.contains_key(key.as_ref()) {
+            return 0;
+        }
+        self.versions_before(&key, u64::MAX).1
+    }
+
+    /// the non-empty levels with their index, newest first - the one place that decides which
+    /// way round the levels go (see `older_level`)
+    fn levels_newest_first(&self) -> impl Iterator<Item = (usize, &LSMLevel)> {
+        self.populated
+            .iter()
+            .filter_map(|&i| Some((i, self.levels[i].as_deref()?)))
+    }
+
+    /// `levels_newest_first` without the indexes
+    fn populated_levels(&self) -> impl Iterator<Item = &LSMLevel> {
+        self.levels_newest_first().map(|(_, level)| level)
+    }
+
+    /// empties a level slot
+    fn take_level(&mut self, i: usize) -> Option<Arc<LSMLevel>> {
+        let level = self.levels.get_mut(i)?.take()?;
+        self.populated.retain(|&p| p != i);
+        Some(level)
+    }
+
+    /// fills a level slot, growing `levels` if it has to
+    fn set_level(&mut self, i: usize, level: LSMLevel) {
+        if i >= self.levels.len() {
+            self.levels.resize_with(i + 1, || None);
+        }
+        self.levels[i] = Some(Arc::new(level));
+        if let Err(pos) = self.populated.binary_search(&i) {
+            self.populated.insert(pos, i);
+        }
+    }
+
+    /// iterate over every live key-value pair, in key order
+    ///
+    /// this is a k-way merge over the memtable and levels (see `MergeIter`), so only the newest
+    /// version of each key is returned, and deleted keys are skipped
+    pub fn iter_live(&self) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> + '_ {
+        self.merged()
+            .filter_map(|(key, record)| Some((key.to_vec(), record.value.clone()?)))
+    }
+
+    /// `map` applied to every live pair, in key order
+    ///
+    /// it runs on the borrowed key and value during the merge pass, so nothing's copied that
+    /// `map` doesn't copy itself - handy for pulling one field out of big values
+    pub fn scan_map<T, F: Fn(&[u8], &[u8]) -> T>(&self, map: F) -> Vec<T> {
+        self.merged()
+            .filter_map(|(key, record)| Some(map(key, record.value.as_deref()?)))
+            .collect()
+    }
+
+    /// every live key in key order, without the values - for set operations between trees
+    #[must_use]
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        self.live_keys().map(<[u8]>::to_vec).collect()
+    }
+
+    /// the live keys in both this tree and `other`, in key order
+    ///
+    /// a single merge walk over both trees' live keys, nothing's collected up front. both have to
+    /// order keys the same way (see `with_comparator`)
+    #[must_use]
+    pub fn intersect_keys(&self, other: &LSMTree) -> Vec<Vec<u8>> {
+        self.walk_keys(other, true)
+    }
+
+    /// the live keys in this tree but not in `other`, in key order. same merge walk as
+    /// `intersect_keys`
+    #[must_use]
+    pub fn difference_keys(&self, other: &LSMTree) -> Vec<Vec<u8>> {
+        self.walk_keys(other, false)
+    }
+
+    /// our live keys that are (`in_other`) or aren't in `other`
+    fn walk_keys(&self, other: &LSMTree, in_other: bool) -> Vec<Vec<u8>> {
+        let cmp = self.comparator();
+        let mut theirs = other.live_keys().peekable();
+        let mut keys = vec![];
+        for key in self.live_keys() {
+            while theirs.next_if(|k| cmp.compare(k, key).is_lt()).is_some() {}
+            let found = theirs.next_if(|k| cmp.compare(k, key).is_eq()).is_some();
+            if found == in_other {
+                keys.push(key.to_vec());
+            }
+        }
+        keys
+    }
+
+    /// the live keys in key order, borrowed from wherever their newest version is
+    fn live_keys(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        self.merged()
+            .filter(|(_, record)| record.value.is_some())
+            .map(|(key, _)| key)
+    }
+
+    /// how many live keys there are. exact - deleted keys and shadowed versions aren't counted -
+    /// and nothing gets cloned, but it's still a merge pass over the whole tree
+    #[must_use]
+    pub fn count_live(&self) -> usize {
+        self.merged()
+            .filter(|(_, record)| record.value.is_some())
+            .count()
+    }
+
+    /// `iter_live`, with the sequence number of the write that produced each value
+    pub fn iter_live_with_seq(&self) -> impl Iterator<Item = (Vec<u8>, Vec<u8>, u64)> + '_ {
+        self.merged()
+            .filter_map(|(key, record)| Some((key.to_vec(), record.value.clone()?, record.seq)))
+    }
+
+    /// every key last written at or after `seq` (say, an earlier `next_seq`), in key order, with
+    /// its newest value - `None` if that was a delete
+    ///
+    /// a key written several times since shows up once, as it is now, so a sink applying these
+    /// in order ends up matching the tree. a tombstone that compaction has already dropped from
+    /// the bottom level can't be reported, so take a snapshot at `seq` to be sure of seeing them
+    #[must_use]
+    pub fn changes_since_sorted(&self, seq: u64) -> Vec<Entry> {
+        self.merged()
+            .filter(|(_, record)| record.seq >= seq)
+            .map(|(key, record)| (key.to_vec(), record.value.clone()))
+            .collect()
+    }
+
+    /// every version still stored that was written with `lo <= seq < hi`, as `(key, value, seq)`
+    /// in seq order. a delete is a `None`, an append just the suffix it wrote
+    ///
+    /// this reads what's physically there, so a version that got overwritten has usually been
+    /// collapsed away by the time it's asked for - the memtable only holds one version per key,
+    /// and compaction drops shadowed ones (and tombstones with nothing left under them). only
+    /// versions a snapshot could see are kept, so for a full audit trail hold a snapshot after
+    /// every write you need to see
+    #[must_use]
+    pub fn versions_between(&self, lo: u64, hi: u64) -> Vec<(Vec<u8>, Option<Vec<u8>>, u64)> {
+        let memtables = std::iter::once(&self.memtable).chain(self.frozen.iter().map(|f| &**f));
+        let levels = self
+            .populated_levels()
+            .flat_map(|level| level.entries().map(|(key, record)| (key, record)));
+        let mut versions: Vec<_> = memtables
+            .flat_map(|memtable| memtable.iter())
+            .chain(levels)
+            .filter(|(_, record)| (lo..hi).contains(&record.seq))
+            .map(|(key, record)| {
+                let value = self.blobs.inline(record).value.clone();
+                (key.clone(), value, record.seq)
+            })
+            .collect();
+        versions.sort_by_key(|&(_, _, seq)| seq);
+        versions
+    }
+
+    /// the live pairs with `start <= key < end`, in key order
+    ///
+    /// levels whose key range (see `level_key_ranges`) doesn't overlap the query aren't read at
+    /// all, so a narrow range over recent keys never touches the big levels at the bottom
+    #[must_use]
+    pub fn range(&self, start: &[u8], end: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let start = self.normalize(start);
+        let end = self.normalize(end);
+        let cmp = self.comparator();
+        let after_start = |key: &[u8]| cmp.compare(key, &start).is_ge();
+        let before_end = |key: &[u8]| cmp.compare(key, &end).is_lt();
+
+        // newest first - the memtable, each frozen memtable, then each level
+        let mut sources: Vec<Box<dyn Iterator<Item = EntryRef<'_>> + Send + '_>> = vec![];
+        let memtables =
+            std::iter::once(&self.memtable).chain(self.frozen.iter().rev().map(|f| &**f));
+        for memtable in memtables {
+            let entries = memtable.entries_from(&start, self.comparator.as_deref());
+            sources.push(Box::new(entries.take_while(|(k, _)| before_end(k))));
+        }
+
+        for level in self.populated_levels() {
+            let Some((first, last)) = level.key_range() else {
+                continue;
+            };
+            if !after_start(last) || !before_end(first) {
+                continue;
+            }
+
+            let entries: Vec<EntryRef<'_>> = level
+                .entries_from(&start, cmp)
+                .map(|(k, r)| (k.as_slice(), r))
+                .take_while(|(k, _)| before_end(k))
+                .collect();
+            sources.push(Box::new(entries.into_iter()));
+        }
+
+        MergeIter::new(sources, cmp, &self.blobs)
+            .filter_map(|(key, record)| Some((key.to_vec(), record.value.clone()?)))
+            .collect()
+    }
+
+    /// each level's first and last key, `None` for an empty slot. indexed like the levels, newest
+    /// first, for pruning levels a range scan doesn't need to read
+    ///
+    /// the range covers every version the level holds, tombstones included
+    #[must_use]
+    pub fn level_key_ranges(&self) -> Vec<Option<(Vec<u8>, Vec<u8>)>> {
+        self.levels
+            .iter()
+            .map(|level| {
+                let (first, last) = level.as_deref()?.key_range()?;
+                Some((first.to_vec(), last.to_vec()))
+            })
+            .collect()
+    }
+
+    /// every key's newest version in key order, tombstones included as `None`. a canonical form
+    /// for comparing trees in tests - it doesn't depend on how writes were split across the
+    /// memtable and levels, only on what each key resolves to
+    ///
+    /// a tree that deleted a key isn't equal to one that never had it, since the tombstone shows
+    /// up here
+    #[must_use]
+    pub fn collect_sorted(&self) -> Vec<Entry> {
+        self.merged()
+            .map(|(key, record)| (key.to_vec(), record.value.clone()))
+            .collect()
+    }
+
+    /// SHA-256 over every live pair in key order, for checking two replicas hold the same data
+    /// without shipping it. only what the keys resolve to goes in, so it doesn't depend on write
+    /// order or how the levels are laid out. tombstones are left out
+    ///
+    /// each key and value is hashed with its length in front, so `("ab", "c")` and `("a", "bc")`
+    /// don't collide
+    #[must_use]
+    pub fn content_hash(&self) -> [u8; 32] {
+        hash_pairs(
+            self.merged()
+                .filter_map(|(key, record)| Some((key, record.value.clone()?))),
+        )
+    }
+
+    /// `content_hash` for just the live pairs with `start <= key < end`. two replicas can compare
+    /// halves of a range, then halves of whichever half differs, and so on down to the keys that
+    /// are out of sync, without transferring the rest
+    ///
+    /// a range covering every key hashes the same as `content_hash`
+    #[must_use]
+    pub fn range_hash(&self, start: &[u8], end: &[u8]) -> [u8; 32] {
+        let pairs = self.range(start, end);
+        hash_pairs(pairs.iter().map(|(key, value)| (key.as_slice(), value)))
+    }
+
+    /// every live value, in key order. like `iter_live` without cloning the keys, for passes that
+    /// only care about values (sums, averages, ...)
+    pub fn values(&self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        self.merged().filter_map(|(_, record)| record.value.clone())
+    }
+
+    /// co-iterate the live pairs with another stream of pairs, for merge joins
+    ///
+    /// `other` has to be sorted by key with no duplicates, same as the tree. every key from either
+    /// side comes out once, in key order, tagged with which side(s) it came from
+    pub fn merge_with<I: Iterator<Item = (Vec<u8>, Vec<u8>)>>(
+        &self,
+        other: I,
+    ) -> impl Iterator<Item = MergeSide> {
+        MergeJoin::new(self.iter_live(), other, self.comparator())
+    }
+
+    /// call `f` with every live pair, in key order, without cloning anything
+    ///
+    /// the slices are only valid for the duration of the call. today they point straight into the
+    /// memtable and levels, but `f` can't hold onto them, so a value that had to be decoded into a
+    /// temporary buffer (e.g. out of a compressed block) can be handed over the same way
+    pub fn for_each_live<F: FnMut(&[u8], &[u8])>(&self, mut f: F) {
+        for (key, record) in self.merged() {
+            if let Some(value) = &record.value {
+                f(key, value);
+            }
+        }
+    }
+
+    /// collect the live pairs where `pred(key, value)` is true
+    ///
+    /// the predicate runs on borrowed slices inside the merge pass, so only matching pairs ever
+    /// get cloned
+    pub fn scan_filter<F: Fn(&[u8], &[u8]) -> bool>(&self, pred: F) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.merged()
+            .filter_map(|(key, record)| {
+                let value = record.value.as_deref()?;
+                pred(key, value).then(|| (key.to_vec(), value.to_vec()))
+            })
+            .collect()
+    }
+
+    /// `key`'s value along with the live keys right before and after it, as (value, previous,
+    /// next). `key` doesn't have to exist - its neighbors are wherever it would sort
+    ///
+    /// every source seeks to `key`, then merges forwards for the value and the next key, and
+    /// backwards for the previous one. tombstones on either side are skipped over. unlike `get`,
+    /// the backing store isn't consulted
+    #[must_use]
+    pub fn get_with_neighbors(&self, key: &[u8]) -> Neighbors {
+        let key = self.normalize(key);
+        let cmp = self.comparator();
+        let mut after = self
+            .merged_from(Some(&key))
+            .filter(|(_, record)| record.value.is_some());
+        let (value, next) = match after.next() {
+            Some((k, record)) if cmp.compare(k, &key).is_eq() => {
+                (record.value.clone(), after.next().map(|(k, _)| k.to_vec()))
+            }
+            next => (None, next.map(|(k, _)| k.to_vec())),
+        };
+
+        let reversed = Reversed(cmp);
+        let prev = MergeIter::new(self.sources_before(&key), &reversed, &self.blobs)
+            .find(|(_, record)| record.value.is_some())
+            .map(|(k, _)| k.to_vec());
+        (value, prev, next)
+    }
+
+    /// the distinct segments right under `prefix`, like a directory listing - for a key
+    /// `prefix + "child" + sep + ...` that's `"child"`, however deep the rest of the key goes
+    ///
+    /// `prefix` usually ends with `sep` (`/usr/`). it's normalized like any other key. comes back
+    /// sorted
+    #[must_use]
+    pub fn list_children(&self, prefix: &[u8], sep: u8) -> Vec<Vec<u8>> {
+        let prefix = self.normalize(prefix);
+        let mut children = BTreeSet::new();
+
+        // with a custom comparator keys sharing a prefix aren't necessarily next to each other,
+        // so this has to look at every key
+        for (key, record) in self.merged() {
+            if record.value.is_none() || !key.starts_with(&prefix) {
+                continue;
+            }
+
+            let rest = &key[prefix.len()..];
+            let child = rest.split(|&b| b == sep).next().unwrap_or_default();
+            if !child.is_empty() {
+                children.insert(child);
+            }
+        }
+
+        children.into_iter().map(<[u8]>::to_vec).collect()
+    }
+
+    /// the first `limit` live pairs whose key starts with `prefix`, in key order. the cap keeps a
+    /// short prefix from turning into a full scan of everything after it
+    ///
+    /// an empty prefix matches every key, the empty key (which sorts first) included
+    ///
+    /// `prefix` is normalized like any other key. in byte order the scan seeks to the prefix and
+    /// stops at the first key past it. with a custom comparator keys sharing a prefix can be
+    /// anywhere, so it keeps going until it has `limit` of them (see `prefixed`)
+    #[must_use]
+    pub fn scan_prefix_limited(&self, prefix: &[u8], limit: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let prefix = self.normalize(prefix);
+        self.prefixed(&prefix)
+            .filter_map(|(key, record)| Some((key.to_vec(), record.value.clone()?)))
+            .take(limit)
+            .collect()
+    }
+
+    /// the live pairs whose key's first component is `component`, for composite keys laid out as
+    /// `len (u32 be) | component | rest`. keys too short to hold their own length prefix never
+    /// match
+    ///
+    /// `component` is matched against the stored keys as is, without normalizing. the matching
+    /// keys are exactly the ones starting with `len | component`, so in byte order this is a seek
+    /// and a scan over just them (see `prefixed`)
+    #[must_use]
+    pub fn scan_component(&self, component: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        // too long for its own length prefix, so no key can hold it
+        let Ok(len) = u32::try_from(component.len()) else {
+            return vec![];
+        };
+        let prefix = [&len.to_be_bytes()[..], component].concat();
+        self.prefixed(&prefix)
+            .filter_map(|(key, record)| Some((key.to_vec(), record.value.clone()?)))
+            .collect()
+    }
+
+    /// the newest version of every key starting with an already-normalized `prefix`, in key
+    /// order, tombstones included
+    ///
+    /// in byte order keys sharing a prefix sit next to each other, so this seeks to `prefix` and
+    /// stops at the first key past it. with a custom comparator they can be anywhere, so it's a
+    /// full pass
+    fn prefixed<'a>(
+        &'a self,
+        prefix: &'a [u8],
+    ) -> impl Iterator<Item = (&'a [u8], Cow<'a, Record>)> + 'a {
+        let contiguous = self.comparator.is_none();
+        self.merged_from(contiguous.then_some(prefix))
+            .take_while(move |(key, _)| !contiguous || key.starts_with(prefix))
+            .filter(move |(key, _)| key.starts_with(prefix))
+    }
+
+    /// key and value size statistics across every live entry, for right-sizing the flush
+    /// threshold and block size
+    #[must_use]
+    pub fn size_distribution(&self) -> SizeDistribution {
+        let mut key_sizes = vec![];
+        let mut value_sizes = vec![];
+
+        for (key, record) in self.merged() {
+            if let Some(value) = &record.value {
+                key_sizes.push(key.len());
+                value_sizes.push(value.len());
+            }
+        }
+
+        SizeDistribution {
+            count: key_sizes.len(),
+            keys: SizeStats::from_sizes(key_sizes),
+            values: SizeStats::from_sizes(value_sizes),
+        }
+    }
+
+    /// per-level counts of live entries and tombstones, for seeing how much dead data compaction
+    /// is carrying around. one entry per level slot, empty levels included
+    ///
+    /// these are raw counts - an entry shadowed by a newer version in a shallower level still
+    /// counts as live in its own level
+    #[must_use]
+    pub fn level_stats(&self) -> Vec<LevelStat> {
+        self.levels
+            .iter()
+            .enumerate()
+            .map(|(index, level)| {
+                let mut stat = LevelStat {
+                    index,
+                    capacity: self.level_capacity(index),
+                    ..LevelStat::default()
+                };
+                for (key, record) in level.iter().flat_map(|level| level.entries()) {
+                    if let Some(len) = record.value_len() {
+                        stat.live += 1;
+                        stat.bytes += key.len() + len;
+                    } else {
+                        stat.tombstones += 1;
+                        stat.bytes += key.len();
+                    }
+                }
+                stat
+            })
+            .collect()
+    }
+
+    /// how big level data would be prefix-compressed (see `block::PrefixBlock`), as a fraction of
+    /// its key + value bytes, going by the `sample_levels` newest populated levels. below 1 means
+    /// compression would pay off, around 1 (or over, from the per-entry headers) means it won't
+    ///
+    /// values moved out to blob files are read back in and counted like any other. the blocks are
+    /// built and thrown away - nothing in the tree is compressed. the ratio is
+    /// weighted by bytes across the sampled levels, and 1.0 if there's nothing to sample
+    #[must_use]
+    // usize -> f64 only loses precision past 2^52 bytes
+    #[allow(clippy::cast_precision_loss)]
+    pub fn estimate_compression_ratio(&self, sample_levels: usize) -> f64 {
+        let (mut raw, mut compressed) = (0, 0);
+        for level in self.populated_levels().take(sample_levels) {
+            let entries: Vec<Entry> = level
+                .entries()
+                .map(|(key, record)| (key.clone(), self.blobs.inline(record).into_owned().value))
+                .collect();
+            raw += entries
+                .iter()
+                .map(|(key, value)| key.len() + value.as_ref().map_or(0, Vec::len))
+                .sum::<usize>();
+            compressed += PrefixBlock::encode(&entries, SAMPLE_RESTART_INTERVAL).encoded_len();
+        }
+
+        if raw == 0 {
+            return 1.0;
+        }
+        compressed as f64 / raw as f64
+    }
+
+    /// `size_distribution` and `level_stats` together, plus the memtable size
+    #[must_use]
+    pub fn stats(&self) -> Stats {
+        Stats {
+            sizes: self.size_distribution(),
+            levels: self.level_stats(),
+            memtable: self.memtable.len(),
+            stall_count: self.stall_count,
+            total_stall_duration: self.total_stall_duration,
+        }
+    }
+
+    /// `stats` as a JSON object, for dashboards and anything else that wants machine-readable
+    /// output
+    ///
+    /// # Panics
+    ///
+    /// never in practice - the stats are plain numbers, which always serialize
+    #[cfg(feature = "serde")]
+    #[must_use]
+    pub fn stats_json(&self) -> String {
+        serde_json::to_string(&self.stats()).expect("stats always serialize")
+    }
+
+    /// every key's newest version (tombstones included) in key order, borrowed from the memtable
+    /// and levels
+    fn merged(&self) -> MergeIter<'_> {
+        self.merged_from(None)
+    }
+
+    /// `merged`, starting from the first key at or after an already-normalized `start`. every
+    /// source seeks to it first, so nothing before it is read
+    fn merged_from(&self, start: Option<&[u8]>) -> MergeIter<'_> {
+        let cmp = self.comparator();
+        let memtables =
+            std::iter::once(&self.memtable).chain(self.frozen.iter().rev().map(|f| &**f));
+        let mut sources: Vec<Box<dyn Iterator<Item = EntryRef<'_>> + Send + '_>> = vec![];
+        for memtable in memtables {
+            sources.push(match start {
+                None => memtable.entries(self.comparator.as_deref()),
+                Some(start) => memtable.entries_from(start, self.comparator.as_deref()),
+            });
+        }
+
+        for level in self.populated_levels() {
+            match start {
+                None => sources.push(Box::new(level.entries().map(|(k, r)| (k.as_slice(), r)))),
+                Some(start) => sources.push(Box::new(
+                    level
+                        .entries_from(start, cmp)
+                        .map(|(k, r)| (k.as_slice(), r)),
+                )),
+            }
+        }
+
+        MergeIter::new(sources, cmp, &self.blobs)
+    }
+
+    /// every source's entries before an already-normalized `end`, newest source first like
+    /// `merged`, each in reverse key order - for merging backwards with `Reversed`
+    fn sources_before(
+        &self,
+        end: &[u8],
+    ) -> Vec<Box<dyn Iterator<Item = EntryRef<'_>> + Send + '_>> {
+        let cmp = self.comparator();
+        let memtables =
+            std::iter::once(&self.memtable).chain(self.frozen.iter().rev().map(|f| &**f));
+        let mut sources: Vec<Box<dyn Iterator<Item = EntryRef<'_>> + Send + '_>> = vec![];
+        for memtable in memtables {
+            sources.push(memtable.entries_before(end, self.comparator.as_deref()));
+        }
+
+        for level in self.populated_levels() {
+            sources.push(Box::new(
+                level
+                    .entries_before(end, cmp)
+                    .map(|(k, r)| (k.as_slice(), r)),
+            ));
+        }
+        sources
+    }
+
+    /// returns a copy of a single level's raw contents, for debugging compaction
+    ///
+    /// unlike `get` this doesn't resolve newest-wins across levels - tombstones are kept and
+    /// entries come back in the order they're stored. `None` if the level is empty or doesn't
+    /// exist
+    #[must_use]
+    pub fn level_entries(&self, level: usize) -> Option<Vec<Entry>> {
+        let level = self.levels.get(level)?.as_ref()?;
+        Some(
+            level
+                .entries()
+                .map(|(k, r)| (k.clone(), self.blobs.inline(r).value.clone()))
+                .collect(),
+        )
+    }
+
+    /// every raw entry with `start <= key < end`, grouped by where it's stored - newest first, the
+    /// memtable and any frozen ones under `MEMTABLE_SOURCE`, then each level under its index.
+    /// sources with nothing in the range are left out
+    ///
+    /// for seeing where data lives, not for reading it - nothing is resolved, so a key can show up
+    /// in several sources, tombstones are kept, and an appended value is just the operand
+    #[must_use]
+    pub fn scan_by_level(&self, start: &[u8], end: &[u8]) -> Vec<(usize, Vec<Entry>)> {
+        let start = self.normalize(start);
+        let end = self.normalize(end);
+        let cmp = self.comparator();
+        let in_range =
+            |key: &[u8]| cmp.compare(key, &start).is_ge() && cmp.compare(key, &end).is_lt();
+        let raw =
+            |(key, record): EntryRef<'_>| (key.to_vec(), self.blobs.inline(record).value.clone());
+
+        let mut sources = vec![];
+        let memtables =
+            std::iter::once(&self.memtable).chain(self.frozen.iter().rev().map(|f| &**f));
+        for memtable in memtables {
+            let entries: Vec<Entry> = memtable
+                .entries(self.comparator.as_deref())
+                .filter(|(key, _)| in_range(key))
+                .map(raw)
+                .collect();
+            sources.push((MEMTABLE_SOURCE, entries));
+        }
+        for (i, level) in self.levels_newest_first() {
+            let entries = level
+                .entries()
+                .filter(|(key, _)| in_range(key))
+                .map(|(key, record)| raw((key, record)))
+                .collect();
+            sources.push((i, entries));
+        }
+
+        sources.retain(|(_, entries)| !entries.is_empty());
+        sources
+    }
+
+    /// checks every level is sorted, and that its partitions cover disjoint key ranges in order
+    ///
+    /// # Errors
+    ///
+    /// a description of the first problem found, with the offending keys
+    pub fn check_no_overlap(&self) -> Result<(), String> {
+        let show = |key: &[u8]| format!("{:?}", String::from_utf8_lossy(key));
+
+        for (i, level) in self.levels.iter().enumerate() {
+            let Some(level) = level else {
+                continue;
+            };
+
+            for (p, partition) in level.partitions.iter().enumerate() {
+                // a key can repeat when a snapshot holds older versions, but only newest first
+                for pair in partition.data.windows(2) {
+                    let ((a, older), (b, newer)) = (&pair[0], &pair[1]);
+                    let ordering = self.comparator().compare(a, b);
+                    if ordering.is_gt() || (ordering.is_eq() && older.seq <= newer.seq) {
+                        return Err(format!(
+                            "L{i} partition {p} is out of order: {} before {}",
+                            show(a),
+                            show(b)
+                        ));
+                    }
+                }
+            }
+
+            for (p, pair) in level.partitions.windows(2).enumerate() {
+                let (left, right) = (&pair[0].data, &pair[1].data);
+                let (Some((left_first, _)), Some((left_last, _))) = (left.first(), left.last())
+                else {
+                    continue;
+                };
+                let (Some((right_first, _)), Some((right_last, _))) = (right.first(), right.last())
+                else {
+                    continue;
+                };
+                if self.comparator().compare(left_last, right_first).is_ge() {
+                    return Err(format!(
+                        "L{i} partition {p} [{}..={}] overlaps partition {} [{}..={}]",
+                        show(left_first),
+                        show(left_last),
+                        p + 1,
+                        show(right_first),
+                        show(right_last)
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// checks the tree's internal invariants - see `check_no_overlap`
+    ///
+    /// # Errors
+    ///
+    /// `LsmError::Corruption` describing the first broken invariant
+    pub fn verify(&self) -> Result<(), LsmError> {
+        self.check_no_overlap().map_err(LsmError::Corruption)?;
+
+        let populated: Vec<usize> = (0..self.levels.len())
+            .filter(|&i| self.levels[i].is_some())
+            .collect();
+        if populated != self.populated {
+            return Err(LsmError::Corruption(format!(
+                "populated level index {:?} doesn't match levels {populated:?}",
+                self.populated
+            )));
+        }
+        Ok(())
+    }
+
+    /// best-effort recovery for a level that can't be trusted - throws it away and lets reads fall
+    /// through to whatever older versions the deeper levels have
+    ///
+    /// keys that were also written in the memtable or a shallower level aren't affected, since
+    /// the newer version still wins. everything else the level was serving is listed in the
+    /// report, either as regressed to an older value or as lost - a key whose older version is a
+    /// tombstone reads as deleted now, so that's lost too
+    pub fn repair_level(&mut self, level: usize) -> RepairReport {
+        let mut report = RepairReport {
+            level,
+            ..RepairReport::default()
+        };
+        let Some(dropped) = self.take_level(level) else {
+            return report;
+        };
+        report.dropped = dropped.len();
+
+        let mut keys: Vec<&Vec<u8>> = dropped.entries().map(|(key, _)| key).collect();
+        keys.dedup();
+        for key in keys {
+            let shadowed = self.memtable.contains_key(key)
+                || self.frozen.iter().any(|f| f.contains_key(key))
+                || self
+                    .levels_newest_first()
+                    .take_while(|&(i, _)| i < level)
+                    .any(|(_, l)| l.get(key, self.comparator()).is_some());
+            if shadowed {
+                continue;
+            }
+
+            // nothing newer has the key and the dropped level's slot is empty now, so this is
+            // whatever the deeper levels have
+            let older = self.lookup_tree(key);
+            if older.is_some_and(|record| record.value.is_some()) {
+                report.regressed.push(key.clone());
+            } else {
+                report.lost.push(key.clone());
+            }
+        }
+        report
+    }
+
+    /// every physical version of `key`, newest first, as (value or tombstone, level, seq). the
+    /// level is `None` for the memtable and frozen memtables
+    ///
+    /// unlike `get`, shadowed versions are included - for working out why a key read stale
+    #[must_use]
+    pub fn debug_versions(&self, key: &[u8]) -> Vec<(Option<Vec<u8>>, Option<usize>, u64)> {
+        let key = self.normalize(key);
+        let memtable = self.memtable_versions(&key).map(|record| (record, None));
+        let levels = self.levels_newest_first().flat_map(|(i, level)| {
+            level
+                .versions(&key, self.comparator())
+                .map(move |record| (record, Some(i)))
+        });
+
+        memtable
+            .chain(levels)
+            .map(|(record, level)| (self.blobs.inline(record).value.clone(), level, record.seq))
+            .collect()
+    }
+
+    /// load a batch of pairs straight into the levels as a single sorted run, instead of pushing
+    /// them through the memtable one at a time
+    ///
+    /// the input doesn't need to be sorted. `policy` decides what happens when a key shows up more
+    /// than once. the loaded pairs are newer than anything already in the tree
+    ///
+    /// # Errors
+    ///
+    /// `LsmError::InvalidArgument` if the input has a duplicate key and `policy` is
+    /// `DuplicatePolicy::Error`, `LsmError::ReadOnly` if the tree is. nothing is loaded in either
+    /// case
+    pub fn bulk_load(
+        &mut self,
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+        policy: DuplicatePolicy,
+    ) -> Result<(), LsmError> {
+        if self.poisoned {
+            return Err(LsmError::Poisoned);
+        }
+        if self.read_only {
+            return Err(LsmError::ReadOnly);
+        }
+        let mut entries: Vec<(Vec<u8>, Vec<u8>)> = entries
+            .into_iter()
+            .map(|(key, value)| (self.normalize(&key).into_owned(), value))
+            .collect();
+
+        // stable, so duplicates stay in input order
+        entries.sort_by(|a, b| self.comparator().compare(&a.0, &b.0));
+
+        let mut deduped: Vec<(Vec<u8>, Vec<u8>)> = Vec::with_capacity(entries.len());
+        for (key, value) in entries {
+            match deduped.last_mut() {
+                Some(last) if last.0 == key => match policy {
+                    DuplicatePolicy::KeepFirst => {}
+                    DuplicatePolicy::KeepLast => last.1 = value,
+                    DuplicatePolicy::Error => {
+                        return Err(LsmError::InvalidArgument(format!(
+                            "duplicate key in bulk load: {key:?}"
+                        )));
+                    }
+                },
+                _ => deduped.push((key, value)),
+            }
+        }
+
+        if deduped.is_empty() {
+            return Ok(());
+        }
+
+        // the memtable is older than the load, so it has to sit below it
+        if !self.is_memtable_empty() {
+            self.flush_memtable();
+        }
+
+        let mut data: Vec<VersionedEntry> = deduped
+            .into_iter()
+            .map(|(key, value)| {
+                let seq = self.next_seq;
+                self.next_seq += 1;
+                (key, Record::new(Some(value), seq))
+            })
+            .collect();
+        self.offload(&mut data);
+        self.guarded(|tree| {
+            tree.merge_into_level(NEWEST_LEVEL, Cow::Owned(data));
+            tree.evict();
+        });
+        if self.poisoned {
+            return Err(LsmError::Poisoned);
+        }
+        self.refresh_pinned();
+        Ok(())
+    }
+
+    /// merge every live entry of `other` into this tree. for keys both trees have, whichever
+    /// version has the higher sequence number wins - `other` doesn't win just because it's being
+    /// merged in
+    ///
+    /// that's only meaningful if both trees hand out sequence numbers from the same space (e.g.
+    /// shards seeded with `with_next_seq` so their writes never reuse a seq). afterwards this
+    /// tree's next seq is past both trees', so new writes are newer than anything absorbed
+    ///
+    /// `other` is read in a single merged pass, and the entries that win go in as one run through
+    /// the normal compaction path. this tree's memtable is flushed first so the absorbed run can
+    /// sit above everything it's newer than
+    // `other` is taken by value on purpose - once absorbed, the shard shouldn't be used again
+    #[allow(clippy::needless_pass_by_value)]
+    pub fn absorb(&mut self, other: LSMTree) {
+        self.assert_not_poisoned();
+        if !self.is_memtable_empty() {
+            self.flush_memtable();
+        }
+
+        // tombstones come along too, a newer delete in `other` has to shadow our value
+        let mut newer: Vec<VersionedEntry> = other
+            .merged()
+            .filter(|(key, record)| {
+                self.lookup(key)
+                    .is_none_or(|existing| existing.seq < record.seq)
+            })
+            .map(|(key, record)| (key.to_vec(), record.into_owned()))
+            .collect();
+
+        self.next_seq = self.next_seq.max(other.next_seq);
+
+        if !newer.is_empty() {
+            self.offload(&mut newer);
+            self.guarded(|tree| tree.merge_into_level(NEWEST_LEVEL, Cow::Owned(newer)));
+            self.refresh_pinned();
+        }
+    }
+
+    /// merge every version of the keys in `start..end` down into the bottom level, dropping
+    /// shadowed versions and tombstones along the way. keys outside the range aren't touched
+    ///
+    /// tombstones are otherwise never dropped, so this is the way to reclaim the space after
+    /// deleting a run of keys. a tombstone is kept if a backing store is set (it's hiding the
+    /// store's value) or a live snapshot still needs the version under it
+    ///
+    /// frozen memtables are flushed first, so the versions in them go through the merge too
+    /// rather than being left above it
+    pub fn compact_range(&mut self, start: &[u8], end: &[u8]) {
+        self.assert_not_poisoned();
+        let start = self.normalize(start).into_owned();
+        let end = self.normalize(end).into_owned();
+        if let Some(newest) = self.frozen.len().checked_sub(1) {
+            self.flush_frozen_through(newest);
+        }
+
+        // the memtable's entries in the range are taken out here rather than in the merge, so
+        // they can go back if it panics. finding them needs the comparator, which could panic too
+        let mut keys: Vec<Vec<u8>> = vec![];
+        self.guarded(|tree| {
+            let cmp = tree.comparator();
+            keys = tree
+                .memtable
+                .keys()
+                .filter(|k| cmp.compare(k, &start).is_ge() && cmp.compare(k, &end).is_lt())
+                .cloned()
+                .collect();
+        });
+        let mut memtable: Vec<VersionedEntry> = keys
+            .into_iter()
+            .filter_map(|k| self.memtable.remove_entry(&k))
+            .collect();
+        let blobs = self.blobs.clone();
+        self.offload(&mut memtable);
+
+        self.guarded(|tree| tree.merge_range_down(&start, &end, &mut memtable));
+        if self.poisoned {
+            let memtable = self.unflushed(memtable);
+            self.memtable.extend(memtable);
+            self.blobs = blobs;
+        }
+        self.refresh_pinned();
+    }
+
+    /// `memtable` is the memtable's entries in the range, already taken out of it
+    fn merge_range_down(&mut self, start: &[u8], end: &[u8], memtable: &mut [VersionedEntry]) {
+        let cmp = self.comparator.clone();
+        let cmp: &dyn Comparator = cmp.as_deref().unwrap_or(&Bytewise);
+        let in_range =
+            |key: &[u8]| cmp.compare(key, start).is_ge() && cmp.compare(key, end).is_lt();
+
+        // newest first - the memtable, then each level
+        memtable.sort_by(|(a, _), (b, _)| cmp.compare(a, b));
+        let mut runs: Vec<Vec<VersionedEntry>> = vec![];
+
+        let bottom = self.populated.last().copied().unwrap_or(0);
+        let mut rest = vec![];
+        for i in self.populated.clone() {
+            if !self.levels[i]
+                .as_ref()
+                .is_some_and(|l| l.entries().any(|(k, _)| in_range(k)))
+            {
+                continue;
+            }
+            let Some(level) = self.take_level(i) else {
+                continue;
+            };
+            let (inside, outside): (Vec<_>, Vec<_>) = Arc::unwrap_or_clone(level)
+                .into_data()
+                .into_iter()
+                .partition(|(k, _)| in_range(k));
+            runs.push(inside);
+            if i == bottom {
+                rest = outside;
+            } else if !outside.is_empty() {
+                let level = self.new_level(i, outside);
+                self.set_level(i, level);
+            }
+        }
+
+        let input = memtable.len() + runs.iter().map(Vec::len).sum::<usize>();
+        if input == 0 {
+            return;
+        }
+        let data = runs
+            .iter()
+            .rev()
+            .map(Vec::as_slice)
+            .chain([&*memtable])
+            .fold(vec![], |older, newer| merge_sorted(&older, newer, cmp));
+        let data = retain_visible(data, &snapshot::live(&self.snapshots));
+
+        // nothing in the tree is older than these, so a key's oldest version can lose its
+        // tombstone, and an append operand has nothing left to go on top of
+        let mut compacted: Vec<VersionedEntry> = Vec::with_capacity(data.len());
+        let mut purged = 0;
+        let mut entries = data.into_iter().peekable();
+        while let Some((key, record)) = entries.next() {
+            let oldest = entries.peek().is_none_or(|(next, _)| *next != key);
+            if oldest && record.value.is_none() && self.backing_store.is_none() {
+                purged += 1;
+                continue;
+            }
+            let record = if oldest && record.append {
+                record.apply(None)
+            } else {
+                record
+            };
+            compacted.push((key, record));
+        }
+
+        log::debug!(
+            "compact_range level={bottom} input={input} output={} tombstones={} \
+             tombstones_purged={purged}",
+            compacted.len(),
+            tombstones(&compacted),
+        );
+
+        // `rest` is all outside the range, so this just slots the two together
+        let data = merge_sorted(&rest, &compacted, cmp);
+        if !data.is_empty() {
+            let level = self.new_level(bottom, data);
+            self.set_level(bottom, level);
+        }
+    }
+
+    /// re-reads every pinned key, for writes that go straight into the levels instead of through
+    /// `insert`
+    fn refresh_pinned(&mut self) {
+        let keys: Vec<Vec<u8>> = self.pinned.keys().cloned().collect();
+        for key in keys {
+            let record = self.lookup_tree(&key).map(Cow::into_owned);
+            self.pinned.insert(key, record);
+        }
+    }
+
+    /// flushes memtable data to level 0
+    fn flush_memtable(&mut self) {
+        self.flush_memtable_into(NEWEST_LEVEL);
+    }
+
+    fn flush_memtable_into(&mut self, level: usize) {
+        // frozen memtables are older, so they go down first
+        let mut memtables: Vec<_> = std::mem::take(&mut self.frozen)
+            .into_iter()
+            .map(|entries| (Arc::unwrap_or_clone(entries), false))
+            .collect();
+
+        // std::mem::take takes ownership of the value and replaces with an empty value
+        let memtable = std::mem::take(&mut self.memtable);
+        memtables.push((memtable, self.memtable_in_order));
+
+        if let Err(mut unflushed) = self.flush_memtables(memtables, level) {
+            self.memtable = unflushed.pop().unwrap_or_default();
+            self.frozen = unflushed.into_iter().map(Arc::new).collect();
+        }
+    }
+
+    /// merges memtables into `level`, oldest first, each with whether it's known to iterate in
+    /// the comparator's order already
+    ///
+    /// the merge only borrows the entries, so if it panics they're still here to hand back (see
+    /// `guarded`), for the caller to put back where they came from
+    fn flush_memtables(
+        &mut self,
+        memtables: Vec<(Memtable, bool)>,
+        level: usize,
+    ) -> Result<(), Vec<Memtable>> {
+        // the map's iterator knows its length, so each of these allocates once
+        let mut runs: Vec<(Vec<VersionedEntry>, bool)> = memtables
+            .into_iter()
+            .map(|(memtable, in_order)| (memtable.into_iter().collect(), in_order))
+            .collect();
+        let blobs = self.blobs.clone();
+        for (run, _) in &mut runs {
+            self.offload(run);
+        }
+
+        self.guarded(|tree| {
+            for (run, in_order) in &mut runs {
+                tree.flush_run(run, *in_order, level);
+            }
+            tree.evict();
+        });
+        if self.poisoned {
+            let unflushed = runs
+                .into_iter()
+                .map(|(run, _)| self.unflushed(run))
+                .collect();
+            self.blobs = blobs;
+            return Err(unflushed);
+        }
+
+        if let Some(pool) = &mut self.buffer_pool {
+            for (run, _) in runs {
+                pool.recycle(run);
+            }
+        }
+        Ok(())
+    }
+
+    /// merges a memtable's worth of entries into `level`, sorting them first unless `in_order`
+    fn flush_run(&mut self, run: &mut [VersionedEntry], in_order: bool, level: usize) {
+        if run.is_empty() {
+            return;
+        }
+
+        if let Some(cmp) = &self.comparator
+            && !in_order
+        {
+            run.sort_by(|(a, _), (b, _)| cmp.compare(a, b));
+        }
+
+        log::debug!("flush entries={}", run.len());
+        self.merge_into_level(level, Cow::Borrowed(run));
+    }
+
+    /// memtable entries a failed compaction was moving into the levels, as a memtable again. any
+    /// values `offload` had moved out are read back in, so the blob files it wrote can be rolled
+    /// back too
+    fn unflushed(&self, run: Vec<VersionedEntry>) -> Memtable {
+        run.into_iter()
+            .map(|(key, record)| {
+                let record = self.blobs.inline(&record).into_owned();
+                (key, record)
+            })
+            .collect()
+    }
+
+    /// moves values over the blob threshold out to a new blob file. runs on everything on its
+    /// way into the levels
+    fn offload(&mut self, data: &mut [VersionedEntry]) {
+        let Some(threshold) = self.blob_threshold else {
+            return;
+        };
+        self.blobs
+            .write(data.iter_mut().map(|(_, record)| record).filter(|record| {
+                !record.append && record.value.as_ref().is_some_and(|v| v.len() > threshold)
+            }));
+    }
+
+    /// runs a compaction so it either completes or leaves the levels and blob files exactly as
+    /// they were. both are immutable behind their `Arc`s, so holding on to the old ones is cheap,
+    /// and putting them back undoes whatever was half-merged
+    ///
+    /// the memtable isn't saved - a copy on every flush would cost as much as the flush. a
+    /// compaction that moves memtable entries takes them out before calling this, and only lends
+    /// them to the closure, so it can put them back itself (see `flush_memtables`). nothing in
+    /// here touches sequence numbers or pins
+    ///
+    /// if it panics (say, a buggy comparator) the tree is poisoned, and nothing compacts again
+    fn guarded(&mut self, compaction: impl FnOnce(&mut LSMTree)) {
+        if self.poisoned {
+            return;
+        }
+
+        let levels = self.levels.clone();
+        let populated = self.populated.clone();
+        let blobs = self.blobs.clone();
+        if panic::catch_unwind(AssertUnwindSafe(|| compaction(self))).is_err() {
+            log::error!("compaction panicked, rolled back and poisoned the tree");
+            self.levels = levels;
+            self.populated = populated;
+            self.blobs = blobs;
+            self.poisoned = true;
+        }
+    }
+
+    /// drops the oldest live keys until we're back under `max_entries`. runs right after a flush,
+    /// so the memtable is empty
+    fn evict(&mut self) {
+        let Some(max_entries) = self.max_entries else {
+            return;
+        };
+
+        let mut live: Vec<(u64, &[u8])> = self
+            .merged()
+            .filter(|(_, record)| record.value.is_some())
+            .map(|(key, record)| (record.seq, key))
+            .collect();
+        if live.len() <= max_entries {
+            return;
+        }
+
+        // a snapshot reading any version of the key would lose it
+        let snapshots = snapshot::live(&self.snapshots);
+        let seen = |key: &[u8]| {
+            let mut newer_seq = u64::MAX;
+            self.versions(key).any(|record| {
+                let visible = snapshot::is_visible(&snapshots, record.seq, newer_seq);
+                newer_seq = record.seq;
+                visible
+            })
+        };
+
+        // oldest first. pinned and snapshotted keys count towards the cap, but they're never
+        // evicted
+        let excess = live.len() - max_entries;
+        live.retain(|(_, key)| !self.pinned.contains_key(*key) && !seen(key));
+        live.sort_unstable();
+        let evicted: BTreeSet<Vec<u8>> =
+            live.iter().take(excess).map(|(_, k)| k.to_vec()).collect();
+
+        // every version of an evicted key has to go, otherwise an older one further down would
+        // resurface
+        for i in 0..self.levels.len() {
+            let Some(level) = self.take_level(i) else {
+                continue;
+            };
+            let data: Vec<_> = Arc::unwrap_or_clone(level)
+                .into_data()
+                .into_iter()
+                .filter(|(k, _)| !evicted.contains(k))
+                .collect();
+            if !data.is_empty() {
+                let level = self.new_level(i, data);
+                self.set_level(i, level);
+            }
+        }
+    }
+
+    fn merge_into_level(&mut self, level: usize, new_data: Cow<'_, [VersionedEntry]>) {
+        if level >= self.levels.len() {
+            log::debug!(
+                "compact level={level} existing=0 incoming={n} output={n} tombstones={} \
+                 tombstones_purged=0 cascade=false",
+                tombstones(&new_data),
+                n = new_data.len(),
+            );
+            let new_level = self.new_level(level, new_data.into_owned());
+            self.set_level(level, new_level);
+            self.debug_assert_within_capacity(level);
+            return;
+        }
+
+        let existing_data = self
+            .take_level(level)
+            .map(|level| Arc::unwrap_or_clone(level).into_data())
+            .unwrap_or_default();
+
+        let cmp = self.comparator.clone();
+        let cmp = cmp.as_deref().unwrap_or(&Bytewise);
+        let live = snapshot::live(&self.snapshots);
+        let data = match &mut self.buffer_pool {
+            None => retain_visible(merge_sorted(&existing_data, &new_data, cmp), &live),
+            Some(pool) => {
+                let data = merge_sorted_with(&existing_data, &new_data, cmp, &mut |entry| {
+                    pool.copy_entry(entry)
+                });
+                retain_visible_with(data, &live, &mut |entry| pool.recycle([entry]))
+            }
+        };
+
+        // cascading compaction - check if merged data exceeds level capacity (see `level_capacity`
+        // for notes). if so, merge into the next level. if not, set current level data
+        let deferred = self.defer_cascade && level == 0;
+        let cascade = !deferred && data.len() >= self.level_capacity(level);
+
+        // tombstones are never dropped yet, only shadowed versions of a key
+        log::debug!(
+            "compact level={level} existing={} incoming={} output={} tombstones={} \
+             tombstones_purged=0 cascade={cascade}",
+            existing_data.len(),
+            new_data.len(),
+            data.len(),
+            tombstones(&data),
+        );
+        if let Some(pool) = &mut self.buffer_pool {
+            pool.recycle(existing_data);
+            if let Cow::Owned(new_data) = new_data {
+                pool.recycle(new_data);
+            }
+        }
+
+        if cascade {
+            self.merge_into_level(older_level(level), Cow::Owned(data));
+        } else {
+            let new_level = self.new_level(level, data);
+            self.set_level(level, new_level);
+            self.debug_assert_within_capacity(level);
+        }
+    }
+
+    /// once a merge into `level` is done it has to be under its capacity, or it should have
+    /// cascaded. the deepest level is exempt - it's wherever data stops, whatever its size - and
+    /// so is L0 while `with_deferred_cascade` is holding it back
+    fn debug_assert_within_capacity(&self, level: usize) {
+        let len = self.levels[level].as_ref().map_or(0, |l| l.len());
+        let deepest = self.populated.last() == Some(&level);
+        let deferred = self.defer_cascade && level == NEWEST_LEVEL;
+        debug_assert!(
+            deepest || deferred || len < self.level_capacity(level),
+            "level {level} holds {len} entries, capacity {}",
+            self.level_capacity(level)
+        );
+    }
+
+    /// builds a level out of sorted data, splitting it into `partitions_per_level` partitions
+    fn new_level(&self, level: usize, data: Vec<VersionedEntry>) -> LSMLevel {
+        let bits_per_key = (self.bloom_bits_per_key)(level);
+        let partition_size = data.len().div_ceil(self.partitions_per_level).max(1);
+
+        let max_seq = data.iter().map(|(_, record)| record.seq).max().unwrap_or(0);
+
+        // cutting the sorted data into chunks, without splitting up a key's versions, gives
+        // disjoint key ranges
+        let mut partitions = vec![];
+        let mut entries = data.into_iter().peekable();
+        while entries.peek().is_some() {
+            let mut data: Vec<_> = entries.by_ref().take(partition_size).collect();
+
+            // all versions of a key stay in the same partition, since `partition_for` only
+            // looks in one
+            while let Some(entry) =
+                entries.next_if(|(k, _)| data.last().is_some_and(|(last, _)| last == k))
+            {
+                data.push(entry);
+            }
+            let keys = data.iter().map(|(k, _)| k.as_slice());
+            let bloom = BloomFilter::with_seed(keys, bits_per_key, self.bloom_seed);
+            partitions.push(Partition { data, bloom });
+        }
+
+        LSMLevel {
+            partitions,
+            max_seq,
+        }
+    }
+}
+
+/// drops versions that are shadowed by a newer version of the same key, unless a live snapshot
+/// can still see them. `data` is sorted with each key's versions newest first
+fn retain_visible(data: Vec<VersionedEntry>, snapshots: &[u64]) -> Vec<VersionedEntry> {
+    retain_visible_with(data, snapshots, &mut drop)
+}
+
+/// `retain_visible`, handing each dropped entry to `dropped` (see `BufferPool`)
+fn retain_visible_with(
+    data: Vec<VersionedEntry>,
+    snapshots: &[u64],
+    dropped: &mut dyn FnMut(VersionedEntry),
+) -> Vec<VersionedEntry> {
+    let mut retained: Vec<VersionedEntry> = Vec::with_capacity(data.len());
+
+    // seq of the version right above, if it's the same key. visibility is relative to that one,
+    // whether or not it was kept
+    let mut newer: Option<u64> = None;
+    let mut entries = data.into_iter().peekable();
+    while let Some((key, record)) = entries.next() {
+        let keep = newer.is_none_or(|newer| snapshot::is_visible(snapshots, record.seq, newer));
+        newer = entries
+            .peek()
+            .filter(|(next, _)| *next == key)
+            .map(|_| record.seq);
+        if keep {
+            retained.push((key, record));
+            continue;
+        }
+
+        // a shadowed version under an append operand can't just go, it's folded into the operand.
+        // unless it's in a blob - then it stays where it is, rather than reading it back in
+        if let Some((last_key, last)) = retained.last_mut()
+            && *last_key == key
+            && last.append
+        {
+            if record.blob.is_some() {
+                retained.push((key, record));
+                continue;
+            }
+            *last = last.apply(Some(&record));
+        }
+        dropped((key, record));
+    }
+    retained
+}
+
+fn tombstones(data: &[VersionedEntry]) -> usize {
+    data.iter()
+        .filter(|(_, record)| record.value.is_none())
+        .count()
+}
+
+/// SHA-256 over `(key, value)` pairs, each key and value with its length in front
+fn hash_pairs<'a>(pairs: impl Iterator<Item = (&'a [u8], impl AsRef<[u8]>)>) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for (key, value) in pairs {
+        for bytes in [key, value.as_ref()] {
+            hasher.update((bytes.len() as u64).to_le_bytes());
+            hasher.update(bytes);
+        }
+    }
+    hasher.finalize().into()
+}
+
+/// merge 2 sorted vecs
+///
+/// when merging:
+/// 1. push smaller key into result
+/// 2. if equal, the higher seq goes first - so every key's versions end up newest first, whichever
+///    side they came from. on a tie `new_data` wins
+/// 3. when list runs out, go to the end of the other list
+///
+/// every version is kept, `retain_visible` drops the ones nobody can read anymore
+///
+/// when one side is `GALLOP_RATIO` times the size of the other (a small flush into a big level,
+/// usually) this goes through `merge_galloping` instead, which comes out the same
+fn merge_sorted(
+    old_data: &[VersionedEntry],
+    new_data: &[VersionedEntry],
+    cmp: &dyn Comparator,
+) -> Vec<VersionedEntry> {
+    merge_sorted_with(old_data, new_data, cmp, &mut VersionedEntry::clone)
+}
+
+/// `merge_sorted`, making each entry of the output with `copy` (see `BufferPool`)
+fn merge_sorted_with(
+    old_data: &[VersionedEntry],
+    new_data: &[VersionedEntry],
+    cmp: &dyn Comparator,
+    copy: &mut dyn FnMut(&VersionedEntry) -> VersionedEntry,
+) -> Vec<VersionedEntry> {
+    if new_data.len().saturating_mul(GALLOP_RATIO) <= old_data.len() {
+        merge_galloping(
+            new_data,
+            old_data,
+            |new, old| !old_goes_first(cmp, old, new),
+            copy,
+        )
+    } else if old_data.len().saturating_mul(GALLOP_RATIO) <= new_data.len() {
+        merge_galloping(
+            old_data,
+            new_data,
+            |old, new| old_goes_first(cmp, old, new),
+            copy,
+        )
+    } else {
+        merge_linear(old_data, new_data, cmp, copy)
+    }
+}
+
+// how many times bigger one side of a merge has to be before we gallop through it
+const GALLOP_RATIO: usize = 8;
+
+/// whether `old` goes ahead of `new` in `merge_sorted` - it's the smaller key, or the newer
+/// version of the same key
+fn old_goes_first(cmp: &dyn Comparator, old: &VersionedEntry, new: &VersionedEntry) -> bool {
+    match cmp.compare(&old.0, &new.0) {
+        Ordering::Less => true,
+        Ordering::Equal => old.1.seq > new.1.seq,
+        Ordering::Greater => false,
+    }
+}
+
+/// `merge_sorted` for a short run against a much longer one. for each entry in `small`, gallops
+/// through `large` for the entries that go ahead of it and copies them over in one go, so most of
+/// `large` is never compared at all
+///
+/// `small_first(s, l)` says whether `s` goes ahead of `l`
+fn merge_galloping(
+    small: &[VersionedEntry],
+    large: &[VersionedEntry],
+    small_first: impl Fn(&VersionedEntry, &VersionedEntry) -> bool,
+    copy: &mut dyn FnMut(&VersionedEntry) -> VersionedEntry,
+) -> Vec<VersionedEntry> {
+    let mut merged = Vec::with_capacity(small.len() + large.len());
+    let mut j = 0;
+    for entry in small {
+        let ahead = gallop(&large[j..], |l| !small_first(entry, l));
+        merged.extend(large[j..j + ahead].iter().map(&mut *copy));
+        j += ahead;
+        merged.push(copy(entry));
+    }
+    merged.extend(large[j..].iter().map(copy));
+    merged
+}
+
+/// the length of the prefix of `slice` where `pred` holds (it has to be true then false, like
+/// for `partition_point`). probes 1, 2, 4, ... entries in, then binary searches the last gap, so
+/// it's cheap when the prefix is short
+fn gallop<T>(slice: &[T], pred: impl Fn(&T) -> bool) -> usize {
+    let mut bound = 1;
+    while bound <= slice.len() && pred(&slice[bound - 1]) {
+        bound *= 2;
+    }
+    let lo = bound / 2;
+    let hi = bound.min(slice.len());
+    lo + slice[lo..hi].partition_point(pred)
+}
+
+/// `merge_sorted` one entry at a time, for runs of about the same size
+fn merge_linear(
+    old_data: &[VersionedEntry],
+    new_data: &[VersionedEntry],
+    cmp: &dyn Comparator,
+    copy: &mut dyn FnMut(&VersionedEntry) -> VersionedEntry,
+) -> Vec<VersionedEntry> {
+    // every entry from both sides ends up in here, so size it once up front
+    let mut merged = Vec::with_capacity(old_data.len() + new_data.len());
+    let mut i = 0;
+    let mut j = 0;
+
+    // while we still have data remaining in both lists
+    while i < old_data.len() && j < new_data.len() {
+        match cmp.compare(&old_data[i].0, &new_data[j].0) {
+            Ordering::Less => {
+                merged.push(copy(&old_data[i]));
+                i += 1;
+            }
+            Ordering::Equal if old_data[i].1.seq > new_data[j].1.seq => {
+                merged.push(copy(&old_data[i]));
+                i += 1;
+            }
+            // on equal keys the older version goes after, next time round
+            Ordering::Greater | Ordering::Equal => {
+                merged.push(copy(&new_data[j]));
+                j += 1;
+            }
+        }
+    }
+
+    // while we still have data remaining in existing
+    while i < old_data.len() {
+        merged.push(copy(&old_data[i]));
+        i += 1;
+    }
+
+    // while we still have data remaining in new
+    while j < new_data.len() {
+        merged.push(copy(&new_data[j]));
+        j += 1;
+    }
+
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{self, AtomicUsize};
+
+    #[test]
+    fn test_basic_insert_and_get() {
+        let mut lsm = LSMTree::new(3);
+        lsm.insert(b"key1".to_vec(), Some(b"value1".to_vec()));
+        lsm.insert(b"key2".to_vec(), Some(b"value2".to_vec()));
+
+        assert_eq!(lsm.get(b"key1"), Some(b"value1".to_vec()));
+        assert_eq!(lsm.get(b"key2"), Some(b"value2".to_vec()));
+        assert_eq!(lsm.get(b"key3"), None);
+    }
+
+    #[test]
+    fn test_memtable_flush() {
+        let mut lsm = LSMTree::new(2);
+
+        lsm.insert(b"k1".to_vec(), Some(b"v1".to_vec()));
+        lsm.insert(b"k2".to_vec(), Some(b"v2".to_vec()));
+
+        assert_eq!(lsm.memtable.len(), 0);
+        assert_eq!(lsm.levels.len(), 1);
+        assert!(lsm.levels[0].is_some());
+        assert_eq!(lsm.levels[0].as_ref().unwrap().len(), 2);
+
+        assert_eq!(lsm.get(b"k1"), Some(b"v1".to_vec()));
+        assert_eq!(lsm.get(b"k2"), Some(b"v2".to_vec()));
+    }
+
+    #[test]
+    fn test_level_merge() {
+        // Threshold 2.
+        // Level 0 capacity: 2 * 2^0 = 2.
+        // Level 1 capacity: 2 * 2^1 = 4.
+        let mut lsm = LSMTree::new(2);
+
+        // 1. Insert 2 items -> Flush to L0. L0 size 2.
+        lsm.insert(b"a".to_vec(), Some(b"1".to_vec()));
+        lsm.insert(b"b".to_vec(), Some(b"2".to_vec()));
+
+        assert_eq!(lsm.levels.len(), 1);
+        assert_eq!(lsm.levels[0].as_ref().unwrap().len(), 2);
+
+        // 2. Insert 2 items -> Flush to L0.
+        // Merge (L0 existing) + (New) = 4 items.
+        // 4 > L0 capacity (2). So push to L1.
+        lsm.insert(b"c".to_vec(), Some(b"3".to_vec()));
+        lsm.insert(b"d".to_vec(), Some(b"4".to_vec()));
+
+        assert_eq!(lsm.levels.len(), 2); // Should have created L1
+        assert!(lsm.levels[0].is_none()); // L0 data moved up
+        assert!(lsm.levels[1].is_some()); // L1 has the data
+        assert_eq!(lsm.levels[1].as_ref().unwrap().len(), 4);
+
+        assert_eq!(lsm.get(b"a"), Some(b"1".to_vec()));
+        assert_eq!(lsm.get(b"d"), Some(b"4".to_vec()));
+    }
+
+    #[test]
+    fn test_overwrite() {
+        let mut lsm = LSMTree::new(2);
+        lsm.insert(b"key1".to_vec(), Some(b"val1".to_vec()));
+        lsm.insert(b"key1".to_vec(), Some(b"val2".to_vec())); // Overwrite in memtable
+
+        assert_eq!(lsm.get(b"key1"), Some(b"val2".to_vec()));
+
+        // Flush
+        lsm.insert(b"key2".to_vec(), Some(b"val3".to_vec())); // Trigger flush (size 2)
+
+        // Now key1 is in L0 with val2.
+        assert_eq!(lsm.get(b"key1"), Some(b"val2".to_vec()));
+
+        // Overwrite again in memtable
+        lsm.insert(b"key1".to_vec(), Some(b"val3".to_vec()));
+        assert_eq!(lsm.get(b"key1"), Some(b"val3".to_vec()));
+    }
+
+    #[test]
+    fn test_delete() {
+        let mut lsm = LSMTree::new(2);
+        lsm.insert(b"key1".to_vec(), Some(b"val1".to_vec()));
+
+        // flush
+        lsm.insert(b"key2".to_vec(), Some(b"val2".to_vec()));
+        lsm.delete(b"key1".to_vec());
+
+        assert_eq!(lsm.get(b"key1"), None);
+    }
+
+    #[test]
+    fn test_level_entries() {
+        let mut lsm = LSMTree::new(2);
+        lsm.insert(b"a".to_vec(), Some(b"1".to_vec()));
+        lsm.delete(b"b".to_vec());
+
+        // L0 holds exactly what the memtable flushed, tombstone included
+        assert_eq!(
+            lsm.level_entries(0),
+            Some(vec![
+                (b"a".to_vec(), Some(b"1".to_vec())),
+                (b"b".to_vec(), None),
+            ])
+        );
+
+        // second flush merges with L0 and cascades to L1, leaving L0 empty
+        lsm.insert(b"a".to_vec(), Some(b"2".to_vec()));
+        lsm.insert(b"c".to_vec(), Some(b"3".to_vec()));
+
+        assert_eq!(lsm.level_entries(0), None);
+        assert_eq!(
+            lsm.level_entries(1),
+            Some(vec![
+                (b"a".to_vec(), Some(b"2".to_vec())),
+                (b"b".to_vec(), None),
+                (b"c".to_vec(), Some(b"3".to_vec())),
+            ])
+        );
+        assert_eq!(lsm.level_entries(2), None);
+    }
+
+    struct Lowercase;
+
+    impl KeyTransform for Lowercase {
+        fn normalize(&self, key: &[u8]) -> Vec<u8> {
+            key.to_ascii_lowercase()
+        }
+    }
+
+    #[test]
+    fn test_key_transform() {
+        let mut lsm = LSMTree::new(2).with_key_transform(Lowercase);
+        lsm.insert(b"Foo".to_vec(), Some(b"1".to_vec()));
+
+        assert_eq!(lsm.get(b"foo"), Some(b"1".to_vec()));
+        assert_eq!(lsm.get(b"FOO"), Some(b"1".to_vec()));
+
+        // flushes to L0 under the normalized key, and the overwrite collides with it
+        lsm.insert(b"bar".to_vec(), Some(b"2".to_vec()));
+        lsm.insert(b"FOO".to_vec(), Some(b"3".to_vec()));
+        assert_eq!(lsm.level_entries(0).unwrap()[1].0, b"foo".to_vec());
+        assert_eq!(lsm.get(b"Foo"), Some(b"3".to_vec()));
+
+        lsm.delete(b"fOO".to_vec());
+        assert_eq!(lsm.get(b"foo"), None);
+    }
+
+    #[test]
+    fn test_iter_live() {
+        let mut lsm = LSMTree::new(2);
+
+        // L2: a=1 b=20 c=<tombstone> d=4
+        lsm.insert(b"a".to_vec(), Some(b"1".to_vec()));
+        lsm.insert(b"b".to_vec(), Some(b"2".to_vec()));
+        lsm.insert(b"c".to_vec(), Some(b"3".to_vec()));
+        lsm.insert(b"d".to_vec(), Some(b"4".to_vec()));
+        lsm.insert(b"b".to_vec(), Some(b"20".to_vec()));
+        lsm.delete(b"c".to_vec());
+
+        // L1: d=40 e=5
+        lsm.insert(b"d".to_vec(), Some(b"40".to_vec()));
+        lsm.insert(b"e".to_vec(), Some(b"5".to_vec()));
+
+        // memtable: a=10
+        lsm.insert(b"a".to_vec(), Some(b"10".to_vec()));
+
+        assert!(lsm.levels[1].is_some());
+        assert!(lsm.levels[2].is_some());
+        assert_eq!(
+            lsm.iter_live().collect::<Vec<_>>(),
+            vec![
+                (b"a".to_vec(), b"10".to_vec()),
+                (b"b".to_vec(), b"20".to_vec()),
+                (b"d".to_vec(), b"40".to_vec()),
+                (b"e".to_vec(), b"5".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_absorb() {
+        // k1=a1 k2=a2
+        let mut a = LSMTree::new(2);
+        a.insert(b"k1".to_vec(), Some(b"a1".to_vec()));
+        a.insert(b"k2".to_vec(), Some(b"a2".to_vec()));
+
+        // k2=b2 k3=b3 k4=<tombstone>, all written after a's writes
+        let mut b = LSMTree::new(2).with_next_seq(a.next_seq());
+        b.insert(b"k2".to_vec(), Some(b"b2".to_vec()));
+        b.insert(b"k3".to_vec(), Some(b"b3".to_vec()));
+        b.delete(b"k4".to_vec());
+
+        // k3=a3 k4=a4, written after b's writes
+        let mut a = a.with_next_seq(b.next_seq());
+        a.insert(b"k3".to_vec(), Some(b"a3".to_vec()));
+        a.insert(b"k4".to_vec(), Some(b"a4".to_vec()));
+        a.delete(b"k5".to_vec());
+
+        let next_seq = a.next_seq();
+        a.absorb(b);
+
+        assert_eq!(a.get(b"k1"), Some(b"a1".to_vec()));
+        assert_eq!(a.get(b"k2"), Some(b"b2".to_vec()));
+        assert_eq!(a.get(b"k3"), Some(b"a3".to_vec()));
+        assert_eq!(a.get(b"k4"), Some(b"a4".to_vec()));
+        assert_eq!(a.get(b"k5"), None);
+        assert_eq!(a.next_seq(), next_seq);
+
+        // a newer tombstone from the absorbed tree shadows our value
+        let mut c = LSMTree::new(2).with_next_seq(a.next_seq());
+        c.delete(b"k1".to_vec());
+        a.absorb(c);
+        assert_eq!(a.get(b"k1"), None);
+    }
+
+    #[test]
+    fn test_scan_filter() {
+        let mut lsm = LSMTree::new(2);
+        lsm.insert(b"a".to_vec(), Some(b"xx".to_vec()));
+        lsm.insert(b"b".to_vec(), Some(b"yyy".to_vec()));
+        lsm.insert(b"c".to_vec(), Some(b"zz".to_vec()));
+        lsm.insert(b"d".to_vec(), Some(b"w".to_vec()));
+
+        // shadowed by a shorter value, then deleted
+        lsm.insert(b"b".to_vec(), Some(b"yy".to_vec()));
+        lsm.delete(b"c".to_vec());
+
+        assert_eq!(
+            lsm.scan_filter(|_, v| v.len() == 2),
+            vec![
+                (b"a".to_vec(), b"xx".to_vec()),
+                (b"b".to_vec(), b"yy".to_vec()),
+            ]
+        );
+        assert!(lsm.scan_filter(|_, v| v.len() == 3).is_empty());
+    }
+
+    #[test]
+    fn test_try_new() {
+        assert!(matches!(
+            LSMTree::try_new(0),
+            Err(LsmError::InvalidArgument(_))
+        ));
+
+        let mut lsm = LSMTree::try_new(2).unwrap();
+        lsm.insert(b"a".to_vec(), Some(b"1".to_vec()));
+        assert_eq!(lsm.get(b"a"), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn test_threshold_of_one() {
+        // every write is flushed straight away
+        let mut lsm = LSMTree::new(1);
+        for i in 0..50u8 {
+            lsm.insert(vec![i], Some(vec![i]));
+            assert!(lsm.memtable.is_empty());
+        }
+        lsm.delete(vec![7]);
+        assert!(lsm.memtable.is_empty());
+        assert_eq!(lsm.count_live(), 49);
+        assert_eq!(lsm.get(&[8]), Some(vec![8]));
+        assert!(lsm.verify().is_ok());
+
+        // 0 would never stop cascading, so it's bumped up to 1
+        let mut lsm = LSMTree::new(0);
+        assert_eq!(lsm.flush_threshold(), 1);
+        for i in 0..50u8 {
+            lsm.insert(vec![i], Some(vec![i]));
+        }
+        assert_eq!(lsm.count_live(), 50);
+    }
+
+    #[test]
+    fn test_size_distribution() {
+        assert_eq!(
+            LSMTree::new(2).size_distribution(),
+            SizeDistribution::default()
+        );
+
+        let mut lsm = LSMTree::new(2);
+        lsm.insert(b"a".to_vec(), Some(vec![0; 10]));
+        lsm.insert(b"bb".to_vec(), Some(vec![0; 20]));
+        lsm.insert(b"cccc".to_vec(), Some(vec![0; 60]));
+        lsm.insert(b"dddddddd".to_vec(), Some(vec![0; 30]));
+
+        // neither of these count: a shadowed version and a tombstone
+        lsm.insert(b"a".to_vec(), Some(vec![0; 10]));
+        lsm.delete(b"eeeeeeeeeeeeeeee".to_vec());
+
+        let dist = lsm.size_distribution();
+        assert_eq!(dist.count, 4);
+        assert_eq!(
+            dist.keys,
+            SizeStats {
+                min: 1,
+                max: 8,
+                mean: 3.75,
+                median: 3.0,
+            }
+        );
+        assert_eq!(
+            dist.values,
+            SizeStats {
+                min: 10,
+                max: 60,
+                mean: 30.0,
+                median: 25.0,
+            }
+        );
+    }
+
+    struct MockStore {
+        data: BTreeMap<Vec<u8>, Vec<u8>>,
+        loads: Arc<AtomicUsize>,
+    }
+
+    impl BackingStore for MockStore {
+        fn load(&self, key: &[u8]) -> Option<Vec<u8>> {
+            self.loads.fetch_add(1, atomic::Ordering::Relaxed);
+            self.data.get(key).cloned()
+        }
+    }
+
+    #[test]
+    fn test_backing_store() {
+        let loads = Arc::new(AtomicUsize::new(0));
+        let store = MockStore {
+            data: BTreeMap::from([
+                (b"a".to_vec(), b"store-a".to_vec()),
+                (b"b".to_vec(), b"store-b".to_vec()),
+                (b"c".to_vec(), b"store-c".to_vec()),
+            ]),
+            loads: Arc::clone(&loads),
+        };
+        let load_count = || loads.load(atomic::Ordering::Relaxed);
+
+        let mut lsm = LSMTree::new(2).with_backing_store(store);
+
+        // miss in the tree, loaded from the store, but `get` doesn't populate
+        assert_eq!(lsm.get(b"a"), Some(b"store-a".to_vec()));
+        assert_eq!(lsm.get(b"a"), Some(b"store-a".to_vec()));
+        assert_eq!(load_count(), 2);
+        assert_eq!(lsm.get(b"zzz"), None);
+        assert_eq!(load_count(), 3);
+
+        // `get_or_load` does, so later reads are served by the tree
+        assert_eq!(lsm.get_or_load(b"b"), Some(b"store-b".to_vec()));
+        assert_eq!(lsm.get(b"b"), Some(b"store-b".to_vec()));
+        assert_eq!(load_count(), 4);
+
+        // the tree's own writes win over the store
+        lsm.insert(b"a".to_vec(), Some(b"tree-a".to_vec()));
+        assert_eq!(lsm.get(b"a"), Some(b"tree-a".to_vec()));
+
+        // tombstones suppress the fall-through, in the memtable and after a flush
+        lsm.delete(b"c".to_vec());
+        assert_eq!(lsm.get(b"c"), None);
+        assert_eq!(lsm.get_or_load(b"c"), None);
+        lsm.insert(b"d".to_vec(), Some(b"tree-d".to_vec()));
+        assert!(lsm.memtable.is_empty());
+        assert_eq!(lsm.get(b"c"), None);
+        assert_eq!(load_count(), 4);
+    }
+
+    #[test]
+    fn test_bloom_bits_per_key() {
+        let bits_per_key = |level| 8 + level * 4;
+        let mut lsm = LSMTree::new(8).with_bloom_bits_per_key(bits_per_key);
+        for i in 0..200u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(vec![1]));
+        }
+
+        let mut populated = 0;
+        for (i, level) in lsm.levels.iter().enumerate() {
+            let Some(level) = level else {
+                continue;
+            };
+            populated += 1;
+            let partition = &level.partitions[0];
+            assert_eq!(
+                partition.bloom.num_bits(),
+                partition.data.len() * bits_per_key(i)
+            );
+        }
+        assert!(populated >= 2);
+
+        for i in 0..200u32 {
+            assert_eq!(lsm.get(&i.to_be_bytes()), Some(vec![1]));
+        }
+        assert_eq!(lsm.get(&500u32.to_be_bytes()), None);
+    }
+
+    #[test]
+    fn test_partitions() {
+        let mut lsm = LSMTree::new(8).with_partitions(4);
+        for i in 0..200u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(i.to_be_bytes().to_vec()));
+        }
+
+        let mut partitioned = 0;
+        for level in lsm.levels.iter().flatten() {
+            assert!(level.partitions.len() <= 4);
+            if level.partitions.len() > 1 {
+                partitioned += 1;
+            }
+
+            // each partition is sorted, and ends before the next one starts
+            for partition in &level.partitions {
+                assert!(partition.data.is_sorted_by(|a, b| a.0 < b.0));
+            }
+            for pair in level.partitions.windows(2) {
+                assert!(pair[0].data.last().unwrap().0 < pair[1].data[0].0);
+            }
+
+            // lookups go to the partition that actually holds the key
+            for (i, partition) in level.partitions.iter().enumerate() {
+                for (key, _) in &partition.data {
+                    assert_eq!(level.partition_for(key, &Bytewise), Some(i));
+                }
+            }
+        }
+        assert!(partitioned > 0);
+
+        for i in 0..200u32 {
+            assert_eq!(lsm.get(&i.to_be_bytes()), Some(i.to_be_bytes().to_vec()));
+        }
+        assert_eq!(lsm.get(&[]), None);
+        assert_eq!(lsm.get(&500u32.to_be_bytes()), None);
+    }
+
+    #[test]
+    fn test_max_entries() {
+        let mut lsm = LSMTree::new(4).with_max_entries(10);
+        for i in 0..30u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(vec![1]));
+        }
+
+        // 28 flushed + 2 still in the memtable
+        let live: Vec<_> = lsm.iter_live().map(|(k, _)| k).collect();
+        assert_eq!(live.len(), 12);
+
+        // the newest writes survive
+        let expected: Vec<_> = (18..30u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        assert_eq!(live, expected);
+        assert_eq!(lsm.get(&0u32.to_be_bytes()), None);
+
+        // rewriting an old key makes it new again, so it's not the next to go
+        lsm.insert(18u32.to_be_bytes().to_vec(), Some(vec![2]));
+        lsm.insert(30u32.to_be_bytes().to_vec(), Some(vec![1]));
+        assert_eq!(lsm.iter_live().count(), 10);
+        assert_eq!(lsm.get(&18u32.to_be_bytes()), Some(vec![2]));
+        assert_eq!(lsm.get(&19u32.to_be_bytes()), None);
+    }
+
+    #[test]
+    fn test_max_entries_keeps_snapshotted_keys() {
+        let mut lsm = LSMTree::new(4).with_max_entries(4);
+        for i in 0..4u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(vec![1]));
+        }
+        let snapshot = lsm.acquire_snapshot();
+        for i in 4..8u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(vec![1]));
+        }
+
+        // the snapshot still sees 0..4, so they stay and the next oldest go instead
+        for i in 0..4u32 {
+            assert_eq!(lsm.get_at(&i.to_be_bytes(), &snapshot), Some(vec![1]));
+        }
+        let live: Vec<_> = lsm.iter_live().map(|(k, _)| k).collect();
+        let expected: Vec<_> = (0..4u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        assert_eq!(live, expected);
+
+        // once it's gone they're the oldest again
+        drop(snapshot);
+        for i in 8..12u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(vec![1]));
+        }
+        let live: Vec<_> = lsm.iter_live().map(|(k, _)| k).collect();
+        let expected: Vec<_> = (8..12u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        assert_eq!(live, expected);
+    }
+
+    #[test]
+    fn test_for_each_live() {
+        let mut lsm = LSMTree::new(3);
+        for i in 0..20u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(vec![0; i as usize]));
+        }
+        lsm.delete(5u32.to_be_bytes().to_vec());
+        lsm.insert(7u32.to_be_bytes().to_vec(), Some(vec![0; 100]));
+
+        let mut total = 0;
+        let mut count = 0;
+        lsm.for_each_live(|_, v| {
+            total += v.len();
+            count += 1;
+        });
+
+        assert_eq!(total, lsm.iter_live().map(|(_, v)| v.len()).sum::<usize>());
+        assert_eq!(count, 19);
+    }
+
+    #[test]
+    fn test_level_stats() {
+        let mut lsm = LSMTree::new(2);
+
+        // L2: a=1 b=<tombstone> c=3 d=<tombstone>
+        lsm.insert(b"a".to_vec(), Some(b"1".to_vec()));
+        lsm.insert(b"b".to_vec(), Some(b"2".to_vec()));
+        lsm.insert(b"c".to_vec(), Some(b"3".to_vec()));
+        lsm.insert(b"d".to_vec(), Some(b"4".to_vec()));
+        lsm.delete(b"b".to_vec());
+        lsm.delete(b"d".to_vec());
+
+        // L1: a=<tombstone> e=5
+        lsm.delete(b"a".to_vec());
+        lsm.insert(b"e".to_vec(), Some(b"5".to_vec()));
+
+        assert_eq!(
+            lsm.level_stats(),
+            vec![
+                LevelStat {
+                    index: 0,
+                    capacity: 2,
+                    ..LevelStat::default()
+                },
+                LevelStat {
+                    index: 1,
+                    live: 1,
+                    tombstones: 1,
+                    bytes: 3,
+                    capacity: 4,
+                },
+                LevelStat {
+                    index: 2,
+                    live: 2,
+                    tombstones: 2,
+                    bytes: 6,
+                    capacity: 8,
+                },
+            ]
+        );
+    }
+
+    fn with_duplicates() -> Vec<(Vec<u8>, Vec<u8>)> {
+        vec![
+            (b"b".to_vec(), b"b-first".to_vec()),
+            (b"a".to_vec(), b"a".to_vec()),
+            (b"b".to_vec(), b"b-middle".to_vec()),
+            (b"c".to_vec(), b"c".to_vec()),
+            (b"b".to_vec(), b"b-last".to_vec()),
+        ]
+    }
+
+    #[test]
+    fn test_bulk_load_keep_first() {
+        let mut lsm = LSMTree::new(2);
+        lsm.insert(b"a".to_vec(), Some(b"old".to_vec()));
+        lsm.bulk_load(with_duplicates(), DuplicatePolicy::KeepFirst)
+            .unwrap();
+
+        assert_eq!(
+            lsm.iter_live().collect::<Vec<_>>(),
+            vec![
+                (b"a".to_vec(), b"a".to_vec()),
+                (b"b".to_vec(), b"b-first".to_vec()),
+                (b"c".to_vec(), b"c".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bulk_load_keep_last() {
+        let mut lsm = LSMTree::new(2);
+        lsm.bulk_load(with_duplicates(), DuplicatePolicy::KeepLast)
+            .unwrap();
+
+        assert_eq!(lsm.get(b"b"), Some(b"b-last".to_vec()));
+        assert_eq!(lsm.iter_live().count(), 3);
+    }
+
+    #[test]
+    fn test_bulk_load_error() {
+        let mut lsm = LSMTree::new(2);
+        lsm.insert(b"z".to_vec(), Some(b"1".to_vec()));
+        let next_seq = lsm.next_seq();
+
+        let err = lsm
+            .bulk_load(with_duplicates(), DuplicatePolicy::default())
+            .unwrap_err();
+        assert!(matches!(err, LsmError::InvalidArgument(_)));
+
+        // nothing was loaded, or even flushed
+        assert_eq!(lsm.iter_live().count(), 1);
+        assert_eq!(lsm.memtable.len(), 1);
+        assert_eq!(lsm.next_seq(), next_seq);
+
+        // no duplicates, no error
+        lsm.bulk_load(
+            vec![
+                (b"x".to_vec(), b"1".to_vec()),
+                (b"y".to_vec(), b"2".to_vec()),
+            ],
+            DuplicatePolicy::Error,
+        )
+        .unwrap();
+        assert_eq!(lsm.iter_live().count(), 3);
+    }
+
+    #[test]
+    fn test_clone_shares_levels() {
+        let mut lsm = LSMTree::new(4);
+        for i in 0..1000u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(i.to_le_bytes().to_vec()));
+        }
+        let mut snapshot = lsm.clone();
+
+        // same levels, not copies of them
+        assert_eq!(snapshot.levels.len(), lsm.levels.len());
+        for (a, b) in snapshot.levels.iter().zip(&lsm.levels) {
+            match (a, b) {
+                (Some(a), Some(b)) => assert!(Arc::ptr_eq(a, b)),
+                (None, None) => {}
+                _ => panic!("level mismatch"),
+            }
+        }
+
+        // enough writes to compact every level of the clone
+        for i in 0..1000u32 {
+            snapshot.insert(i.to_be_bytes().to_vec(), Some(b"new".to_vec()));
+        }
+        snapshot.delete(0u32.to_be_bytes().to_vec());
+
+        assert_eq!(
+            lsm.get(&0u32.to_be_bytes()),
+            Some(0u32.to_le_bytes().to_vec())
+        );
+        assert_eq!(
+            lsm.get(&999u32.to_be_bytes()),
+            Some(999u32.to_le_bytes().to_vec())
+        );
+        assert_eq!(lsm.iter_live().count(), 1000);
+        assert_eq!(snapshot.get(&0u32.to_be_bytes()), None);
+        assert_eq!(snapshot.get(&999u32.to_be_bytes()), Some(b"new".to_vec()));
+    }
+
+    #[test]
+    fn test_repair_level() {
+        let mut lsm = LSMTree::new(2);
+        for (k, v) in [("a", "1"), ("b", "2"), ("c", "3"), ("d", "4"), ("b", "20")] {
+            lsm.insert(k.as_bytes().to_vec(), Some(v.as_bytes().to_vec()));
+        }
+        lsm.delete(b"c".to_vec());
+        lsm.insert(b"d".to_vec(), Some(b"40".to_vec()));
+        lsm.insert(b"e".to_vec(), Some(b"5".to_vec()));
+        lsm.insert(b"e".to_vec(), Some(b"50".to_vec()));
+        assert_eq!(lsm.level_entries(1).unwrap().len(), 2);
+        assert_eq!(lsm.level_entries(2).unwrap().len(), 4);
+
+        // scramble L1 so it's no longer sorted
+        let level = Arc::get_mut(lsm.levels[1].as_mut().unwrap()).unwrap();
+        level.partitions[0].data.reverse();
+
+        let report = lsm.repair_level(1);
+        assert_eq!(report.level, 1);
+        assert_eq!(report.dropped, 2);
+        assert_eq!(report.regressed, vec![b"d".to_vec()]);
+
+        // e was rewritten in the memtable, so it was never served from L1
+        assert!(report.lost.is_empty());
+
+        assert!(lsm.levels[1].is_none());
+        assert_eq!(lsm.get(b"d"), Some(b"4".to_vec()));
+        assert_eq!(lsm.get(b"e"), Some(b"50".to_vec()));
+        assert_eq!(
+            lsm.iter_live().collect::<Vec<_>>(),
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"20".to_vec()),
+                (b"d".to_vec(), b"4".to_vec()),
+                (b"e".to_vec(), b"50".to_vec()),
+            ]
+        );
+
+        // keys that only lived in the dropped level are lost. the flush lands in L1 again
+        lsm.insert(b"f".to_vec(), Some(b"6".to_vec()));
+        let report = lsm.repair_level(1);
+        assert_eq!(report.dropped, 2);
+        assert!(report.regressed.is_empty());
+        assert_eq!(report.lost, vec![b"e".to_vec(), b"f".to_vec()]);
+        assert_eq!(lsm.get(b"e"), None);
+
+        // nothing to repair
+        assert_eq!(lsm.repair_level(7).dropped, 0);
+
+        // a key whose only older version is a tombstone reads as deleted now, so it's lost too
+        let mut lsm = LSMTree::new(2);
+        lsm.insert(b"g".to_vec(), Some(b"7".to_vec()));
+        lsm.insert(b"a".to_vec(), Some(b"1".to_vec()));
+        lsm.delete(b"g".to_vec());
+        lsm.insert(b"b".to_vec(), Some(b"2".to_vec()));
+        lsm.insert(b"c".to_vec(), Some(b"3".to_vec()));
+        lsm.insert(b"d".to_vec(), Some(b"4".to_vec()));
+        lsm.insert(b"g".to_vec(), Some(b"70".to_vec()));
+        lsm.insert(b"h".to_vec(), Some(b"8".to_vec()));
+        assert_eq!(
+            lsm.debug_versions(b"g"),
+            vec![(Some(b"70".to_vec()), Some(1), 6), (None, Some(2), 2)]
+        );
+
+        let report = lsm.repair_level(1);
+        assert_eq!(report.dropped, 2);
+        assert!(report.regressed.is_empty());
+        assert_eq!(report.lost, vec![b"g".to_vec(), b"h".to_vec()]);
+        assert_eq!(lsm.get(b"g"), None);
+    }
+
+    #[test]
+    fn test_expected_levels() {
+        let mut lsm = LSMTree::new(2).with_expected_levels(16);
+        assert!(lsm.levels.capacity() >= 16);
+
+        // capacity reserved, but nothing in it yet
+        assert!(lsm.levels.is_empty());
+        for i in 0..100u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(vec![1]));
+        }
+        assert!(lsm.levels.capacity() >= 16);
+        assert_eq!(lsm.iter_live().count(), 100);
+    }
+
+    #[test]
+    fn test_get_many() {
+        let mut lsm = LSMTree::new(2);
+        for (k, v) in [("c", "3"), ("a", "1"), ("d", "4"), ("b", "2")] {
+            lsm.insert(k.as_bytes().to_vec(), Some(v.as_bytes().to_vec()));
+        }
+        lsm.delete(b"d".to_vec());
+
+        let keys: Vec<Vec<u8>> = ["d", "b", "x", "a", "b"]
+            .iter()
+            .map(|k| k.as_bytes().to_vec())
+            .collect();
+        assert_eq!(
+            lsm.get_many(&keys),
+            keys.iter().map(|k| lsm.get(k)).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            lsm.get_many(&keys),
+            vec![
+                None,
+                Some(b"2".to_vec()),
+                None,
+                Some(b"1".to_vec()),
+                Some(b"2".to_vec()),
+            ]
+        );
+        assert!(lsm.get_many(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_values() {
+        let mut lsm = LSMTree::new(3);
+        for i in 0..50u32 {
+            lsm.insert(
+                i.to_be_bytes().to_vec(),
+                Some((i * 2).to_le_bytes().to_vec()),
+            );
+        }
+        for i in (0..50u32).step_by(7) {
+            lsm.delete(i.to_be_bytes().to_vec());
+        }
+        lsm.insert(1u32.to_be_bytes().to_vec(), Some(b"updated".to_vec()));
+
+        assert_eq!(
+            lsm.values().collect::<Vec<_>>(),
+            lsm.iter_live().map(|(_, v)| v).collect::<Vec<_>>()
+        );
+        assert_eq!(lsm.values().count(), 42);
+    }
+
+    #[test]
+    fn test_pin() {
+        let mut lsm = LSMTree::new(2);
+        lsm.insert(b"hot".to_vec(), Some(b"1".to_vec()));
+        lsm.pin(b"hot".to_vec());
+        lsm.pin(b"missing".to_vec());
+
+        for i in 0..100u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(vec![1]));
+            if i == 50 {
+                lsm.insert(b"hot".to_vec(), Some(b"2".to_vec()));
+            }
+        }
+        assert_eq!(
+            lsm.pinned.get(b"hot".as_slice()),
+            Some(&lsm.lookup_tree(b"hot").map(Cow::into_owned))
+        );
+        assert_eq!(lsm.get(b"hot"), Some(b"2".to_vec()));
+        assert_eq!(lsm.get(b"missing"), None);
+
+        // the flushes still persisted the writes to the levels
+        let mut tree = lsm.clone();
+        tree.pinned.clear();
+        assert_eq!(tree.get(b"hot"), Some(b"2".to_vec()));
+
+        // with the levels gone, only the pinned map can answer
+        lsm.levels.clear();
+        lsm.populated.clear();
+        assert_eq!(lsm.get(b"hot"), Some(b"2".to_vec()));
+        assert_eq!(lsm.get_many(&[b"hot".to_vec()]), vec![Some(b"2".to_vec())]);
+        lsm.unpin(b"hot");
+        assert_eq!(lsm.get(b"hot"), None);
+    }
+
+    #[test]
+    fn test_pinned_keys_not_evicted() {
+        let mut lsm = LSMTree::new(2).with_max_entries(4);
+        lsm.insert(b"hot".to_vec(), Some(b"1".to_vec()));
+        lsm.pin(b"hot".to_vec());
+        for i in 0..19u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(vec![1]));
+        }
+        lsm.pinned.clear();
+        assert_eq!(lsm.get(b"hot"), Some(b"1".to_vec()));
+        assert_eq!(lsm.iter_live().count(), 4);
+    }
+
+    #[test]
+    fn test_merge_with() {
+        let mut lsm = LSMTree::new(2);
+        for k in ["a", "b", "d", "f"] {
+            lsm.insert(
+                k.as_bytes().to_vec(),
+                Some(format!("tree-{k}").into_bytes()),
+            );
+        }
+        lsm.delete(b"f".to_vec());
+
+        let other: Vec<(Vec<u8>, Vec<u8>)> = ["b", "c", "f", "g"]
+            .iter()
+            .map(|k| (k.as_bytes().to_vec(), format!("other-{k}").into_bytes()))
+            .collect();
+
+        let pair = |k: &str, side: &str| format!("{side}-{k}").into_bytes();
+        assert_eq!(
+            lsm.merge_with(other.into_iter()).collect::<Vec<_>>(),
+            vec![
+                MergeSide::Left(b"a".to_vec(), pair("a", "tree")),
+                MergeSide::Both(b"b".to_vec(), pair("b", "tree"), pair("b", "other")),
+                MergeSide::Right(b"c".to_vec(), pair("c", "other")),
+                MergeSide::Left(b"d".to_vec(), pair("d", "tree")),
+                // deleted in the tree
+                MergeSide::Right(b"f".to_vec(), pair("f", "other")),
+                MergeSide::Right(b"g".to_vec(), pair("g", "other")),
+            ]
+        );
+        assert_eq!(lsm.merge_with(std::iter::empty()).count(), 3);
+    }
+
+    #[test]
+    fn test_debug_versions() {
+        let mut lsm = LSMTree::new(2);
+        for (k, v) in [("a", "1"), ("b", "2"), ("c", "3"), ("d", "4"), ("b", "20")] {
+            lsm.insert(k.as_bytes().to_vec(), Some(v.as_bytes().to_vec()));
+        }
+        lsm.delete(b"c".to_vec());
+        lsm.insert(b"d".to_vec(), Some(b"40".to_vec()));
+        lsm.insert(b"e".to_vec(), Some(b"5".to_vec()));
+        lsm.insert(b"d".to_vec(), Some(b"400".to_vec()));
+
+        assert_eq!(
+            lsm.debug_versions(b"d"),
+            vec![
+                (Some(b"400".to_vec()), None, 8),
+                (Some(b"40".to_vec()), Some(1), 6),
+                (Some(b"4".to_vec()), Some(2), 3),
+            ]
+        );
+
+        // b's first version was overwritten when L0 and L1 were compacted together
+        assert_eq!(
+            lsm.debug_versions(b"b"),
+            vec![(Some(b"20".to_vec()), Some(2), 4)]
+        );
+        assert_eq!(lsm.debug_versions(b"c"), vec![(None, Some(2), 5)]);
+        assert!(lsm.debug_versions(b"z").is_empty());
+    }
+
+    #[test]
+    fn test_set_flush_threshold() {
+        let mut lsm = LSMTree::new(10);
+        assert_eq!(lsm.flush_threshold(), 10);
+        for i in 0..6u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(vec![1]));
+        }
+        assert_eq!(lsm.memtable.len(), 6);
+
+        // raising it never flushes
+        lsm.set_flush_threshold(20);
+        assert_eq!(lsm.memtable.len(), 6);
+        assert!(lsm.levels.is_empty());
+
+        lsm.set_flush_threshold(4);
+        assert_eq!(lsm.flush_threshold(), 4);
+        assert!(lsm.memtable.is_empty());
+        assert_eq!(lsm.level_entries(0).unwrap().len(), 6);
+
+        // and the new threshold applies to writes from here on
+        for i in 6..10u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(vec![1]));
+        }
+        assert!(lsm.memtable.is_empty());
+        assert_eq!(lsm.iter_live().count(), 10);
+
+        lsm.set_flush_threshold(0);
+        assert_eq!(lsm.flush_threshold(), 1);
+    }
+
+    /// collects log messages per thread, so tests running in parallel don't see each other's
+    struct TestLogger {
+        records: std::sync::Mutex<Vec<(std::thread::ThreadId, String)>>,
+    }
+
+    impl log::Log for TestLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.records
+                .lock()
+                .unwrap()
+                .push((std::thread::current().id(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: TestLogger = TestLogger {
+        records: std::sync::Mutex::new(vec![]),
+    };
+
+    fn captured_logs() -> Vec<String> {
+        let id = std::thread::current().id();
+        LOGGER
+            .records
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(thread, _)| *thread == id)
+            .map(|(_, msg)| msg.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_compaction_logging() {
+        // another test may have installed it already
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Debug);
+
+        let mut lsm = LSMTree::new(2);
+        lsm.insert(b"a".to_vec(), Some(b"1".to_vec()));
+        lsm.insert(b"b".to_vec(), Some(b"2".to_vec()));
+        lsm.insert(b"c".to_vec(), Some(b"3".to_vec()));
+        lsm.delete(b"a".to_vec());
+
+        assert_eq!(
+            captured_logs(),
+            vec![
+                "flush entries=2",
+                "compact level=0 existing=0 incoming=2 output=2 tombstones=0 tombstones_purged=0 \
+                 cascade=false",
+                "flush entries=2",
+                "compact level=0 existing=2 incoming=2 output=3 tombstones=1 tombstones_purged=0 \
+                 cascade=true",
+                "compact level=1 existing=0 incoming=3 output=3 tombstones=1 tombstones_purged=0 \
+                 cascade=false",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_stall_stats() {
+        // moves forward 5ms every time it's read
+        #[derive(Default)]
+        struct Ticking(atomic::AtomicU64);
+        impl Clock for Ticking {
+            fn now(&self) -> Duration {
+                Duration::from_millis(self.0.fetch_add(5, atomic::Ordering::Relaxed))
+            }
+        }
+
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Debug);
+
+        let stalls = |mode| {
+            let mut lsm = LSMTree::new(2)
+                .with_deferred_cascade()
+                .with_write_stall(4, mode)
+                .with_clock(Ticking::default());
+            for i in 0..12u32 {
+                let _ = lsm.try_insert(i.to_be_bytes().to_vec(), Some(vec![1]));
+            }
+            let stats = lsm.stats();
+            (stats.stall_count, stats.total_stall_duration)
+        };
+
+        // writes 5 and 9 find L0 full, and each waits one tick for the compaction
+        assert_eq!(stalls(StallMode::Block), (2, Duration::from_millis(10)));
+
+        // everything from write 5 on is rejected, without waiting
+        assert_eq!(stalls(StallMode::Error), (8, Duration::ZERO));
+
+        let logs: Vec<_> = captured_logs()
+            .into_iter()
+            .filter(|msg| msg.starts_with("write stall"))
+            .collect();
+        assert_eq!(logs.len(), 2 + 8);
+        assert_eq!(logs[0], "write stall l0=4 limit=4 mode=Block stalled=5ms");
+        assert_eq!(logs[2], "write stall l0=4 limit=4 mode=Error stalled=0ns");
+        assert_eq!(LSMTree::new(2).stats().stall_count, 0);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut lsm = LSMTree::new(2);
+        lsm.insert(b"a".to_vec(), Some(b"1".to_vec()));
+        lsm.insert(b"b".to_vec(), Some(b"2".to_vec()));
+        lsm.insert(b"c".to_vec(), Some(b"3".to_vec()));
+        let snapshot = lsm.acquire_snapshot();
+
+        // overwrite in the memtable, and in a level
+        lsm.insert(b"c".to_vec(), Some(b"30".to_vec()));
+        lsm.insert(b"a".to_vec(), Some(b"10".to_vec()));
+        lsm.delete(b"b".to_vec());
+        lsm.insert(b"d".to_vec(), Some(b"4".to_vec()));
+
+        // enough writes to compact everything together a few times over
+        for i in 0..32u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(vec![1]));
+        }
+
+        assert_eq!(lsm.get(b"a"), Some(b"10".to_vec()));
+        assert_eq!(lsm.get(b"b"), None);
+        assert_eq!(lsm.get(b"c"), Some(b"30".to_vec()));
+        assert_eq!(lsm.get_at(b"a", &snapshot), Some(b"1".to_vec()));
+        assert_eq!(lsm.get_at(b"b", &snapshot), Some(b"2".to_vec()));
+        assert_eq!(lsm.get_at(b"c", &snapshot), Some(b"3".to_vec()));
+        assert_eq!(lsm.get_at(b"d", &snapshot), None);
+        assert_eq!(lsm.get_at(&0u32.to_be_bytes(), &snapshot), None);
+
+        // the old versions are still physically there, but not visible to normal reads
+        assert_eq!(lsm.debug_versions(b"a").len(), 2);
+        assert_eq!(lsm.iter_live().count(), 32 + 3);
+        assert_eq!(lsm.get_many(&[b"b".to_vec()]), vec![None]);
+
+        // once the snapshot is gone the next compaction can drop them
+        drop(snapshot);
+        for i in 32..64u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(vec![1]));
+        }
+        let physical: usize = lsm.levels.iter().flatten().map(|l| l.len()).sum();
+        assert_eq!(physical + lsm.memtable.len(), 64 + 4);
+        let versions = lsm.debug_versions(b"a");
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].0, Some(b"10".to_vec()));
+    }
+
+    #[test]
+    fn test_snapshot_partitions() {
+        let mut lsm = LSMTree::new(4).with_partitions(4);
+        for i in 0..8u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(vec![0]));
+        }
+        let snapshot = lsm.acquire_snapshot();
+        for round in 1..4u8 {
+            for i in 0..8u32 {
+                lsm.insert(i.to_be_bytes().to_vec(), Some(vec![round]));
+            }
+        }
+
+        for i in 0..8u32 {
+            assert_eq!(lsm.get(&i.to_be_bytes()), Some(vec![3]));
+            assert_eq!(lsm.get_at(&i.to_be_bytes(), &snapshot), Some(vec![0]));
+        }
+    }
+
+    #[test]
+    fn test_list_children() {
+        let mut lsm = LSMTree::new(3);
+        for key in [
+            "/etc/hosts",
+            "/usr/bin/cargo",
+            "/usr/bin/rustc",
+            "/usr/lib/libc.so",
+            "/usr/local/bin/smol",
+            "/usr/local/share/doc/readme",
+            "/usr/share",
+            "/usr/bin-extra/tool",
+            "/usr/gone/file",
+            "/var/log/syslog",
+        ] {
+            lsm.insert(key.as_bytes().to_vec(), Some(vec![1]));
+        }
+        lsm.delete(b"/usr/gone/file".to_vec());
+
+        let children = |prefix: &str| -> Vec<String> {
+            lsm.list_children(prefix.as_bytes(), b'/')
+                .into_iter()
+                .map(|c| String::from_utf8(c).unwrap())
+                .collect()
+        };
+        assert_eq!(
+            children("/usr/"),
+            vec!["bin", "bin-extra", "lib", "local", "share"]
+        );
+        assert_eq!(children("/usr/local/"), vec!["bin", "share"]);
+        assert_eq!(children("/"), vec!["etc", "usr", "var"]);
+        assert_eq!(children("/usr/bin/"), vec!["cargo", "rustc"]);
+        assert!(children("/opt/").is_empty());
+    }
+
+    #[test]
+    fn test_manual_flush() {
+        let mut lsm = LSMTree::new(4).with_manual_flush();
+        for i in 0..1000u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(vec![1]));
+        }
+        lsm.set_flush_threshold(2);
+        assert_eq!(lsm.memtable.len(), 1000);
+        assert!(lsm.levels.is_empty());
+        assert_eq!(lsm.get(&999u32.to_be_bytes()), Some(vec![1]));
+
+        lsm.flush();
+        assert!(lsm.memtable.is_empty());
+        assert_eq!(
+            lsm.levels.iter().flatten().map(|l| l.len()).sum::<usize>(),
+            1000
+        );
+        assert_eq!(lsm.iter_live().count(), 1000);
+
+        // nothing to flush
+        let levels = lsm.levels.len();
+        lsm.flush();
+        assert_eq!(lsm.levels.len(), levels);
+    }
+
+    #[test]
+    fn test_lookup_skips_empty_levels() {
+        let mut lsm = LSMTree::new(2);
+        for (k, v) in [("a", "1"), ("b", "2"), ("c", "3"), ("d", "4"), ("b", "20")] {
+            lsm.insert(k.as_bytes().to_vec(), Some(v.as_bytes().to_vec()));
+        }
+        lsm.delete(b"c".to_vec());
+
+        // L0 and L1 were compacted away into L2
+        assert!(lsm.levels[0].is_none());
+        assert!(lsm.levels[1].is_none());
+        assert_eq!(lsm.populated, vec![2]);
+
+        assert_eq!(lsm.get(b"b"), Some(b"20".to_vec()));
+        assert_eq!(lsm.get(b"c"), None);
+        assert_eq!(lsm.get(b"z"), None);
+        assert_eq!(lsm.read_cost(b"b"), 1);
+
+        // stays in sync through more compaction, eviction and repair
+        for i in 0..50u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(vec![1]));
+        }
+        let populated = |lsm: &LSMTree| -> Vec<usize> {
+            (0..lsm.levels.len())
+                .filter(|&i| lsm.levels[i].is_some())
+                .collect()
+        };
+        assert_eq!(lsm.populated, populated(&lsm));
+        lsm.repair_level(lsm.populated[0]);
+        assert_eq!(lsm.populated, populated(&lsm));
+
+        let mut lsm = lsm.with_max_entries(10);
+        lsm.insert(b"x".to_vec(), Some(vec![1]));
+        lsm.insert(b"y".to_vec(), Some(vec![1]));
+        assert_eq!(lsm.populated, populated(&lsm));
+        assert_eq!(lsm.iter_live().count(), 10);
+        for (key, value) in lsm.iter_live() {
+            assert_eq!(lsm.get(&key), Some(value));
+        }
+    }
+
+    #[test]
+    fn test_with_seq() {
+        let mut lsm = LSMTree::new(2);
+        for (k, v) in [("a", "1"), ("b", "2"), ("c", "3"), ("d", "4")] {
+            lsm.insert(k.as_bytes().to_vec(), Some(v.as_bytes().to_vec()));
+        }
+        lsm.insert(b"b".to_vec(), Some(b"20".to_vec()));
+        lsm.delete(b"c".to_vec());
+
+        assert_eq!(lsm.get_with_seq(b"a"), Some((b"1".to_vec(), 0)));
+        assert_eq!(lsm.get_with_seq(b"b"), Some((b"20".to_vec(), 4)));
+        assert_eq!(lsm.get_with_seq(b"c"), None);
+        assert_eq!(
+            lsm.iter_live_with_seq().collect::<Vec<_>>(),
+            vec![
+                (b"a".to_vec(), b"1".to_vec(), 0),
+                (b"b".to_vec(), b"20".to_vec(), 4),
+                (b"d".to_vec(), b"4".to_vec(), 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reserve_memtable() {
+        let mut lsm = LSMTree::new(4);
+        lsm.reserve_memtable(1000);
+        for i in 0..10u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(vec![1]));
+        }
+        lsm.reserve_memtable(0);
+        assert_eq!(lsm.iter_live().count(), 10);
+        assert_eq!(lsm.memtable.len(), 2);
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut lsm = LSMTree::new(2);
+        for i in 0..20u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(vec![1]));
+        }
+        lsm.pin(3u32.to_be_bytes().to_vec());
+        let levels = lsm.levels.len();
+        assert!(levels > 0);
+
+        lsm.clear();
+        assert_eq!(lsm.levels.len(), levels);
+        assert!(lsm.levels.iter().all(Option::is_none));
+        assert_eq!(lsm.iter_live().count(), 0);
+        assert_eq!(lsm.get(&3u32.to_be_bytes()), None);
+
+        // the empty slots don't get in the way of reading the memtable
+        lsm.insert(b"a".to_vec(), Some(b"1".to_vec()));
+        assert_eq!(lsm.get(b"a"), Some(b"1".to_vec()));
+        assert_eq!(lsm.get(b"b"), None);
+        assert_eq!(lsm.read_cost(b"b"), 0);
+
+        // and fills back up like normal
+        lsm.insert(b"b".to_vec(), Some(b"2".to_vec()));
+        assert!(lsm.memtable.is_empty());
+        assert_eq!(lsm.levels.len(), levels);
+        assert_eq!(lsm.get(b"b"), Some(b"2".to_vec()));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_stats_json() {
+        let mut lsm = LSMTree::new(3);
+        for (k, v) in [("a", "1"), ("b", "22"), ("c", "333")] {
+            lsm.insert(k.as_bytes().to_vec(), Some(v.as_bytes().to_vec()));
+        }
+        lsm.delete(b"a".to_vec());
+
+        let json: serde_json::Value = serde_json::from_str(&lsm.stats_json()).unwrap();
+        assert_eq!(json["memtable"], 1);
+        assert_eq!(json["stall_count"], 0);
+        assert_eq!(json["sizes"]["count"], 2);
+        assert_eq!(json["sizes"]["values"]["max"], 3);
+        for key in ["min", "max", "mean", "median"] {
+            assert!(json["sizes"]["keys"].get(key).is_some(), "missing {key}");
+        }
+
+        let levels = json["levels"].as_array().unwrap();
+        assert_eq!(levels.len(), lsm.level_stats().len());
+        for key in ["index", "live", "tombstones", "bytes", "capacity"] {
+            assert!(levels[0].get(key).is_some(), "missing {key}");
+        }
+        assert_eq!(levels[0]["live"], 3);
+    }
+
+    #[test]
+    fn test_check_no_overlap() {
+        let mut lsm = LSMTree::new(4).with_partitions(3);
+        for i in 0..40u32 {
+            lsm.insert(format!("key-{i:02}").into_bytes(), Some(vec![1]));
+        }
+        let snapshot = lsm.acquire_snapshot();
+        for i in 0..40u32 {
+            lsm.insert(format!("key-{i:02}").into_bytes(), Some(vec![2]));
+        }
+        assert_eq!(lsm.check_no_overlap(), Ok(()));
+        assert!(lsm.verify().is_ok());
+        drop(snapshot);
+
+        let i = lsm.populated[0];
+        let level = Arc::get_mut(lsm.levels[i].as_mut().unwrap()).unwrap();
+        assert!(level.partitions.len() > 1);
+
+        // copy the first key of partition 1 onto the end of partition 0
+        let moved = level.partitions[1].data[0].clone();
+        level.partitions[0].data.push(moved.clone());
+        let err = lsm.check_no_overlap().unwrap_err();
+        assert!(err.contains("overlaps partition 1"), "{err}");
+        let key = String::from_utf8(moved.0).unwrap();
+        assert!(err.contains(&format!("..=\"{key}\"] overlaps")), "{err}");
+        assert!(matches!(lsm.verify(), Err(LsmError::Corruption(_))));
+
+        // out of order within a partition
+        let level = Arc::get_mut(lsm.levels[i].as_mut().unwrap()).unwrap();
+        level.partitions[0].data.pop();
+        level.partitions[0].data.swap(0, 1);
+        let err = lsm.check_no_overlap().unwrap_err();
+        assert!(err.contains("out of order"), "{err}");
+    }
+
+    /// orders keys as decimal numbers, so "9" < "10" - not byte order
+    struct Numeric;
+
+    impl Comparator for Numeric {
+        fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+            a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+        }
+    }
+
+    #[test]
+    fn test_comparator() {
+        let numbers = [7u32, 100, 9, 10, 1000, 2, 55, 999, 11, 3, 40, 5000, 8];
+        let key = |n: &u32| n.to_string().into_bytes();
+
+        let mut lsm = LSMTree::new(3).with_partitions(2).with_comparator(Numeric);
+        for n in &numbers {
+            lsm.insert(key(n), Some(n.to_le_bytes().to_vec()));
+        }
+        lsm.delete(b"55".to_vec());
+        lsm.bulk_load(vec![(b"6".to_vec(), vec![6])], DuplicatePolicy::Error)
+            .unwrap();
+
+        // every level sorted numerically. a byte order search would look for "10" before "9"
+        // and miss it
+        assert_eq!(lsm.check_no_overlap(), Ok(()));
+        for n in &numbers {
+            let expected = (*n != 55).then(|| n.to_le_bytes().to_vec());
+            assert_eq!(lsm.get(&key(n)), expected, "{n}");
+        }
+        assert_eq!(lsm.get(b"6"), Some(vec![6]));
+
+        let mut expected: Vec<u32> = numbers.iter().copied().filter(|&n| n != 55).collect();
+        expected.push(6);
+        expected.sort_unstable();
+        let keys: Vec<Vec<u8>> = lsm.iter_live().map(|(k, _)| k).collect();
+        assert_eq!(keys, expected.iter().map(key).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_u64_keys() {
+        let numbers = [7u64, 256, 1, 65_536, 255, 2, u64::MAX, 300, 0, 1 << 40];
+        let mut lsm = LSMTree::new(3)
+            .with_partitions(2)
+            .with_comparator(BigEndianU64Comparator);
+        for n in numbers {
+            lsm.insert_u64(n, Some(n.to_le_bytes().to_vec()));
+        }
+        lsm.delete(300u64.to_be_bytes().to_vec());
+
+        assert_eq!(lsm.check_no_overlap(), Ok(()));
+        assert_eq!(lsm.get_u64(256), Some(256u64.to_le_bytes().to_vec()));
+        assert_eq!(lsm.get_u64(300), None);
+        assert_eq!(lsm.get_u64(3), None);
+
+        // 2..=65_536 in numeric order. with little-endian keys 256 would sort before 2
+        let decode = |k: &[u8]| u64::from_be_bytes(k.try_into().unwrap());
+        let range: Vec<u64> = lsm
+            .iter_live()
+            .map(|(k, _)| decode(&k))
+            .filter(|n| (2..=65_536).contains(n))
+            .collect();
+        assert_eq!(range, [2, 7, 255, 256, 65_536]);
+
+        let all: Vec<u64> = lsm.iter_live().map(|(k, _)| decode(&k)).collect();
+        assert_eq!(all, [0, 1, 2, 7, 255, 256, 65_536, 1 << 40, u64::MAX]);
+    }
+
+    #[test]
+    fn test_scan_component() {
+        let key = |component: &[u8], rest: &[u8]| {
+            let len = u32::try_from(component.len()).unwrap();
+            [&len.to_be_bytes()[..], component, rest].concat()
+        };
+
+        let mut lsm = LSMTree::new(2);
+        lsm.insert(key(b"user", b"1"), Some(b"alice".to_vec()));
+        lsm.insert(key(b"user", b"2"), Some(b"bob".to_vec()));
+        lsm.insert(key(b"users", b"1"), Some(b"nope".to_vec()));
+        lsm.insert(key(b"use", b"r1"), Some(b"nope".to_vec()));
+        lsm.insert(key(b"order", b"1"), Some(b"o1".to_vec()));
+        lsm.insert(key(b"user", b""), Some(b"all".to_vec()));
+        lsm.insert(key(b"user", b"3"), Some(b"carol".to_vec()));
+        lsm.delete(key(b"user", b"2"));
+        lsm.insert(b"ab".to_vec(), Some(b"too short".to_vec()));
+
+        // "users" and "use" + "r1" share bytes with "user" but not its length
+        assert_eq!(
+            lsm.scan_component(b"user"),
+            vec![
+                (key(b"user", b""), b"all".to_vec()),
+                (key(b"user", b"1"), b"alice".to_vec()),
+                (key(b"user", b"3"), b"carol".to_vec()),
+            ]
+        );
+        assert_eq!(
+            lsm.scan_component(b"order"),
+            vec![(key(b"order", b"1"), b"o1".to_vec())]
+        );
+        assert_eq!(lsm.scan_component(b"missing"), vec![]);
+
+        // with a comparator the matching keys aren't next to each other, so it can't just seek
+        let mut numeric = LSMTree::new(2).with_comparator(Numeric);
+        for (k, v) in lsm.iter_live() {
+            numeric.insert(k, Some(v));
+        }
+        for component in [&b"user"[..], b"order", b"use", b"missing"] {
+            assert_eq!(
+                numeric.scan_component(component),
+                lsm.scan_component(component)
+            );
+        }
+        assert_eq!(numeric.scan_component(b"users").len(), 1);
+    }
+
+    #[test]
+    fn test_freeze_memtable() {
+        let mut lsm = LSMTree::new(4);
+        assert!(lsm.freeze_memtable().is_none());
+
+        lsm.insert(b"a".to_vec(), Some(b"1".to_vec()));
+        lsm.insert(b"b".to_vec(), Some(b"2".to_vec()));
+        lsm.delete(b"c".to_vec());
+        let frozen = lsm.freeze_memtable().unwrap();
+        assert_eq!(frozen.len(), 3);
+        assert_eq!(lsm.stats().memtable, 0);
+        assert!(lsm.populated.is_empty());
+
+        // still readable, and newer writes shadow it
+        lsm.insert(b"b".to_vec(), Some(b"20".to_vec()));
+        assert_eq!(lsm.get(b"a"), Some(b"1".to_vec()));
+        assert_eq!(lsm.get(b"b"), Some(b"20".to_vec()));
+        assert_eq!(lsm.get(b"c"), None);
+        assert_eq!(
+            lsm.iter_live().collect::<Vec<_>>(),
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"20".to_vec())
+            ]
+        );
+
+        lsm.flush_frozen(frozen);
+        assert!(lsm.frozen.is_empty());
+        assert_eq!(lsm.level_entries(0).unwrap().len(), 3);
+        assert_eq!(lsm.stats().memtable, 1);
+        assert_eq!(lsm.get(b"a"), Some(b"1".to_vec()));
+        assert_eq!(lsm.get(b"b"), Some(b"20".to_vec()));
+
+        // a regular flush takes frozen memtables down first, so they stay below newer writes
+        lsm.insert(b"d".to_vec(), Some(b"4".to_vec()));
+        let older = lsm.freeze_memtable().unwrap();
+        lsm.insert(b"d".to_vec(), Some(b"40".to_vec()));
+        lsm.flush();
+        assert_eq!(lsm.get(b"d"), Some(b"40".to_vec()));
+        assert!(lsm.frozen.is_empty());
+
+        // already flushed, so it's a no-op
+        lsm.flush_frozen(older);
+        assert_eq!(lsm.get(b"d"), Some(b"40".to_vec()));
+    }
+
+    #[test]
+    fn test_merge_sorted_by_seq() {
+        let entry =
+            |key: &[u8], value: &[u8], seq| (key.to_vec(), Record::new(Some(value.to_vec()), seq));
+        // the positionally-old run has the newer version of "b"
+        let old = vec![entry(b"a", b"a1", 1), entry(b"b", b"b9", 9)];
+        let new = vec![entry(b"b", b"b5", 5), entry(b"c", b"c6", 6)];
+
+        let merged = merge_sorted(&old, &new, &Bytewise);
+        let versions: Vec<(&[u8], u64)> =
+            merged.iter().map(|(k, r)| (k.as_slice(), r.seq)).collect();
+        assert_eq!(versions, [(&b"a"[..], 1), (b"b", 9), (b"b", 5), (b"c", 6)]);
+        assert_eq!(
+            resolve(merged[1..3].iter().map(|(_, r)| r), &BlobStore::default())
+                .and_then(|r| r.value.clone()),
+            Some(b"b9".to_vec())
+        );
+
+        // the other way round, new_data wins as usual
+        let merged = merge_sorted(&new, &old, &Bytewise);
+        assert_eq!(merged[1].1.seq, 9);
+        assert_eq!(merged[2].1.seq, 5);
+    }
+
+    #[test]
+    fn test_collect_sorted() {
+        let mut a = LSMTree::new(2);
+        for key in [b"a", b"b", b"c", b"d", b"e"] {
+            a.insert(key.to_vec(), Some(b"old".to_vec()));
+        }
+        a.insert(b"c".to_vec(), Some(b"3".to_vec()));
+        a.delete(b"d".to_vec());
+
+        // same end state, written in a different order into a tree that flushes differently
+        let mut b = LSMTree::new(3);
+        b.delete(b"d".to_vec());
+        for key in [b"e", b"c", b"b", b"a"] {
+            b.insert(key.to_vec(), Some(b"old".to_vec()));
+        }
+        b.insert(b"c".to_vec(), Some(b"3".to_vec()));
+        assert_ne!(a.level_stats(), b.level_stats());
+
+        let expected = vec![
+            (b"a".to_vec(), Some(b"old".to_vec())),
+            (b"b".to_vec(), Some(b"old".to_vec())),
+            (b"c".to_vec(), Some(b"3".to_vec())),
+            (b"d".to_vec(), None),
+            (b"e".to_vec(), Some(b"old".to_vec())),
+        ];
+        assert_eq!(a.collect_sorted(), expected);
+        assert_eq!(b.collect_sorted(), expected);
+
+        // a tombstone isn't the same as never having the key
+        b.delete(b"f".to_vec());
+        assert_ne!(a.collect_sorted(), b.collect_sorted());
+    }
+
+    #[test]
+    fn test_get_status() {
+        let mut lsm = LSMTree::new(2);
+        lsm.insert(b"a".to_vec(), Some(b"1".to_vec()));
+        lsm.insert(b"b".to_vec(), Some(b"2".to_vec()));
+        lsm.delete(b"b".to_vec());
+        lsm.insert(b"c".to_vec(), Some(b"3".to_vec()));
+        lsm.insert(b"d".to_vec(), Some(b"4".to_vec()));
+        lsm.delete(b"e".to_vec());
+
+        assert_eq!(lsm.get_status(b"a"), GetStatus::Present(b"1".to_vec()));
+        assert_eq!(lsm.get_status(b"b"), GetStatus::Deleted);
+        assert_eq!(lsm.get_status(b"e"), GetStatus::Deleted);
+        assert_eq!(lsm.get_status(b"z"), GetStatus::Absent);
+
+        // the tombstone stays newest as it's compacted down, until the key is written again
+        for i in 0..8u8 {
+            lsm.insert(vec![b'f', i], Some(vec![i]));
+        }
+        assert_eq!(lsm.get_status(b"b"), GetStatus::Deleted);
+        lsm.insert(b"b".to_vec(), Some(b"20".to_vec()));
+        assert_eq!(lsm.get_status(b"b"), GetStatus::Present(b"20".to_vec()));
+    }
+
+    #[test]
+    fn test_range_prunes_levels() {
+        let mut lsm = LSMTree::new(2);
+        for key in [b"a", b"b", b"c", b"d"] {
+            lsm.insert(key.to_vec(), Some(key.to_vec()));
+        }
+        lsm.insert(b"x".to_vec(), Some(b"x".to_vec()));
+        lsm.flush();
+        lsm.insert(b"b".to_vec(), Some(b"b2".to_vec()));
+
+        let range = |a: &[u8], b: &[u8]| Some((a.to_vec(), b.to_vec()));
+        assert_eq!(
+            lsm.level_key_ranges(),
+            vec![range(b"x", b"x"), range(b"a", b"d")]
+        );
+
+        // only L0 overlaps
+        assert_eq!(lsm.range(b"w", b"z"), vec![(b"x".to_vec(), b"x".to_vec())]);
+
+        // only L1, with the memtable's newer "b" on top. end is exclusive
+        assert_eq!(
+            lsm.range(b"b", b"d"),
+            vec![
+                (b"b".to_vec(), b"b2".to_vec()),
+                (b"c".to_vec(), b"c".to_vec())
+            ]
+        );
+
+        // both, and nothing in a gap between them
+        assert_eq!(lsm.range(b"c", b"y").len(), 3);
+        assert_eq!(lsm.range(b"e", b"w"), vec![]);
+
+        lsm.clear();
+        assert_eq!(lsm.level_key_ranges(), vec![None, None]);
+
+        // the seek into a level lands in the right partition, and the right spot in it
+        let mut lsm = LSMTree::new(4).with_partitions(3);
+        for i in 0..100u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(vec![1]));
+        }
+        for (start, end) in [(0u32, 100u32), (17, 18), (33, 71), (50, 50), (99, 200)] {
+            let (start, end) = (start.to_be_bytes(), end.to_be_bytes());
+            let expected: Vec<_> = lsm
+                .iter_live()
+                .filter(|(k, _)| k.as_slice() >= start.as_slice() && k.as_slice() < end.as_slice())
+                .collect();
+            assert_eq!(lsm.range(&start, &end), expected);
+        }
+    }
+
+    #[test]
+    fn test_insert_and_get_prev() {
+        let mut lsm = LSMTree::new(2);
+        assert_eq!(lsm.insert_and_get_prev(b"a".to_vec(), b"1".to_vec()), None);
+        assert_eq!(
+            lsm.insert_and_get_prev(b"a".to_vec(), b"2".to_vec()),
+            Some(b"1".to_vec())
+        );
+
+        // the previous value can be down in the levels
+        lsm.insert(b"b".to_vec(), Some(b"x".to_vec()));
+        assert!(lsm.memtable.is_empty());
+        assert_eq!(
+            lsm.insert_and_get_prev(b"a".to_vec(), b"3".to_vec()),
+            Some(b"2".to_vec())
+        );
+
+        lsm.delete(b"a".to_vec());
+        assert_eq!(lsm.insert_and_get_prev(b"a".to_vec(), b"4".to_vec()), None);
+        assert_eq!(lsm.get(b"a"), Some(b"4".to_vec()));
+    }
+
+    #[test]
+    fn test_newest_wins_across_levels() {
+        let mut lsm = LSMTree::new(2);
+        for round in 0..20u8 {
+            lsm.insert(vec![b'k', round % 3], Some(vec![round]));
+            lsm.insert(vec![b'f', round], Some(vec![round]));
+            if round % 7 == 0 {
+                lsm.delete(vec![b'k', 1]);
+            }
+        }
+        assert!(lsm.populated.len() > 1);
+
+        // a key's versions only ever get older going down the levels, and the newest one is
+        // what reads see
+        for key in lsm.collect_sorted().into_iter().map(|(k, _)| k) {
+            let versions = lsm.debug_versions(&key);
+            let seqs: Vec<u64> = versions.iter().map(|&(_, _, seq)| seq).collect();
+            assert!(seqs.is_sorted_by(|a, b| a > b), "{key:?} {seqs:?}");
+            let slots: Vec<usize> = versions.iter().filter_map(|&(_, level, _)| level).collect();
+            assert!(slots.is_sorted(), "{key:?} {slots:?}");
+            assert_eq!(lsm.get(&key), versions[0].0);
+        }
+
+        assert_eq!(lsm.get(&[b'k', 0]), Some(vec![18]));
+        assert_eq!(lsm.get(&[b'k', 1]), Some(vec![19]));
+        assert_eq!(lsm.get(&[b'k', 2]), Some(vec![17]));
+        assert_eq!(
+            lsm.levels_newest_first()
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>(),
+            lsm.populated
+        );
+    }
+
+    #[test]
+    fn test_scan_prefix_limited() {
+        let write = |lsm: &mut LSMTree| {
+            for i in (0..200u32).rev() {
+                lsm.insert(
+                    format!("user/{i:03}").into_bytes(),
+                    Some(i.to_le_bytes().to_vec()),
+                );
+            }
+            lsm.insert(b"use".to_vec(), Some(vec![]));
+            lsm.insert(b"usf".to_vec(), Some(vec![]));
+            lsm.delete(b"user/001".to_vec());
+        };
+        let mut lsm = LSMTree::new(8);
+        write(&mut lsm);
+
+        let keys = |pairs: Vec<(Vec<u8>, Vec<u8>)>| {
+            pairs
+                .into_iter()
+                .map(|(k, _)| String::from_utf8(k).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            keys(lsm.scan_prefix_limited(b"user/", 4)),
+            ["user/000", "user/002", "user/003", "user/004"]
+        );
+        assert_eq!(lsm.scan_prefix_limited(b"user/", 0), vec![]);
+        assert_eq!(lsm.scan_prefix_limited(b"user/", 1000).len(), 199);
+        assert_eq!(keys(lsm.scan_prefix_limited(b"us", 2)), ["use", "user/000"]);
+        assert_eq!(lsm.scan_prefix_limited(b"zzz", 10), vec![]);
+
+        // the seek lands in the right partition of each level
+        let mut partitioned = LSMTree::new(8).with_partitions(4);
+        write(&mut partitioned);
+        for (prefix, limit) in [(&b"user/1"[..], 30), (b"user/05", 5), (b"usf", 1), (b"", 3)] {
+            assert_eq!(
+                partitioned.scan_prefix_limited(prefix, limit),
+                lsm.scan_prefix_limited(prefix, limit)
+            );
+        }
+        assert_eq!(
+            keys(partitioned.scan_prefix_limited(b"user/19", 3)),
+            ["user/190", "user/191", "user/192"]
+        );
+
+        // with a custom comparator the prefix's keys aren't next to each other
+        let mut lsm = LSMTree::new(4).with_comparator(Numeric);
+        for n in [5u32, 50, 500, 6, 51, 510] {
+            lsm.insert(n.to_string().into_bytes(), Some(vec![]));
+        }
+        assert_eq!(keys(lsm.scan_prefix_limited(b"5", 3)), ["5", "50", "51"]);
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let mut a = LSMTree::new(2);
+        for i in 0..20u8 {
+            a.insert(vec![i], Some(vec![i; 3]));
+        }
+        a.delete(vec![5]);
+
+        // different order, a different layout, and a tombstone for a key `a` never had
+        let mut b = LSMTree::new(5);
+        for i in (0..20u8).rev() {
+            b.insert(vec![i], Some(vec![0]));
+            b.insert(vec![i], Some(vec![i; 3]));
+        }
+        b.delete(vec![5]);
+        b.delete(vec![100]);
+        assert_ne!(a.level_stats(), b.level_stats());
+        assert_eq!(a.content_hash(), b.content_hash());
+
+        b.insert(vec![7], Some(vec![7, 7, 8]));
+        assert_ne!(a.content_hash(), b.content_hash());
+
+        // lengths are part of the hash, so shifting a byte from key to value changes it
+        let mut c = LSMTree::new(2);
+        c.insert(b"ab".to_vec(), Some(b"c".to_vec()));
+        let mut d = LSMTree::new(2);
+        d.insert(b"a".to_vec(), Some(b"bc".to_vec()));
+        assert_ne!(c.content_hash(), d.content_hash());
+        assert_ne!(LSMTree::new(2).content_hash(), c.content_hash());
+    }
+
+    #[test]
+    fn test_range_hash() {
+        let build = || {
+            let mut lsm = LSMTree::new(3);
+            for i in 0..40u8 {
+                lsm.insert(vec![i], Some(vec![i]));
+            }
+            lsm
+        };
+        let a = build();
+        let mut b = build();
+        b.insert(vec![25], Some(vec![0]));
+
+        let everything: (&[u8], &[u8]) = (&[], &[0xff]);
+        assert_eq!(a.range_hash(everything.0, everything.1), a.content_hash());
+        assert_ne!(
+            a.range_hash(everything.0, everything.1),
+            b.range_hash(everything.0, everything.1)
+        );
+
+        // binary search down to the changed key
+        let (mut start, mut end) = (0u8, 40u8);
+        while end - start > 1 {
+            let mid = start + (end - start) / 2;
+            if a.range_hash(&[start], &[mid]) == b.range_hash(&[start], &[mid]) {
+                start = mid;
+            } else {
+                end = mid;
+            }
+        }
+        assert_eq!(start, 25);
+
+        assert_eq!(a.range_hash(&[0], &[25]), b.range_hash(&[0], &[25]));
+        assert_eq!(a.range_hash(&[26], &[40]), b.range_hash(&[26], &[40]));
+        assert_ne!(a.range_hash(&[25], &[26]), b.range_hash(&[25], &[26]));
+    }
+
+    #[test]
+    fn test_empty_key() {
+        let mut lsm = LSMTree::new(2).with_partitions(2);
+        lsm.insert(b"b".to_vec(), Some(b"2".to_vec()));
+        lsm.insert(vec![], Some(b"empty".to_vec()));
+        lsm.insert(b"a".to_vec(), Some(b"1".to_vec()));
+        assert_eq!(lsm.get(b""), Some(b"empty".to_vec()));
+
+        // sorts before everything, through flushes and compaction
+        for i in 0..10u8 {
+            lsm.insert(vec![b'c', i], Some(vec![i]));
+        }
+        assert!(!lsm.memtable.contains_key(&[][..]));
+        assert_eq!(lsm.check_no_overlap(), Ok(()));
+        assert_eq!(lsm.get(b""), Some(b"empty".to_vec()));
+        assert_eq!(lsm.iter_live().next(), Some((vec![], b"empty".to_vec())));
+
+        // an empty prefix or range start matches everything, the empty key included
+        assert_eq!(lsm.scan_prefix_limited(b"", usize::MAX).len(), 13);
+        assert_eq!(
+            lsm.scan_prefix_limited(b"", 2),
+            vec![(vec![], b"empty".to_vec()), (b"a".to_vec(), b"1".to_vec())]
+        );
+        assert_eq!(lsm.range(b"", b"a"), vec![(vec![], b"empty".to_vec())]);
+        assert_eq!(lsm.range(b"", b"").len(), 0);
+        assert_eq!(lsm.list_children(b"", b'/').len(), 12);
+        assert_eq!(lsm.get_with_neighbors(b"").2, Some(b"a".to_vec()));
+
+        lsm.delete(vec![]);
+        assert_eq!(lsm.get(b""), None);
+        assert_eq!(lsm.get_status(b""), GetStatus::Deleted);
+        assert_eq!(
+            lsm.scan_prefix_limited(b"", 1),
+            vec![(b"a".to_vec(), b"1".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_compact_coalesces_levels() {
+        let mut lsm = LSMTree::new(2);
+        for i in 0..30u8 {
+            lsm.insert(vec![i], Some(vec![i]));
+        }
+        assert_eq!(lsm.populated, vec![2, 3, 4]);
+
+        // pulling most keys down to the bottom leaves L2 with a few stragglers, though
+        // everything would fit in L4
+        lsm.compact_range(&[0], &[26]);
+        assert_eq!(lsm.populated, vec![2, 4]);
+        let before = lsm.collect_sorted();
+
+        lsm.compact();
+        assert_eq!(lsm.populated, vec![4]);
+        assert_eq!(lsm.collect_sorted(), before);
+        assert!(lsm.verify().is_ok());
+        for i in 0..30u8 {
+            assert_eq!(lsm.get(&[i]), Some(vec![i]));
+        }
+
+        // afterwards no two neighbouring levels fit together, and nothing's lost along the way
+        let mut lsm = LSMTree::new(3);
+        for i in 0..200u8 {
+            lsm.insert(vec![i % 150], Some(vec![i]));
+        }
+        let before = lsm.collect_sorted();
+        lsm.compact();
+        let stats = lsm.level_stats();
+        for pair in lsm.populated.windows(2) {
+            let len = |i: usize| stats[i].live + stats[i].tombstones;
+            assert!(len(pair[0]) + len(pair[1]) >= lsm.level_capacity(pair[1]));
+        }
+        assert_eq!(lsm.collect_sorted(), before);
+    }
+
+    #[test]
+    fn test_levels_within_capacity() {
+        // xorshift, so the workload is random but the same every run
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move |n: u64| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % n
+        };
+
+        let mut lsm = LSMTree::new(4).with_partitions(2);
+        for _ in 0..5000 {
+            let key = next(500).to_be_bytes().to_vec();
+            match next(100) {
+                0..70 => lsm.insert(key, Some(vec![0; 4])),
+                70..90 => lsm.delete(key),
+                90..95 => lsm.flush(),
+                95..98 => {
+                    let pairs = (0..next(20)).map(|i| (i.to_be_bytes().to_vec(), vec![1]));
+                    lsm.bulk_load(pairs.collect(), DuplicatePolicy::KeepLast)
+                        .unwrap();
+                }
+                _ => {
+                    let start = next(500);
+                    lsm.compact_range(&start.to_be_bytes(), &(start + 50).to_be_bytes());
+                }
+            }
+
+            // the debug_assert fires inside the merge, but check the whole layout too
+            let deepest = lsm.populated.last().copied();
+            for (i, level) in lsm.levels_newest_first() {
+                assert!(
+                    Some(i) == deepest || level.len() < lsm.level_capacity(i),
+                    "level {i} holds {}",
+                    level.len()
+                );
+            }
+        }
+        assert!(lsm.populated.len() > 2);
+    }
+
+    #[test]
+    fn test_galloping_merge() {
+        let mut seq = 0;
+        let mut run = |keys: &[u8]| -> Vec<VersionedEntry> {
+            // a key twice in a row is two versions of it, newest first
+            let mut entries: Vec<VersionedEntry> = keys
+                .iter()
+                .map(|&k| {
+                    seq += 1;
+                    (vec![k], Record::new(Some(vec![k]), seq))
+                })
+                .collect();
+            for pair in entries.chunk_by_mut(|a, b| a.0 == b.0) {
+                pair.reverse();
+            }
+            entries
+        };
+
+        let large = run(&(0..200).step_by(2).collect::<Vec<u8>>());
+        let small = run(&[0, 1, 2, 2, 50, 51, 100, 198, 199, 250]);
+        let older_small = {
+            let mut small = small.clone();
+            for (_, record) in &mut small {
+                record.seq = 0;
+            }
+            small
+        };
+
+        // gallop on either side, with the small run newer or older than the large one
+        for (old, new) in [
+            (&large, &small),
+            (&small, &large),
+            (&large, &older_small),
+            (&older_small, &large),
+        ] {
+            let merged = merge_sorted(old, new, &Bytewise);
+            assert_eq!(
+                merged,
+                merge_linear(old, new, &Bytewise, &mut VersionedEntry::clone)
+            );
+            assert_eq!(merged.len(), old.len() + new.len());
+        }
+
+        // an empty side just copies the other
+        assert_eq!(merge_sorted(&large, &[], &Bytewise), large);
+        assert_eq!(merge_sorted(&[], &small, &Bytewise), small);
+        assert_eq!(gallop(&[1, 2, 3, 10, 11], |&n| n < 5), 3);
+        assert_eq!(gallop(&[1, 2, 3], |&n| n < 5), 3);
+        assert_eq!(gallop::<u8>(&[], |_| true), 0);
+    }
+
+    #[test]
+    fn test_count_live() {
+        let mut lsm = LSMTree::new(2);
+        assert_eq!(lsm.count_live(), 0);
+        for i in 0..20u8 {
+            lsm.insert(vec![i], Some(vec![i]));
+        }
+        lsm.insert(vec![3], Some(vec![30]));
+        lsm.delete(vec![4]);
+        lsm.delete(vec![5]);
+        lsm.delete(vec![100]);
+        assert_eq!(lsm.count_live(), 18);
+        assert_eq!(lsm.count_live(), lsm.iter_live().count());
+    }
+
+    #[test]
+    fn test_overlapping_runs_dedup_by_seq() {
+        let entry =
+            |key: &[u8], value: &[u8], seq| (key.to_vec(), Record::new(Some(value.to_vec()), seq));
+
+        // three runs in the order they'd be searched, but the one searched last wrote "b" last
+        let runs = [
+            vec![entry(b"a", b"a5", 5), entry(b"b", b"b4", 4)],
+            vec![entry(b"b", b"b2", 2), entry(b"c", b"c3", 3)],
+            vec![entry(b"a", b"a1", 1), entry(b"b", b"b9", 9)],
+        ];
+        let expected = vec![
+            (b"a".to_vec(), Some(b"a5".to_vec())),
+            (b"b".to_vec(), Some(b"b9".to_vec())),
+            (b"c".to_vec(), Some(b"c3".to_vec())),
+        ];
+
+        let blobs = BlobStore::default();
+        let sources: Vec<Box<dyn Iterator<Item = EntryRef<'_>> + Send + '_>> = runs
+            .iter()
+            .map(|run| {
+                let run = run.iter().map(|(k, r)| (k.as_slice(), r));
+                Box::new(run) as Box<dyn Iterator<Item = EntryRef<'_>> + Send + '_>
+            })
+            .collect();
+        let merged: Vec<Entry> = MergeIter::new(sources, &Bytewise, &blobs)
+            .map(|(k, r)| (k.to_vec(), r.value.clone()))
+            .collect();
+        assert_eq!(merged, expected);
+
+        // compacting them together keeps just the newest version of each key, whatever order the
+        // runs are folded in
+        for order in [[0, 1, 2], [2, 1, 0], [1, 2, 0]] {
+            let data = order
+                .iter()
+                .fold(vec![], |acc, &i| merge_sorted(&acc, &runs[i], &Bytewise));
+            let compacted: Vec<Entry> = retain_visible(data, &[])
+                .into_iter()
+                .map(|(k, r)| (k, r.value))
+                .collect();
+            assert_eq!(compacted, expected, "{order:?}");
+        }
+
+        // the same runs as levels - get goes by seq too, and agrees with the merged scan
+        let mut lsm = LSMTree::new(100);
+        for (i, run) in runs.iter().enumerate() {
+            let level = lsm.new_level(i, run.clone());
+            lsm.set_level(i, level);
+        }
+        for (key, value) in &expected {
+            assert_eq!(&lsm.get(key), value);
+        }
+        let live: Vec<Entry> = lsm.iter_live().map(|(k, v)| (k, Some(v))).collect();
+        assert_eq!(live, expected);
+
+        // b9 is in the last level so that gets searched, but nothing in the middle one is newer
+        // than b4
+        assert_eq!(lsm.read_cost(b"b"), 2);
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut lsm = LSMTree::new(2);
+        assert!(lsm.keys().is_empty());
+        for round in 0..3u8 {
+            for i in (0..10u8).rev() {
+                lsm.insert(vec![i], Some(vec![round]));
+            }
+        }
+        lsm.delete(vec![3]);
+        lsm.insert(vec![4], Some(vec![]));
+        lsm.delete(vec![42]);
+
+        // versions of the same key in several levels come out once
+        assert!((0..10u8).any(|i| lsm.debug_versions(&[i]).len() > 1));
+        let keys = lsm.keys();
+        assert_eq!(keys, lsm.iter_live().map(|(k, _)| k).collect::<Vec<_>>());
+        assert_eq!(keys.len(), 9);
+        assert!(keys.is_sorted());
+        assert!(!keys.contains(&vec![3]));
+    }
+
+    #[test]
+    fn test_key_set_operations() {
+        let tree = |keys: &[u8], deleted: &[u8]| {
+            let mut lsm = LSMTree::new(2);
+            for &k in keys {
+                lsm.insert(vec![k], Some(vec![k]));
+            }
+            for &k in deleted {
+                lsm.delete(vec![k]);
+            }
+            lsm
+        };
+        let keys = |ks: &[u8]| ks.iter().map(|&k| vec![k]).collect::<Vec<_>>();
+
+        // 5 is deleted from b, so it only counts as in a
+        let a = tree(&[1, 2, 3, 5, 8, 9, 10], &[]);
+        let b = tree(&[0, 2, 3, 4, 5, 9, 11], &[5]);
+        assert_eq!(a.intersect_keys(&b), keys(&[2, 3, 9]));
+        assert_eq!(b.intersect_keys(&a), keys(&[2, 3, 9]));
+        assert_eq!(a.difference_keys(&b), keys(&[1, 5, 8, 10]));
+        assert_eq!(b.difference_keys(&a), keys(&[0, 4, 11]));
+
+        let disjoint = tree(&[20, 21, 22], &[]);
+        assert!(a.intersect_keys(&disjoint).is_empty());
+        assert_eq!(a.difference_keys(&disjoint), a.keys());
+
+        let empty = LSMTree::new(2);
+        assert!(a.intersect_keys(&empty).is_empty());
+        assert_eq!(a.difference_keys(&empty), a.keys());
+        assert!(empty.difference_keys(&a).is_empty());
+        assert_eq!(a.intersect_keys(&a), a.keys());
+    }
+
+    #[test]
+    fn test_merge_presized() {
+        let run = |keys: std::ops::Range<u8>, seq| -> Vec<VersionedEntry> {
+            keys.map(|k| (vec![k], Record::new(Some(vec![k]), seq)))
+                .collect()
+        };
+        let old = run(0..40, 1);
+        let new = run(20..60, 2);
+
+        // allocated once at the full size, so pushing never had to grow it
+        let merged = merge_linear(&old, &new, &Bytewise, &mut VersionedEntry::clone);
+        assert_eq!(merged.capacity(), old.len() + new.len());
+        assert_eq!(merged, merge_sorted(&old, &new, &Bytewise));
+        assert_eq!(merged.len(), 80);
+        assert!(merged.is_sorted_by(|a, b| old_goes_first(&Bytewise, a, b)));
+    }
+
+    #[test]
+    fn test_monotonic_inserts() {
+        let key = |i: u32| i.to_be_bytes().to_vec();
+
+        // ever-increasing keys stay on the fast path through every flush
+        let mut lsm = LSMTree::new(8);
+        for i in 0..100u32 {
+            lsm.insert(key(i), Some(i.to_le_bytes().to_vec()));
+            assert!(lsm.memtable_in_order, "{i}");
+        }
+        lsm.append(key(100), b"x".to_vec());
+        lsm.append(key(100), b"y".to_vec());
+        assert_eq!(lsm.get(&key(100)), Some(b"xy".to_vec()));
+        assert_eq!(lsm.iter_live().count(), 101);
+        assert_eq!(lsm.get(&key(42)), Some(42u32.to_le_bytes().to_vec()));
+        assert!(lsm.verify().is_ok());
+
+        // one key out of order drops the memtable off the fast path until it's flushed
+        let mut mixed = LSMTree::new(8);
+        let mut expected = BTreeMap::new();
+        for i in 0..100u32 {
+            let k = if i % 10 == 7 { key(i / 2) } else { key(i) };
+            mixed.insert(k.clone(), Some(i.to_le_bytes().to_vec()));
+            expected.insert(k, i.to_le_bytes().to_vec());
+        }
+        mixed.insert(key(200), Some(vec![2]));
+        mixed.insert(key(5), Some(vec![5]));
+        expected.insert(key(200), vec![2]);
+        expected.insert(key(5), vec![5]);
+        assert!(!mixed.memtable_in_order);
+        mixed.flush();
+        mixed.insert(key(1000), Some(vec![1]));
+        assert!(mixed.memtable_in_order);
+        expected.insert(key(1000), vec![1]);
+        assert_eq!(
+            mixed.iter_live().collect::<Vec<_>>(),
+            expected.into_iter().collect::<Vec<_>>()
+        );
+
+        // increasing numerically but not in bytes ("9" < "10" < "11"), so the flush still sorts
+        let mut numeric = LSMTree::new(4).with_comparator(Numeric);
+        for n in 8..20u32 {
+            numeric.insert(n.to_string().into_bytes(), Some(vec![1]));
+        }
+        assert!(numeric.verify().is_ok());
+        let keys: Vec<_> = numeric.iter_live().map(|(k, _)| k).collect();
+        let sorted: Vec<_> = (8..20u32).map(|n| n.to_string().into_bytes()).collect();
+        assert_eq!(keys, sorted);
+    }
+
+    #[test]
+    fn test_estimate_compression_ratio() {
+        // long shared prefixes and repetitive values
+        let mut paths = LSMTree::new(16);
+        for i in 0..200u32 {
+            let key = format!("/usr/local/share/app/data/{i:08}").into_bytes();
+            paths.insert(key, Some(vec![0; 4]));
+        }
+        paths.flush();
+        let ratio_paths = paths.estimate_compression_ratio(usize::MAX);
+        assert!(ratio_paths < 0.5, "{ratio_paths}");
+
+        // hashes share next to nothing
+        let mut random = LSMTree::new(16);
+        for i in 0..200u32 {
+            let hash = Sha256::digest(i.to_le_bytes());
+            random.insert(hash[..16].to_vec(), Some(hash[16..].to_vec()));
+        }
+        random.flush();
+        let ratio = random.estimate_compression_ratio(usize::MAX);
+        assert!((0.9..1.2).contains(&ratio), "{ratio}");
 
-    // threshold for flushing memtable to disk
-    memtable_flush_threshold: usize,
-}
+        // only the sampled levels count, and nothing in the tree changes
+        let before = random.level_stats();
+        let sampled = random.estimate_compression_ratio(1);
+        assert!((0.9..1.2).contains(&sampled), "{sampled}");
+        assert_eq!(random.level_stats(), before);
 
-pub struct LSMLevel {
-    data: Vec<(Vec<u8>, Option<Vec<u8>>)>,
-}
+        assert!((LSMTree::new(4).estimate_compression_ratio(3) - 1.0).abs() < f64::EPSILON);
+        assert!((paths.estimate_compression_ratio(0) - 1.0).abs() < f64::EPSILON);
 
-impl LSMTree {
-    #[must_use]
-    pub fn new(memtable_flush_threshold: usize) -> Self {
-        let memtable = BTreeMap::new();
-        LSMTree {
-            memtable,
-            levels: vec![],
-            memtable_flush_threshold,
+        // offloaded values are compressed like the rest, not counted as empty
+        let mut offloaded = LSMTree::new(16).with_blob_threshold(2);
+        for i in 0..200u32 {
+            let key = format!("/usr/local/share/app/data/{i:08}").into_bytes();
+            offloaded.insert(key, Some(vec![0; 4]));
         }
+        offloaded.flush();
+        assert!(offloaded.blobs.written > 0);
+        let offloaded = offloaded.estimate_compression_ratio(usize::MAX);
+        assert!(
+            (offloaded - ratio_paths).abs() < f64::EPSILON,
+            "{offloaded}"
+        );
     }
 
-    /// returns the capacity for a given level
-    ///
-    /// each level can hold threshold × 2 ^ level
-    /// this balances write amplification with read performance and space usage
-    ///
-    /// examples:
-    /// 1. if each level had the same capacity, on every write, we would flush through every level
-    /// 2. if this was a large ratio (for example 10^level), we do compaction less frequently, but:
-    ///   - waste more space - arent getting rid of stale values
-    ///   - reads are slower - more in a level, slower binary search is
-    fn level_capacity(&self, level: usize) -> usize {
-        self.memtable_flush_threshold << level
+    #[test]
+    fn test_changes_since_sorted() {
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        let mut lsm = LSMTree::new(3);
+        for i in 0..20u32 {
+            lsm.insert(key(i), Some(vec![0]));
+        }
+
+        let seq = lsm.next_seq();
+        let _snapshot = lsm.acquire_snapshot();
+        lsm.insert(key(15), Some(vec![1]));
+        lsm.delete(key(4));
+        lsm.insert(key(30), Some(vec![1]));
+        lsm.insert(key(2), Some(vec![1]));
+        lsm.insert(key(15), Some(vec![2]));
+        lsm.delete(key(30));
+        lsm.delete(key(99));
+        // pushes the older changes down through the levels
+        for i in 100..110u32 {
+            lsm.insert(key(i), Some(vec![3]));
+        }
+        assert!(lsm.populated.len() > 1);
+
+        let mut expected = vec![
+            (key(2), Some(vec![1])),
+            (key(4), None),
+            (key(15), Some(vec![2])),
+            (key(30), None),
+            (key(99), None),
+        ];
+        expected.extend((100..110u32).map(|i| (key(i), Some(vec![3]))));
+        assert_eq!(lsm.changes_since_sorted(seq), expected);
+
+        assert_eq!(lsm.changes_since_sorted(lsm.next_seq()), vec![]);
+        assert_eq!(lsm.changes_since_sorted(0).len(), 20 + 2 + 10);
     }
 
-    /// insert the key-value pair into self.memtable (it's a `BTreeMap`)
-    /// check if memtable size has reached `self.memtable_threshold`
-    /// if threshold reached, call `self.flush_memtable()` to write it to level 0
-    pub fn insert(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) {
-        self.memtable.insert(key, value);
+    #[test]
+    fn test_scan_by_level() {
+        let mut lsm = LSMTree::new(2);
+        lsm.insert(b"a".to_vec(), Some(b"1".to_vec()));
+        lsm.insert(b"c".to_vec(), Some(b"1".to_vec()));
+        lsm.insert(b"b".to_vec(), Some(b"2".to_vec()));
+        lsm.delete(b"c".to_vec());
+        lsm.insert(b"z".to_vec(), Some(b"2".to_vec()));
+        lsm.flush();
+        lsm.insert(b"a".to_vec(), Some(b"3".to_vec()));
+        assert_eq!(lsm.populated, vec![0, 1]);
 
-        if self.memtable.len() >= self.memtable_flush_threshold {
-            self.flush_memtable();
-        }
+        let frozen = lsm.freeze_memtable().unwrap();
+        lsm.insert(b"b".to_vec(), Some(b"4".to_vec()));
+
+        // every version where it sits, the shadowed ones and the tombstone too. L0 only has z
+        let entry = |k: &[u8], v: Option<&[u8]>| (k.to_vec(), v.map(<[u8]>::to_vec));
+        assert_eq!(
+            lsm.scan_by_level(b"a", b"y"),
+            vec![
+                (MEMTABLE_SOURCE, vec![entry(b"b", Some(b"4"))]),
+                (MEMTABLE_SOURCE, vec![entry(b"a", Some(b"3"))]),
+                (
+                    1,
+                    vec![
+                        entry(b"a", Some(b"1")),
+                        entry(b"b", Some(b"2")),
+                        entry(b"c", None)
+                    ]
+                ),
+            ]
+        );
+
+        // a range only one source has anything in
+        assert_eq!(
+            lsm.scan_by_level(b"x", b"zz"),
+            vec![(0, vec![entry(b"z", Some(b"2"))])]
+        );
+        assert!(lsm.scan_by_level(b"d", b"x").is_empty());
+        lsm.flush_frozen(frozen);
     }
 
-    /// deletes a key by inserting a tombstone (`None`) for that key
-    pub fn delete(&mut self, key: Vec<u8>) {
-        self.insert(key, None);
+    #[test]
+    fn test_flush_to_level() {
+        let mut lsm = LSMTree::new(4);
+        lsm.insert(b"a".to_vec(), Some(b"1".to_vec()));
+        lsm.insert(b"b".to_vec(), Some(b"1".to_vec()));
+        lsm.delete(b"c".to_vec());
+        lsm.flush_to_level(2).unwrap();
+        assert_eq!(lsm.populated, vec![2]);
+        assert_eq!(
+            lsm.level_entries(2).unwrap(),
+            vec![
+                (b"a".to_vec(), Some(b"1".to_vec())),
+                (b"b".to_vec(), Some(b"1".to_vec())),
+                (b"c".to_vec(), None),
+            ]
+        );
+
+        // merges with what's already there, and newer versions still win
+        lsm.insert(b"b".to_vec(), Some(b"2".to_vec()));
+        lsm.insert(b"c".to_vec(), Some(b"2".to_vec()));
+        lsm.flush_to_level(2).unwrap();
+        assert_eq!(lsm.populated, vec![2]);
+        assert_eq!(lsm.level_entries(2).unwrap().len(), 3);
+
+        // then normal flushes stack up on top of it
+        lsm.insert(b"a".to_vec(), Some(b"3".to_vec()));
+        lsm.flush();
+        assert_eq!(lsm.populated, vec![0, 2]);
+        assert_eq!(lsm.get(b"a"), Some(b"3".to_vec()));
+        assert_eq!(lsm.get(b"b"), Some(b"2".to_vec()));
+        assert_eq!(lsm.get(b"c"), Some(b"2".to_vec()));
+        assert!(lsm.verify().is_ok());
+
+        // newer data can't go under L0's older data
+        lsm.insert(b"d".to_vec(), Some(b"4".to_vec()));
+        let err = lsm.flush_to_level(1).unwrap_err();
+        assert!(matches!(err, LsmError::InvalidArgument(_)), "{err}");
+        let err = LSMTree::new(4).flush_to_level(usize::MAX).unwrap_err();
+        assert!(matches!(err, LsmError::InvalidArgument(_)), "{err}");
+        assert_eq!(lsm.memtable.len(), 1);
+        lsm.flush_to_level(0).unwrap();
+        assert_eq!(lsm.get(b"d"), Some(b"4".to_vec()));
     }
 
-    /// get a given key
-    ///
-    /// first checks memtable, then iterates through levels newest-to-oldest, binary searching each
-    /// level
-    ///
-    // https://corrode.dev/blog/defensive-programming/#pattern-use-must-use-on-important-types
-    #[must_use]
-    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        if let Some(value) = self.memtable.get(key) {
-            return value.clone();
+    #[test]
+    fn test_comparator_equality() {
+        fn trim(key: &[u8]) -> &[u8] {
+            key.trim_ascii_end()
         }
 
-        for level in &self.levels {
-            let Some(level) = level else {
-                continue;
-            };
+        /// trailing whitespace doesn't count
+        struct TrimEnd;
+
+        impl Comparator for TrimEnd {
+            fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+                trim(a).cmp(trim(b))
+            }
+        }
 
-            // we have a guarantee that the keys are in sorted order, because the memtable is a
-            // BTreeMap. when we flush the memtable to a level, we iterate through the keys in
-            // order
-            if let Ok(pos) = level.data.binary_search_by(|(k, _)| k.as_slice().cmp(key)) {
-                return level.data[pos].1.clone();
+        impl KeyTransform for TrimEnd {
+            fn normalize(&self, key: &[u8]) -> Vec<u8> {
+                trim(key).to_vec()
             }
         }
 
-        None
+        // the memtable and bloom filters go by bytes, so it's the transform that makes this work -
+        // every key the tree sees is already trimmed
+        let mut lsm = LSMTree::new(2)
+            .with_comparator(TrimEnd)
+            .with_key_transform(TrimEnd);
+        lsm.insert(b"foo".to_vec(), Some(b"1".to_vec()));
+        lsm.insert(b"zzz".to_vec(), Some(b"0".to_vec()));
+        assert_eq!(lsm.get(b"foo "), Some(b"1".to_vec()));
+        assert_eq!(lsm.get(b"foo\t \n"), Some(b"1".to_vec()));
+        assert_eq!(lsm.get(b"fo"), None);
+        lsm.insert(b"foo  ".to_vec(), Some(b"2".to_vec()));
+        assert_eq!(lsm.get(b"foo"), Some(b"2".to_vec()));
+        assert_eq!(lsm.get(b"foo "), Some(b"2".to_vec()));
+        assert_eq!(lsm.iter_live().count(), 2);
     }
 
-    /// flushes memtable data to level 0
-    fn flush_memtable(&mut self) {
-        let mut new_level_data = vec![];
+    #[test]
+    fn test_scan_map() {
+        let mut lsm = LSMTree::new(3);
+        for i in 0..30u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(vec![7; i as usize % 5]));
+        }
+        lsm.insert(4u32.to_be_bytes().to_vec(), Some(vec![7; 100]));
+        lsm.delete(9u32.to_be_bytes().to_vec());
+        lsm.append(12u32.to_be_bytes().to_vec(), vec![8; 3]);
 
-        // std::mem::take takes ownership of the value and replaces with an empty value
-        for (key, value) in std::mem::take(&mut self.memtable) {
-            new_level_data.push((key, value));
+        let lengths = lsm.scan_map(|key, value| (key.to_vec(), value.len()));
+        let expected: Vec<_> = lsm.iter_live().map(|(k, v)| (k, v.len())).collect();
+        assert_eq!(lengths, expected);
+        assert_eq!(lengths.len(), 29);
+        assert!(lengths.contains(&(4u32.to_be_bytes().to_vec(), 100)));
+        assert!(lengths.contains(&(12u32.to_be_bytes().to_vec(), 5)));
+    }
+
+    #[test]
+    fn test_bloom_seed() {
+        let build = |mut lsm: LSMTree| {
+            for i in 0..50u32 {
+                lsm.insert(i.to_be_bytes().to_vec(), Some(vec![1]));
+            }
+            lsm
+        };
+        let blooms = |lsm: &LSMTree| -> Vec<BloomFilter> {
+            lsm.populated_levels()
+                .flat_map(|level| level.partitions.iter().map(|p| p.bloom.clone()))
+                .collect()
+        };
+
+        // the same writes always build the same filters
+        let a = build(LSMTree::new(4));
+        let b = build(LSMTree::new(4));
+        assert_eq!(blooms(&a), blooms(&b));
+
+        let seeded = build(LSMTree::new(4).with_bloom_seed(7));
+        assert_ne!(blooms(&seeded), blooms(&a));
+        assert_eq!(
+            seeded.iter_live().collect::<Vec<_>>(),
+            a.iter_live().collect::<Vec<_>>()
+        );
+        assert_eq!(seeded.get(&9u32.to_be_bytes()), Some(vec![1]));
+    }
+
+    #[test]
+    fn test_versions_between() {
+        let mut lsm = LSMTree::new(2);
+        lsm.insert(b"a".to_vec(), Some(b"0".to_vec()));
+        lsm.insert(b"b".to_vec(), Some(b"0".to_vec()));
+
+        // a snapshot after every write keeps each version around through the flushes
+        let lo = lsm.next_seq();
+        let mut snapshots = vec![];
+        let writes: [(&[u8], Option<&[u8]>); 6] = [
+            (b"a", Some(b"1")),
+            (b"c", Some(b"1")),
+            (b"a", Some(b"2")),
+            (b"b", None),
+            (b"a", Some(b"3")),
+            (b"c", Some(b"2")),
+        ];
+        for (key, value) in writes {
+            lsm.insert(key.to_vec(), value.map(<[u8]>::to_vec));
+            snapshots.push(lsm.acquire_snapshot());
         }
+        let hi = lsm.next_seq();
+        lsm.insert(b"a".to_vec(), Some(b"4".to_vec()));
+        assert!(lsm.populated.len() > 1);
+
+        let expected: Vec<_> = writes
+            .iter()
+            .zip(lo..)
+            .map(|((key, value), seq)| (key.to_vec(), value.map(<[u8]>::to_vec), seq))
+            .collect();
+        assert_eq!(lsm.versions_between(lo, hi), expected);
+        assert_eq!(lsm.versions_between(lo + 2, lo + 4), expected[2..4]);
+        assert!(lsm.versions_between(hi + 1, u64::MAX).is_empty());
 
-        self.merge_into_level(0, new_level_data);
+        // once the snapshots are gone, compaction collapses the overwritten versions, and the
+        // delete of b has nothing left to hide. only c=2 is still the newest of its key
+        drop(snapshots);
+        lsm.compact_range(b"a", b"z");
+        let seqs: Vec<u64> = lsm
+            .versions_between(lo, hi)
+            .iter()
+            .map(|&(_, _, seq)| seq)
+            .collect();
+        assert_eq!(seqs, vec![lo + 5]);
     }
 
-    fn merge_into_level(&mut self, level: usize, new_data: Vec<(Vec<u8>, Option<Vec<u8>>)>) {
-        if level >= self.levels.len() {
-            self.levels.push(Some(LSMLevel { data: new_data }));
-            return;
+    #[test]
+    fn test_read_only() {
+        let mut lsm = LSMTree::new(2);
+        lsm.insert(b"a".to_vec(), Some(b"1".to_vec()));
+        lsm.set_read_only(true);
+        assert!(lsm.is_read_only());
+
+        let seq = lsm.next_seq();
+        assert!(matches!(
+            lsm.try_insert(b"a".to_vec(), Some(b"2".to_vec())),
+            Err(LsmError::ReadOnly)
+        ));
+        assert!(matches!(
+            lsm.write_batch(vec![(b"b".to_vec(), None)], OversizedBatch::Incremental),
+            Err(LsmError::ReadOnly)
+        ));
+        assert!(matches!(
+            lsm.bulk_load(vec![(b"c".to_vec(), b"3".to_vec())], DuplicatePolicy::Error),
+            Err(LsmError::ReadOnly)
+        ));
+        assert_eq!(lsm.next_seq(), seq);
+        assert_eq!(lsm.get(b"a"), Some(b"1".to_vec()));
+        assert_eq!(lsm.iter_live().count(), 1);
+
+        let delete = panic::catch_unwind(AssertUnwindSafe(|| lsm.delete(b"a".to_vec())));
+        assert!(delete.is_err());
+        assert_eq!(lsm.get(b"a"), Some(b"1".to_vec()));
+
+        lsm.set_read_only(false);
+        lsm.insert(b"a".to_vec(), Some(b"2".to_vec()));
+        assert_eq!(lsm.get(b"a"), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn test_write_stall() {
+        let key = |i: u32| i.to_be_bytes().to_vec();
+
+        // nothing ever compacts, so L0 fills up to the limit and writes start failing
+        let mut lsm = LSMTree::new(2)
+            .with_deferred_cascade()
+            .with_write_stall(8, StallMode::Error);
+        let mut written = 0;
+        while lsm.try_insert(key(written), Some(vec![1])).is_ok() {
+            written += 1;
+            assert!(written < 100, "never stalled");
         }
+        assert_eq!(lsm.l0_len(), 8);
+        assert_eq!(lsm.populated, vec![0]);
 
-        let existing_data = self.levels[level]
-            .take()
-            .map(|l| l.data)
-            .unwrap_or_default();
+        // a stalled write leaves nothing behind
+        let seq = lsm.next_seq();
+        assert!(matches!(
+            lsm.try_insert(key(written), Some(vec![1])),
+            Err(LsmError::WouldStall)
+        ));
+        assert_eq!(lsm.next_seq(), seq);
+        assert_eq!(lsm.get(&key(written)), None);
+        assert_eq!(lsm.iter_live().count(), 8);
 
-        let data = merge_sorted(&existing_data, &new_data);
+        // until compaction catches up
+        lsm.compact();
+        assert_eq!(lsm.l0_len(), 0);
+        lsm.try_insert(key(written), Some(vec![1])).unwrap();
 
-        // cascading compaction - check if merged data exceeds level capacity (see `level_capacity` for notes)
-        // if so, merge into the next level. if not, set current level data
-        if data.len() >= self.level_capacity(level) {
-            self.merge_into_level(level + 1, data);
-        } else {
-            self.levels[level] = Some(LSMLevel { data });
+        // blocking runs the cascade on the write instead, and never fails
+        let mut lsm = LSMTree::new(2)
+            .with_deferred_cascade()
+            .with_write_stall(8, StallMode::Block);
+        for i in 0..100 {
+            lsm.try_insert(key(i), Some(vec![1])).unwrap();
+            assert!(lsm.l0_len() < 8 + 2);
+        }
+        assert!(lsm.populated.len() > 1);
+        assert_eq!(lsm.iter_live().count(), 100);
+
+        // a limit under L0's capacity only stalls at the capacity
+        let mut lsm = LSMTree::new(4)
+            .with_deferred_cascade()
+            .with_write_stall(1, StallMode::Error);
+        for i in 0..4 {
+            lsm.try_insert(key(i), Some(vec![1])).unwrap();
         }
+        assert!(matches!(
+            lsm.try_insert(key(4), Some(vec![1])),
+            Err(LsmError::WouldStall)
+        ));
     }
-}
 
-/// merge 2 sorted vecs
-///
-/// when merging:
-/// 1. push smaller key into result
-/// 2. if equal, use `new_data`
-/// 3. when list runs out, go to the end of the other list
-fn merge_sorted(
-    old_data: &[(Vec<u8>, Option<Vec<u8>>)],
-    new_data: &[(Vec<u8>, Option<Vec<u8>>)],
-) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
-    let mut merged = vec![];
-    let mut i = 0;
-    let mut j = 0;
+    #[test]
+    fn test_manual_flush_snapshot_overwrite() {
+        let mut lsm = LSMTree::new(2).with_manual_flush();
+        lsm.insert(b"a".to_vec(), Some(b"1".to_vec()));
+        lsm.insert(b"b".to_vec(), Some(b"2".to_vec()));
+        let snapshot = lsm.acquire_snapshot();
 
-    // while we still have data remaining in both lists
-    while i < old_data.len() && j < new_data.len() {
-        match old_data[i].0.cmp(&new_data[j].0) {
-            std::cmp::Ordering::Less => {
-                merged.push(old_data[i].clone());
-                i += 1;
-            }
-            std::cmp::Ordering::Greater => {
-                merged.push(new_data[j].clone());
-                j += 1;
-            }
-            std::cmp::Ordering::Equal => {
-                merged.push(new_data[j].clone());
-                i += 1;
-                j += 1;
-            }
-        }
+        // the old versions move aside rather than down into a level
+        lsm.insert(b"a".to_vec(), Some(b"10".to_vec()));
+        lsm.delete(b"b".to_vec());
+        lsm.insert(b"a".to_vec(), Some(b"100".to_vec()));
+        assert!(lsm.populated.is_empty());
+        assert_eq!(lsm.frozen.len(), 1);
+        assert_eq!(lsm.get(b"a"), Some(b"100".to_vec()));
+        assert_eq!(lsm.get(b"b"), None);
+        assert_eq!(lsm.get_at(b"a", &snapshot), Some(b"1".to_vec()));
+        assert_eq!(lsm.get_at(b"b", &snapshot), Some(b"2".to_vec()));
+
+        // and go down with the next flush, underneath the newer writes
+        lsm.flush();
+        assert!(lsm.frozen.is_empty());
+        assert_eq!(lsm.get(b"a"), Some(b"100".to_vec()));
+        assert_eq!(lsm.get_at(b"a", &snapshot), Some(b"1".to_vec()));
+        assert_eq!(lsm.get_at(b"b", &snapshot), Some(b"2".to_vec()));
+
+        // a wholesale batch doesn't flush part way through either
+        let mut lsm = LSMTree::new(4);
+        lsm.insert(b"a".to_vec(), Some(b"1".to_vec()));
+        let snapshot = lsm.acquire_snapshot();
+        lsm.write_batch(
+            vec![(b"a".to_vec(), Some(b"2".to_vec())), (b"b".to_vec(), None)],
+            OversizedBatch::Wholesale,
+        )
+        .unwrap();
+        assert!(lsm.populated.is_empty());
+        assert_eq!(lsm.get(b"a"), Some(b"2".to_vec()));
+        assert_eq!(lsm.get_at(b"a", &snapshot), Some(b"1".to_vec()));
     }
 
-    // while we still have data remaining in existing
-    while i < old_data.len() {
-        merged.push(old_data[i].clone());
-        i += 1;
+    #[test]
+    fn test_frozen_memtables_in_debug_tools() {
+        let mut lsm = LSMTree::new(100);
+        lsm.insert(b"a".to_vec(), Some(b"1".to_vec()));
+        lsm.flush();
+        lsm.insert(b"a".to_vec(), Some(b"2".to_vec()));
+        lsm.insert(b"b".to_vec(), None);
+        let frozen = lsm.freeze_memtable().unwrap();
+        lsm.insert(b"c".to_vec(), Some(b"3".to_vec()));
+
+        assert_eq!(
+            lsm.debug_versions(b"a"),
+            vec![
+                (Some(b"2".to_vec()), None, 1),
+                (Some(b"1".to_vec()), Some(0), 0)
+            ]
+        );
+        assert_eq!(lsm.read_cost(b"a"), 0);
+        assert_eq!(lsm.read_cost(b"c"), 0);
+
+        // the frozen tombstone for b is dropped with everything under it, and a's frozen
+        // version doesn't resurface from above the compacted level
+        lsm.compact_range(b"a", b"c");
+        assert!(lsm.frozen.is_empty());
+        assert_eq!(
+            lsm.debug_versions(b"a"),
+            vec![(Some(b"2".to_vec()), Some(0), 1)]
+        );
+        assert_eq!(lsm.debug_versions(b"b"), vec![]);
+        assert_eq!(lsm.get(b"a"), Some(b"2".to_vec()));
+        assert_eq!(lsm.get(b"c"), Some(b"3".to_vec()));
+
+        // it's already been flushed, so handing it back does nothing
+        lsm.flush_frozen(frozen);
+        assert_eq!(lsm.iter_live().count(), 2);
     }
 
-    // while we still have data remaining in new
-    while j < new_data.len() {
-        merged.push(new_data[j].clone());
-        j += 1;
+    #[test]
+    fn test_level_stats_count_blobs() {
+        let write = |lsm: &mut LSMTree| {
+            for i in 0..20u32 {
+                lsm.insert(i.to_be_bytes().to_vec(), Some(vec![1; 100]));
+            }
+            lsm.delete(0u32.to_be_bytes().to_vec());
+            lsm.flush();
+        };
+        let mut plain = LSMTree::new(4);
+        write(&mut plain);
+        let mut offloaded = LSMTree::new(4).with_blob_threshold(64);
+        write(&mut offloaded);
+        assert_eq!(offloaded.blobs.written, 20 * 100);
+
+        assert_eq!(offloaded.level_stats(), plain.level_stats());
+        assert_eq!(offloaded.stats(), plain.stats());
+        let bytes: usize = offloaded.level_stats().iter().map(|s| s.bytes).sum();
+        assert_eq!(bytes, 21 * 4 + 20 * 100);
     }
 
-    merged
-}
+    #[test]
+    fn test_try_get() {
+        let mut lsm = LSMTree::new(2);
+        lsm.insert(b"a".to_vec(), Some(b"1".to_vec()));
+        lsm.insert(b"b".to_vec(), Some(b"2".to_vec()));
+        lsm.delete(b"c".to_vec());
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        assert_eq!(lsm.try_get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(lsm.try_get(b"c").unwrap(), None);
+        assert_eq!(lsm.try_get(b"z").unwrap(), None);
+    }
 
+    #[cfg(feature = "checksum")]
     #[test]
-    fn test_basic_insert_and_get() {
-        let mut lsm = LSMTree::new(3);
-        lsm.insert(b"key1".to_vec(), Some(b"value1".to_vec()));
-        lsm.insert(b"key2".to_vec(), Some(b"value2".to_vec()));
+    fn test_checksum_corruption() {
+        let mut lsm = LSMTree::new(2);
+        lsm.insert(b"a".to_vec(), Some(b"hello".to_vec()));
+        lsm.insert(b"b".to_vec(), Some(b"world".to_vec()));
+        assert!(lsm.memtable.is_empty());
 
-        assert_eq!(lsm.get(b"key1"), Some(b"value1".to_vec()));
-        assert_eq!(lsm.get(b"key2"), Some(b"value2".to_vec()));
-        assert_eq!(lsm.get(b"key3"), None);
+        // flip a bit in b's value, in place
+        let level = Arc::get_mut(lsm.levels[0].as_mut().unwrap()).unwrap();
+        let value = level.partitions[0].data[1].1.value.as_mut().unwrap();
+        value[0] ^= 0x01;
+
+        let err = lsm.try_get(b"b").unwrap_err();
+        assert!(
+            matches!(&err, LsmError::Corruption(msg) if msg.contains("\"b\"")),
+            "{err}"
+        );
+        assert_eq!(lsm.try_get(b"a").unwrap(), Some(b"hello".to_vec()));
+
+        // get doesn't hand the corrupted bytes out either
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Debug);
+        assert_eq!(lsm.get(b"b"), None);
+        assert_eq!(lsm.get(b"a"), Some(b"hello".to_vec()));
+        assert!(
+            captured_logs()
+                .iter()
+                .any(|msg| msg.contains("checksum mismatch for key \"b\"")),
+            "{:?}",
+            captured_logs()
+        );
+
+        // rewriting the key fixes it
+        lsm.insert(b"b".to_vec(), Some(b"world".to_vec()));
+        assert_eq!(lsm.try_get(b"b").unwrap(), Some(b"world".to_vec()));
+        assert_eq!(lsm.get(b"b"), Some(b"world".to_vec()));
     }
 
     #[test]
-    fn test_memtable_flush() {
+    fn test_read_cost() {
         let mut lsm = LSMTree::new(2);
+        for (k, v) in [("a", "1"), ("b", "2"), ("c", "3"), ("d", "4"), ("b", "20")] {
+            lsm.insert(k.as_bytes().to_vec(), Some(v.as_bytes().to_vec()));
+        }
+        lsm.delete(b"c".to_vec());
+        lsm.insert(b"d".to_vec(), Some(b"40".to_vec()));
+        lsm.insert(b"e".to_vec(), Some(b"5".to_vec()));
+        lsm.insert(b"f".to_vec(), Some(b"6".to_vec()));
 
-        lsm.insert(b"k1".to_vec(), Some(b"v1".to_vec()));
-        lsm.insert(b"k2".to_vec(), Some(b"v2".to_vec()));
+        // f is in the memtable, d and e in L1, a only in L2
+        assert_eq!(lsm.populated, vec![1, 2]);
+        assert_eq!(lsm.read_cost(b"f"), 0);
+        assert_eq!(lsm.read_cost(b"e"), 1);
+        assert_eq!(lsm.read_cost(b"d"), 1);
 
-        assert_eq!(lsm.memtable.len(), 0);
-        assert_eq!(lsm.levels.len(), 1);
-        assert!(lsm.levels[0].is_some());
-        assert_eq!(lsm.levels[0].as_ref().unwrap().data.len(), 2);
+        // L1's bloom filter rules a out (the default seed makes that the same every run), so
+        // only L2 gets searched
+        assert_eq!(lsm.read_cost(b"a"), 1);
+        assert_eq!(lsm.get(b"a"), Some(b"1".to_vec()));
 
-        assert_eq!(lsm.get(b"k1"), Some(b"v1".to_vec()));
-        assert_eq!(lsm.get(b"k2"), Some(b"v2".to_vec()));
+        // a deleted key still resolves at the level holding its tombstone
+        assert_eq!(lsm.read_cost(b"c"), 1);
     }
 
     #[test]
-    fn test_level_merge() {
-        // Threshold 2.
-        // Level 0 capacity: 2 * 2^0 = 2.
-        // Level 1 capacity: 2 * 2^1 = 4.
+    fn test_append() {
         let mut lsm = LSMTree::new(2);
+        lsm.insert(b"log".to_vec(), Some(b"a".to_vec()));
+        lsm.append(b"log".to_vec(), b"b".to_vec());
+        assert_eq!(lsm.get(b"log"), Some(b"ab".to_vec()));
 
-        // 1. Insert 2 items -> Flush to L0. L0 size 2.
-        lsm.insert(b"a".to_vec(), Some(b"1".to_vec()));
-        lsm.insert(b"b".to_vec(), Some(b"2".to_vec()));
+        // flush, then append on top of what's now in a level
+        lsm.insert(b"x".to_vec(), Some(b"1".to_vec()));
+        assert!(lsm.memtable.is_empty());
+        lsm.append(b"log".to_vec(), b"c".to_vec());
+        assert!(lsm.memtable[b"log".as_slice()].append);
+        assert_eq!(lsm.get(b"log"), Some(b"abc".to_vec()));
+        lsm.append(b"log".to_vec(), b"d".to_vec());
+        assert_eq!(lsm.get(b"log"), Some(b"abcd".to_vec()));
 
-        assert_eq!(lsm.levels.len(), 1);
-        assert_eq!(lsm.levels[0].as_ref().unwrap().data.len(), 2);
+        // compaction folds the operand into the value underneath
+        lsm.append(b"new".to_vec(), b"1".to_vec());
+        assert!(lsm.memtable.is_empty());
+        assert_eq!(lsm.get(b"log"), Some(b"abcd".to_vec()));
+        assert_eq!(lsm.debug_versions(b"log").len(), 1);
+        assert_eq!(lsm.get(b"new"), Some(b"1".to_vec()));
 
-        // 2. Insert 2 items -> Flush to L0.
-        // Merge (L0 existing) + (New) = 4 items.
-        // 4 > L0 capacity (2). So push to L1.
-        lsm.insert(b"c".to_vec(), Some(b"3".to_vec()));
+        // appending to a deleted key starts over
+        lsm.delete(b"x".to_vec());
+        lsm.append(b"x".to_vec(), b"2".to_vec());
+        assert_eq!(lsm.get(b"x"), Some(b"2".to_vec()));
+
+        let snapshot = lsm.acquire_snapshot();
+        lsm.append(b"log".to_vec(), b"e".to_vec());
+        lsm.append(b"log".to_vec(), b"f".to_vec());
+        assert_eq!(lsm.get(b"log"), Some(b"abcdef".to_vec()));
+        assert_eq!(lsm.get_at(b"log", &snapshot), Some(b"abcd".to_vec()));
+        assert_eq!(
+            lsm.iter_live().collect::<Vec<_>>(),
+            vec![
+                (b"log".to_vec(), b"abcdef".to_vec()),
+                (b"new".to_vec(), b"1".to_vec()),
+                (b"x".to_vec(), b"2".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compact_range() {
+        let mut lsm = LSMTree::new(4);
+        for i in 0..20 {
+            lsm.insert(format!("k{i:02}").into_bytes(), Some(vec![1]));
+        }
+        for i in 5..10 {
+            lsm.delete(format!("k{i:02}").into_bytes());
+        }
+        lsm.delete(b"k15".to_vec());
+        lsm.insert(b"k06".to_vec(), Some(vec![2]));
+        lsm.flush();
+
+        let outside: Vec<Vec<u8>> = (0..5)
+            .chain(10..20)
+            .map(|i| format!("k{i:02}").into_bytes())
+            .collect();
+        let before: Vec<_> = outside.iter().map(|k| lsm.debug_versions(k)).collect();
+        let live: Vec<_> = lsm.iter_live().collect();
+        let tombstones =
+            |lsm: &LSMTree| -> usize { lsm.level_stats().iter().map(|s| s.tombstones).sum() };
+        assert_eq!(tombstones(&lsm), 6);
+
+        lsm.compact_range(b"k05", b"k10");
+
+        // only k06's newest version is left in the range, everything else was a tombstone or
+        // shadowed by one
+        for i in [5, 7, 8, 9] {
+            assert_eq!(lsm.debug_versions(format!("k{i:02}").as_bytes()), vec![]);
+        }
+        assert_eq!(lsm.debug_versions(b"k06").len(), 1);
+        assert_eq!(lsm.get(b"k06"), Some(vec![2]));
+
+        // outside the range, nothing moved - k15's tombstone included
+        let after: Vec<_> = outside.iter().map(|k| lsm.debug_versions(k)).collect();
+        assert_eq!(after, before);
+        assert_eq!(lsm.get(b"k15"), None);
+        assert_eq!(tombstones(&lsm), 1);
+        assert_eq!(lsm.iter_live().collect::<Vec<_>>(), live);
+        lsm.verify().unwrap();
+    }
+
+    #[test]
+    fn test_write_batch_oversized() {
+        // blows up on anything involving k10, once it's told to
+        struct Flaky(Arc<atomic::AtomicBool>);
+        impl Comparator for Flaky {
+            fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+                assert!(
+                    !(self.0.load(atomic::Ordering::Relaxed) && [a, b].contains(&&b"k10"[..])),
+                    "comparator blew up"
+                );
+                a.cmp(b)
+            }
+        }
+
+        // 10x the threshold, newest key first so the writes themselves never compare keys
+        let key = |i: u32| format!("k{i:02}").into_bytes();
+        let batch: Vec<Entry> = (0..40u32).rev().map(|i| (key(i), Some(vec![1]))).collect();
+
+        // incremental: the memtable never goes past the threshold, and when a flush fails the
+        // batch is cut off there
+        let armed = Arc::new(atomic::AtomicBool::new(true));
+        let mut lsm = LSMTree::new(4).with_comparator(Flaky(Arc::clone(&armed)));
+        let result = lsm.write_batch(batch.clone(), OversizedBatch::Incremental);
+        armed.store(false, atomic::Ordering::Relaxed);
+        assert!(matches!(result, Err(LsmError::Poisoned)));
+        assert_eq!(lsm.memtable.len(), 4);
+        assert_eq!(lsm.get(&key(39)), Some(vec![1]));
+        assert_eq!(lsm.get(&key(10)), Some(vec![1]));
+        assert_eq!(lsm.get(&key(7)), None);
+        assert_eq!(lsm.iter_live().count(), 32);
+
+        // wholesale: the whole batch sits in the memtable, and is all still there after the
+        // flush fails
+        armed.store(true, atomic::Ordering::Relaxed);
+        let mut lsm = LSMTree::new(4).with_comparator(Flaky(Arc::clone(&armed)));
+        let result = lsm.write_batch(batch.clone(), OversizedBatch::Wholesale);
+        armed.store(false, atomic::Ordering::Relaxed);
+        assert!(matches!(result, Err(LsmError::Poisoned)));
+        assert_eq!(lsm.memtable.len(), 40);
+        assert!(lsm.populated.is_empty());
+        assert_eq!(lsm.iter_live().count(), 40);
+
+        // when nothing fails both end up with the same contents, deletes included
+        let mut deletes = batch;
+        deletes.push((key(5), None));
+        let mut incremental = LSMTree::new(4);
+        incremental
+            .write_batch(deletes.clone(), OversizedBatch::Incremental)
+            .unwrap();
+        let mut wholesale = LSMTree::new(4);
+        wholesale
+            .write_batch(deletes, OversizedBatch::Wholesale)
+            .unwrap();
+        assert!(incremental.memtable.len() < 4);
+        assert!(wholesale.memtable.is_empty());
+        assert_eq!(wholesale.populated.len(), 1);
+        assert_eq!(
+            incremental.iter_live().collect::<Vec<_>>(),
+            wholesale.iter_live().collect::<Vec<_>>()
+        );
+        assert_eq!(wholesale.iter_live().count(), 39);
+    }
+
+    #[test]
+    fn test_poisoned_by_panicking_compaction() {
+        // blows up comparing a against d, once it's told to
+        struct Flaky(Arc<atomic::AtomicBool>);
+        impl Comparator for Flaky {
+            fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+                let pair = [a, b];
+                assert!(
+                    !(self.0.load(atomic::Ordering::Relaxed)
+                        && pair.contains(&&b"a"[..])
+                        && pair.contains(&&b"d"[..])),
+                    "comparator blew up"
+                );
+                a.cmp(b)
+            }
+        }
+
+        let armed = Arc::new(atomic::AtomicBool::new(false));
+        let mut lsm = LSMTree::new(2).with_comparator(Flaky(Arc::clone(&armed)));
+        lsm.insert(b"a".to_vec(), Some(b"1".to_vec()));
+        lsm.insert(b"b".to_vec(), Some(b"2".to_vec()));
         lsm.insert(b"d".to_vec(), Some(b"4".to_vec()));
+        let levels: Vec<_> = (0..lsm.levels.len())
+            .map(|i| lsm.level_entries(i))
+            .collect();
 
-        assert_eq!(lsm.levels.len(), 2); // Should have created L1
-        assert!(lsm.levels[0].is_none()); // L0 data moved up
-        assert!(lsm.levels[1].is_some()); // L1 has the data
-        assert_eq!(lsm.levels[1].as_ref().unwrap().data.len(), 4);
+        // the flush takes L0 apart to merge into it, then panics part way through
+        armed.store(true, atomic::Ordering::Relaxed);
+        assert!(matches!(
+            lsm.try_insert(b"e".to_vec(), Some(b"5".to_vec())),
+            Err(LsmError::Poisoned)
+        ));
+        armed.store(false, atomic::Ordering::Relaxed);
 
+        assert!(lsm.is_poisoned());
+        let after: Vec<_> = (0..lsm.levels.len())
+            .map(|i| lsm.level_entries(i))
+            .collect();
+        assert_eq!(after, levels);
+        assert_eq!(lsm.populated, vec![0]);
+        assert_eq!(lsm.memtable.len(), 2);
+
+        // still readable, but no more writes
         assert_eq!(lsm.get(b"a"), Some(b"1".to_vec()));
-        assert_eq!(lsm.get(b"d"), Some(b"4".to_vec()));
+        assert_eq!(lsm.get(b"e"), Some(b"5".to_vec()));
+        assert!(matches!(
+            lsm.try_insert(b"f".to_vec(), None),
+            Err(LsmError::Poisoned)
+        ));
+        assert!(matches!(
+            lsm.bulk_load(vec![(b"g".to_vec(), vec![])], DuplicatePolicy::Error),
+            Err(LsmError::Poisoned)
+        ));
+        let insert = panic::catch_unwind(AssertUnwindSafe(|| lsm.insert(b"f".to_vec(), None)));
+        assert!(insert.is_err());
+        assert_eq!(lsm.memtable.len(), 2);
     }
 
     #[test]
-    fn test_overwrite() {
-        let mut lsm = LSMTree::new(2);
-        lsm.insert(b"key1".to_vec(), Some(b"val1".to_vec()));
-        lsm.insert(b"key1".to_vec(), Some(b"val2".to_vec())); // Overwrite in memtable
+    fn test_poisoned_flush_puts_memtables_back() {
+        // blows up once it's made this many more comparisons
+        struct Flaky(Arc<AtomicUsize>);
+        impl Comparator for Flaky {
+            fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+                let left = self.0.fetch_sub(1, atomic::Ordering::Relaxed);
+                assert!(left > 0, "comparator blew up");
+                a.cmp(b)
+            }
+        }
 
-        assert_eq!(lsm.get(b"key1"), Some(b"val2".to_vec()));
+        let budget = Arc::new(AtomicUsize::new(usize::MAX));
+        let tree = || {
+            let mut lsm = LSMTree::new(100)
+                .with_comparator(Flaky(Arc::clone(&budget)))
+                .with_blob_threshold(4);
+            lsm.insert(b"b".to_vec(), Some(b"frozen value".to_vec()));
+            lsm.insert(b"a".to_vec(), Some(b"1".to_vec()));
+            lsm.freeze_memtable().unwrap();
+            lsm.insert(b"d".to_vec(), Some(b"memtable value".to_vec()));
+            lsm.insert(b"c".to_vec(), Some(b"3".to_vec()));
+            lsm
+        };
 
-        // Flush
-        lsm.insert(b"key2".to_vec(), Some(b"val3".to_vec())); // Trigger flush (size 2)
+        // neither memtable is in order, so the flush panics sorting the first one
+        let mut lsm = tree();
+        budget.store(0, atomic::Ordering::Relaxed);
+        assert!(matches!(lsm.flush_to_level(0), Err(LsmError::Poisoned)));
+        budget.store(usize::MAX, atomic::Ordering::Relaxed);
 
-        // Now key1 is in L0 with val2.
-        assert_eq!(lsm.get(b"key1"), Some(b"val2".to_vec()));
+        // both come back whole, with their values rather than blob refs, and the blob file the
+        // flush had started is gone again
+        assert!(lsm.populated.is_empty());
+        assert_eq!(lsm.frozen.len(), 1);
+        assert_eq!(
+            lsm.frozen[0][b"b".as_slice()].value.as_deref(),
+            Some(b"frozen value".as_slice())
+        );
+        assert_eq!(
+            lsm.memtable[b"d".as_slice()].value.as_deref(),
+            Some(b"memtable value".as_slice())
+        );
+        assert_eq!(lsm.blobs.written, 0);
+        assert_eq!(
+            lsm.iter_live().collect::<Vec<_>>(),
+            tree().iter_live().collect::<Vec<_>>()
+        );
 
-        // Overwrite again in memtable
-        lsm.insert(b"key1".to_vec(), Some(b"val3".to_vec()));
-        assert_eq!(lsm.get(b"key1"), Some(b"val3".to_vec()));
+        // same for the memtable entries `compact_range` takes out. flushing the frozen memtable
+        // first takes one comparison and finding the two in range four more, then it panics
+        // sorting them
+        let mut lsm = tree();
+        budget.store(5, atomic::Ordering::Relaxed);
+        lsm.compact_range(b"c", b"z");
+        budget.store(usize::MAX, atomic::Ordering::Relaxed);
+        assert!(lsm.is_poisoned());
+        assert_eq!(lsm.memtable.len(), 2);
+        assert_eq!(lsm.blobs.written, b"frozen value".len());
+        assert_eq!(lsm.get(b"d"), Some(b"memtable value".to_vec()));
     }
 
     #[test]
-    fn test_delete() {
-        let mut lsm = LSMTree::new(2);
-        lsm.insert(b"key1".to_vec(), Some(b"val1".to_vec()));
+    fn test_blob_threshold() {
+        let big = vec![7u8; 1000];
+        let mut lsm = LSMTree::new(2).with_blob_threshold(64);
+        lsm.insert(b"big".to_vec(), Some(big.clone()));
+        lsm.insert(b"small".to_vec(), Some(vec![1; 64]));
+        assert_eq!(lsm.blobs.written, 1000);
 
-        // flush
-        lsm.insert(b"key2".to_vec(), Some(b"val2".to_vec()));
-        lsm.delete(b"key1".to_vec());
+        // push big down through the levels
+        let mut levels = BTreeSet::new();
+        for i in 0..64u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(vec![1]));
+            let versions = lsm.debug_versions(b"big");
+            assert_eq!(versions.len(), 1);
+            levels.insert(versions[0].1.unwrap());
+        }
+        assert!(levels.len() > 2, "{levels:?}");
 
-        assert_eq!(lsm.get(b"key1"), None);
+        // written once, and only a pointer got copied around since
+        assert_eq!(lsm.blobs.written, 1000);
+        let (_, record) = lsm
+            .populated_levels()
+            .flat_map(LSMLevel::entries)
+            .find(|(k, _)| k == b"big")
+            .unwrap();
+        assert!(record.blob.is_some());
+        assert_eq!(record.value, Some(vec![]));
+
+        assert_eq!(lsm.get(b"big"), Some(big.clone()));
+        assert_eq!(lsm.get(b"small"), Some(vec![1; 64]));
+        assert!(lsm.iter_live().any(|(k, v)| k == b"big" && v == big));
+
+        // appends land on top of the blob without reading it back
+        lsm.append(b"big".to_vec(), b"!".to_vec());
+        lsm.flush();
+        let mut expected = big.clone();
+        expected.push(b'!');
+        assert_eq!(lsm.get(b"big"), Some(expected));
+        assert_eq!(lsm.blobs.written, 1000);
+    }
+
+    #[test]
+    fn test_get_with_neighbors() {
+        let mut lsm = LSMTree::new(4);
+        for k in ["a", "c", "e", "g"] {
+            lsm.insert(k.as_bytes().to_vec(), Some(k.to_uppercase().into_bytes()));
+        }
+        assert!(lsm.memtable.is_empty());
+        lsm.insert(b"b".to_vec(), Some(b"B".to_vec()));
+        lsm.insert(b"f".to_vec(), Some(b"F".to_vec()));
+        lsm.delete(b"e".to_vec());
+
+        let some = |s: &str| Some(s.as_bytes().to_vec());
+
+        // b and f are in the memtable, the rest in L0
+        assert_eq!(
+            lsm.get_with_neighbors(b"c"),
+            (some("C"), some("b"), some("f"))
+        );
+        assert_eq!(
+            lsm.get_with_neighbors(b"b"),
+            (some("B"), some("a"), some("c"))
+        );
+
+        // e is deleted, so it's skipped over, and doesn't have a value itself
+        assert_eq!(lsm.get_with_neighbors(b"e"), (None, some("c"), some("f")));
+        assert_eq!(lsm.get_with_neighbors(b"d"), (None, some("c"), some("f")));
+
+        // the ends
+        assert_eq!(lsm.get_with_neighbors(b"a"), (some("A"), None, some("b")));
+        assert_eq!(lsm.get_with_neighbors(b"g"), (some("G"), some("f"), None));
+        assert_eq!(lsm.get_with_neighbors(b"0"), (None, None, some("a")));
+        assert_eq!(lsm.get_with_neighbors(b"z"), (None, some("g"), None));
+        assert_eq!(LSMTree::new(4).get_with_neighbors(b"a"), (None, None, None));
+
+        // across partitions and levels, with overwrites, tombstones and a comparator that isn't
+        // byte order
+        let mut numeric = LSMTree::new(3).with_partitions(2).with_comparator(Numeric);
+        for n in (0..60u32).step_by(3) {
+            numeric.insert(n.to_string().into_bytes(), Some(vec![1]));
+        }
+        for n in [9u32, 10, 12, 15] {
+            numeric.delete(n.to_string().into_bytes());
+        }
+        numeric.insert(b"12".to_vec(), Some(vec![2]));
+        numeric.insert(b"100".to_vec(), Some(vec![3]));
+        assert!(numeric.populated.len() > 1);
+
+        assert_eq!(
+            numeric.get_with_neighbors(b"12"),
+            (Some(vec![2]), some("6"), some("18"))
+        );
+        assert_eq!(
+            numeric.get_with_neighbors(b"11"),
+            (None, some("6"), some("12"))
+        );
+        assert_eq!(
+            numeric.get_with_neighbors(b"15"),
+            (None, some("12"), some("18"))
+        );
+        assert_eq!(
+            numeric.get_with_neighbors(b"57"),
+            (Some(vec![1]), some("54"), some("100"))
+        );
+        assert_eq!(
+            numeric.get_with_neighbors(b"0"),
+            (Some(vec![1]), None, some("3"))
+        );
     }
 }
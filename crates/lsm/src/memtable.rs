@@ -0,0 +1,167 @@
+//! the memtable - the newest writes, in a sorted map
+
+use std::collections::BTreeMap;
+use std::ops::{Bound, Deref, DerefMut};
+use std::sync::OnceLock;
+
+use crate::iter::EntryRef;
+use crate::{Comparator, Record};
+
+/// a `BTreeMap` in byte order, plus its order under the tree's comparator once a scan has asked
+/// for it
+///
+/// a custom comparator doesn't agree with the map's order, so scans need the entries sorted.
+/// that's done by the first scan and kept until the memtable changes - any mutable access throws
+/// it away. a frozen memtable never changes, so it's sorted at most once
+#[derive(Clone, Default)]
+pub(crate) struct Memtable {
+    map: BTreeMap<Vec<u8>, Record>,
+
+    // positions in `map`'s iteration order, arranged in the comparator's order. the tree's
+    // comparator is fixed, so there's only ever one to cache
+    order: OnceLock<Vec<usize>>,
+}
+
+/// a memtable's entries in key order, for merging with the levels
+pub(crate) type Entries<'a> = Box<dyn Iterator<Item = EntryRef<'a>> + Send + 'a>;
+
+impl Memtable {
+    /// the entries in key order, starting from the first one at or after `start`. `cmp` is the
+    /// tree's custom comparator, `None` for byte order - the map's own, so that's a seek
+    pub(crate) fn entries_from<'a>(
+        &'a self,
+        start: &[u8],
+        cmp: Option<&dyn Comparator>,
+    ) -> Entries<'a> {
+        let Some(cmp) = cmp else {
+            let from = self
+                .map
+                .range::<[u8], _>((Bound::Included(start), Bound::Unbounded));
+            return Box::new(from.map(|(k, r)| (k.as_slice(), r)));
+        };
+        let sorted = self.sorted(cmp);
+        let skip = sorted.partition_point(|(k, _)| cmp.compare(k, start).is_lt());
+        Box::new(sorted.into_iter().skip(skip))
+    }
+
+    /// the entries before `end`, in reverse key order - `entries_from` backwards
+    pub(crate) fn entries_before<'a>(
+        &'a self,
+        end: &[u8],
+        cmp: Option<&dyn Comparator>,
+    ) -> Entries<'a> {
+        let Some(cmp) = cmp else {
+            let before = self
+                .map
+                .range::<[u8], _>((Bound::Unbounded, Bound::Excluded(end)));
+            return Box::new(before.rev().map(|(k, r)| (k.as_slice(), r)));
+        };
+        let mut sorted = self.sorted(cmp);
+        sorted.truncate(sorted.partition_point(|(k, _)| cmp.compare(k, end).is_lt()));
+        Box::new(sorted.into_iter().rev())
+    }
+
+    /// every entry in key order - see `entries_from`
+    pub(crate) fn entries<'a>(&'a self, cmp: Option<&dyn Comparator>) -> Entries<'a> {
+        match cmp {
+            None => Box::new(self.map.iter().map(|(k, r)| (k.as_slice(), r))),
+            Some(cmp) => Box::new(self.sorted(cmp).into_iter()),
+        }
+    }
+
+    /// the entries in `cmp`'s order
+    fn sorted(&self, cmp: &dyn Comparator) -> Vec<EntryRef<'_>> {
+        let entries: Vec<EntryRef<'_>> = self.map.iter().map(|(k, r)| (k.as_slice(), r)).collect();
+        let order = self.order.get_or_init(|| {
+            let mut order: Vec<usize> = (0..entries.len()).collect();
+            order.sort_by(|&a, &b| cmp.compare(entries[a].0, entries[b].0));
+            order
+        });
+        order.iter().map(|&i| entries[i]).collect()
+    }
+}
+
+impl Deref for Memtable {
+    type Target = BTreeMap<Vec<u8>, Record>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.map
+    }
+}
+
+impl DerefMut for Memtable {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.order.take();
+        &mut self.map
+    }
+}
+
+impl FromIterator<(Vec<u8>, Record)> for Memtable {
+    fn from_iter<I: IntoIterator<Item = (Vec<u8>, Record)>>(entries: I) -> Self {
+        Memtable {
+            map: entries.into_iter().collect(),
+            order: OnceLock::new(),
+        }
+    }
+}
+
+impl IntoIterator for Memtable {
+    type Item = (Vec<u8>, Record);
+    type IntoIter = std::collections::btree_map::IntoIter<Vec<u8>, Record>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.map.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+    use std::sync::atomic::{self, AtomicUsize};
+
+    use super::Memtable;
+    use crate::{Comparator, Record};
+
+    /// reverse byte order, counting how often it's asked
+    struct Counting(AtomicUsize);
+
+    impl Comparator for Counting {
+        fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+            self.0.fetch_add(1, atomic::Ordering::Relaxed);
+            b.cmp(a)
+        }
+    }
+
+    #[test]
+    fn test_sorted_order_cached_until_write() {
+        let cmp = Counting(AtomicUsize::new(0));
+        let comparisons = || cmp.0.load(atomic::Ordering::Relaxed);
+        let keys = |memtable: &Memtable| -> Vec<u8> {
+            memtable.entries(Some(&cmp)).map(|(k, _)| k[0]).collect()
+        };
+
+        let mut memtable: Memtable = [3u8, 1, 4, 5, 9, 2, 6]
+            .into_iter()
+            .map(|k| (vec![k], Record::new(Some(vec![]), 0)))
+            .collect();
+        assert_eq!(keys(&memtable), [9, 6, 5, 4, 3, 2, 1]);
+        assert!(comparisons() > 0);
+
+        // the second scan reuses the order, and a seek only binary searches it
+        let sorted = comparisons();
+        assert_eq!(keys(&memtable), [9, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(comparisons(), sorted);
+        let from: Vec<u8> = memtable
+            .entries_from(&[4], Some(&cmp))
+            .map(|(k, _)| k[0])
+            .collect();
+        assert_eq!(from, [4, 3, 2, 1]);
+        let searched = comparisons();
+        assert!(searched - sorted < memtable.len(), "{}", searched - sorted);
+
+        // a write throws it away
+        memtable.insert(vec![7], Record::new(Some(vec![]), 1));
+        assert_eq!(keys(&memtable), [9, 7, 6, 5, 4, 3, 2, 1]);
+        assert!(comparisons() > searched);
+    }
+}
@@ -0,0 +1,99 @@
+//! snapshots - a consistent view of the tree as of a single point in time
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// sequence numbers of every live snapshot, with how many handles hold each one
+pub(crate) type Snapshots = Arc<Mutex<BTreeMap<u64, usize>>>;
+
+/// a handle on the tree as it was when the snapshot was taken (see `LSMTree::acquire_snapshot`)
+///
+/// while it's alive, compaction keeps every version the snapshot can see, even ones that have
+/// since been overwritten or deleted. dropping it lets the next compaction throw them away
+pub struct Snapshot {
+    // writes with a lower seq are visible, everything from here on isn't
+    seq: u64,
+    snapshots: Snapshots,
+}
+
+impl Snapshot {
+    pub(crate) fn new(seq: u64, snapshots: &Snapshots) -> Self {
+        *lock(snapshots).entry(seq).or_default() += 1;
+        Snapshot {
+            seq,
+            snapshots: Arc::clone(snapshots),
+        }
+    }
+
+    /// the first sequence number the snapshot can't see
+    #[must_use]
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut snapshots = lock(&self.snapshots);
+        if let Some(count) = snapshots.get_mut(&self.seq) {
+            *count -= 1;
+            if *count == 0 {
+                snapshots.remove(&self.seq);
+            }
+        }
+    }
+}
+
+/// seqs of the live snapshots, in ascending order
+pub(crate) fn live(snapshots: &Snapshots) -> Vec<u64> {
+    lock(snapshots).keys().copied().collect()
+}
+
+/// whether a version written at `seq` is still visible to a snapshot, given the next newer
+/// version of the same key was written at `newer_seq`
+///
+/// a snapshot sees the newest version below its seq, so that's any snapshot in
+/// (`seq`, `newer_seq`]. `live` has to be sorted
+pub(crate) fn is_visible(live: &[u64], seq: u64, newer_seq: u64) -> bool {
+    let i = live.partition_point(|&s| s <= seq);
+    live.get(i).is_some_and(|&s| s <= newer_seq)
+}
+
+// the map is only ever touched in a handful of lines that can't panic, so a poisoned lock still
+// holds consistent data
+fn lock(snapshots: &Snapshots) -> std::sync::MutexGuard<'_, BTreeMap<u64, usize>> {
+    snapshots
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_registration() {
+        let snapshots = Snapshots::default();
+        let a = Snapshot::new(5, &snapshots);
+        let b = Snapshot::new(5, &snapshots);
+        let c = Snapshot::new(9, &snapshots);
+        assert_eq!(live(&snapshots), vec![5, 9]);
+
+        drop(a);
+        assert_eq!(live(&snapshots), vec![5, 9]);
+        drop(b);
+        drop(c);
+        assert!(live(&snapshots).is_empty());
+    }
+
+    #[test]
+    fn test_is_visible() {
+        let live = [5, 9];
+        assert!(is_visible(&live, 4, 5));
+        assert!(is_visible(&live, 4, 7));
+        assert!(!is_visible(&live, 5, 7));
+        assert!(!is_visible(&live, 9, 12));
+        assert!(is_visible(&live, 8, 12));
+        assert!(!is_visible(&[], 1, 2));
+    }
+}
@@ -0,0 +1,273 @@
+//! one-shot export of the tree into sorted files, for handing off to external tools
+//!
+//! each file starts with a single format version byte, then holds entries sorted by the tree's
+//! comparator, laid out back to back as
+//!
+//! ```text
+//! key len (u32 le) | key | kind (u8) | value len (u32 le) | value
+//! ```
+//!
+//! kind is 1 for a value. a tombstone is kind 0 with nothing after it, so a delete costs a single
+//! byte on top of its key
+//!
+//! there's no index or footer - it's meant to be read start to finish (see `read_sstable`)
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{Entry, LSMTree, LsmError};
+
+// bumped whenever the layout changes, so an old reader rejects a newer file instead of
+// misparsing it. 2 added the kind byte, for tombstones
+const FORMAT_VERSION: u8 = 2;
+
+const TOMBSTONE: u8 = 0;
+const VALUE: u8 = 1;
+
+impl LSMTree {
+    /// consume the tree, writing each level to its own sorted file in `dir` (created if it
+    /// doesn't exist), and return the paths, newest level first
+    ///
+    /// the memtable is flushed first. tombstones are purged, and a key only ends up in the file
+    /// for the level holding its newest version, so the files together hold every live entry
+    /// exactly once. levels with nothing live left don't get a file
+    ///
+    /// # Errors
+    ///
+    /// any error creating `dir` or writing a file. files already written are left in place
+    ///
+    /// # Panics
+    ///
+    /// if the tree is poisoned, or the final flush poisons it
+    pub fn drain_to_sstables<P: AsRef<Path>>(mut self, dir: P) -> io::Result<Vec<PathBuf>> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        self.flush();
+
+        let mut seen = BTreeSet::new();
+        let mut paths = vec![];
+        for (i, level) in self.levels_newest_first() {
+            let mut buf = vec![FORMAT_VERSION];
+            for (key, _) in level.entries() {
+                // older versions of a key sit right behind its newest one, or in a later level
+                if !seen.insert(key.as_slice()) {
+                    continue;
+                }
+                let Some(value) = self.lookup_tree(key).and_then(|r| r.value.clone()) else {
+                    continue;
+                };
+                write_entry(&mut buf, key, Some(&value));
+            }
+
+            if buf.len() > 1 {
+                let path = dir.join(format!("level-{i}.sst"));
+                fs::write(&path, buf)?;
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+}
+
+/// write `entries` to a file at `path` in the same format as `LSMTree::drain_to_sstables`,
+/// tombstones included
+///
+/// they're written in the order given - nothing checks they're sorted
+///
+/// # Errors
+///
+/// any error writing the file
+pub fn write_sstable<P: AsRef<Path>>(path: P, entries: &[Entry]) -> io::Result<()> {
+    let mut buf = vec![FORMAT_VERSION];
+    for (key, value) in entries {
+        write_entry(&mut buf, key, value.as_deref());
+    }
+    fs::write(path, buf)
+}
+
+fn write_entry(buf: &mut Vec<u8>, key: &[u8], value: Option<&[u8]>) {
+    write_field(buf, key);
+    match value {
+        Some(value) => {
+            buf.push(VALUE);
+            write_field(buf, value);
+        }
+        None => buf.push(TOMBSTONE),
+    }
+}
+
+fn write_field(buf: &mut Vec<u8>, bytes: &[u8]) {
+    let len = u32::try_from(bytes.len()).expect("keys and values are under 4GiB");
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+/// read back every entry from a file written by `LSMTree::drain_to_sstables` or `write_sstable`,
+/// in order. a tombstone comes back as a `None` value
+///
+/// # Errors
+///
+/// - `LsmError::Io` if the file can't be read
+/// - `LsmError::UnsupportedVersion` if it was written in a format this build doesn't know
+/// - `LsmError::Corruption` if it's empty, truncated, or has an entry of unknown kind
+pub fn read_sstable<P: AsRef<Path>>(path: P) -> Result<Vec<Entry>, LsmError> {
+    let buf = fs::read(path)?;
+    let Some((&version, mut rest)) = buf.split_first() else {
+        return Err(LsmError::Corruption(
+            "sstable is missing its format version".to_string(),
+        ));
+    };
+    if version != FORMAT_VERSION {
+        return Err(LsmError::UnsupportedVersion(version));
+    }
+
+    let mut entries = vec![];
+    while !rest.is_empty() {
+        let key = read_field(&mut rest)?;
+        let Some((&kind, tail)) = rest.split_first() else {
+            return Err(LsmError::Corruption("truncated sstable".to_string()));
+        };
+        rest = tail;
+        let value = match kind {
+            TOMBSTONE => None,
+            VALUE => Some(read_field(&mut rest)?),
+            _ => {
+                return Err(LsmError::Corruption(format!(
+                    "unknown sstable entry kind {kind}"
+                )));
+            }
+        };
+        entries.push((key, value));
+    }
+    Ok(entries)
+}
+
+/// one length-prefixed key or value off the front of `rest`
+fn read_field(rest: &mut &[u8]) -> Result<Vec<u8>, LsmError> {
+    let truncated = || LsmError::Corruption("truncated sstable".to_string());
+    let (len, tail) = rest.split_first_chunk::<4>().ok_or_else(truncated)?;
+    let len = u32::from_le_bytes(*len) as usize;
+    let bytes = tail.get(..len).ok_or_else(truncated)?;
+    *rest = &tail[len..];
+    Ok(bytes.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drain_to_sstables() {
+        let dir = std::env::temp_dir().join(format!("lsm-drain-{}", std::process::id()));
+        let mut lsm = LSMTree::new(2);
+        for i in 0..20u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(i.to_le_bytes().to_vec()));
+        }
+        // a newer version and a tombstone, both shadowing older versions further down
+        lsm.insert(3u32.to_be_bytes().to_vec(), Some(b"new".to_vec()));
+        lsm.delete(5u32.to_be_bytes().to_vec());
+        lsm.insert(100u32.to_be_bytes().to_vec(), Some(vec![1]));
+        assert!(lsm.populated.len() > 1);
+
+        let expected: Vec<(Vec<u8>, Vec<u8>)> = lsm.iter_live().collect();
+        let paths = lsm.drain_to_sstables(&dir).unwrap();
+        assert!(paths.len() > 1);
+
+        let mut drained = vec![];
+        for path in &paths {
+            let entries = read_sstable(path).unwrap();
+            assert!(entries.is_sorted(), "{path:?}");
+            drained.extend(entries.into_iter().map(|(k, v)| (k, v.unwrap())));
+        }
+        drained.sort();
+        assert_eq!(drained, expected);
+
+        // a truncated file is an error, not a short read
+        let bytes = fs::read(&paths[0]).unwrap();
+        fs::write(&paths[0], &bytes[..bytes.len() - 1]).unwrap();
+        let err = read_sstable(&paths[0]).unwrap_err();
+        assert!(matches!(err, LsmError::Corruption(_)), "{err}");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_format_version() {
+        let dir = std::env::temp_dir().join(format!("lsm-version-{}", std::process::id()));
+        let mut lsm = LSMTree::new(4);
+        lsm.insert(b"a".to_vec(), Some(b"1".to_vec()));
+        let paths = lsm.drain_to_sstables(&dir).unwrap();
+
+        let mut bytes = fs::read(&paths[0]).unwrap();
+        assert_eq!(bytes[0], FORMAT_VERSION);
+        assert_eq!(
+            read_sstable(&paths[0]).unwrap(),
+            vec![(b"a".to_vec(), Some(b"1".to_vec()))]
+        );
+
+        // so is one from before tombstones had a kind byte
+        bytes[0] = 1;
+        fs::write(&paths[0], &bytes).unwrap();
+        let err = read_sstable(&paths[0]).unwrap_err();
+        assert!(matches!(err, LsmError::UnsupportedVersion(1)), "{err}");
+
+        // a file from some future version is rejected outright
+        bytes[0] = FORMAT_VERSION + 1;
+        fs::write(&paths[0], &bytes).unwrap();
+        let err = read_sstable(&paths[0]).unwrap_err();
+        assert!(
+            matches!(err, LsmError::UnsupportedVersion(v) if v == FORMAT_VERSION + 1),
+            "{err}"
+        );
+
+        fs::write(&paths[0], []).unwrap();
+        let err = read_sstable(&paths[0]).unwrap_err();
+        assert!(matches!(err, LsmError::Corruption(_)), "{err}");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_tombstones() {
+        let dir = std::env::temp_dir().join(format!("lsm-tombstones-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let keys: Vec<Vec<u8>> = (0..100u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        let values: Vec<Entry> = keys
+            .iter()
+            .map(|k| (k.clone(), Some(vec![7; 32])))
+            .collect();
+        let tombstones: Vec<Entry> = keys.iter().map(|k| (k.clone(), None)).collect();
+
+        let (values_path, tombstones_path) = (dir.join("values.sst"), dir.join("tombstones.sst"));
+        write_sstable(&values_path, &values).unwrap();
+        write_sstable(&tombstones_path, &tombstones).unwrap();
+        assert_eq!(read_sstable(&values_path).unwrap(), values);
+        assert_eq!(read_sstable(&tombstones_path).unwrap(), tombstones);
+
+        // the version byte, then key length, key and kind for each
+        let size = |path: &Path| fs::metadata(path).unwrap().len();
+        assert_eq!(size(&tombstones_path), 1 + 100 * (4 + 4 + 1));
+        assert!(size(&tombstones_path) * 4 < size(&values_path));
+
+        // a mix of both comes back as written
+        let mixed: Vec<Entry> = values
+            .iter()
+            .zip(&tombstones)
+            .enumerate()
+            .map(|(i, (v, t))| if i % 3 == 0 { t.clone() } else { v.clone() })
+            .collect();
+        write_sstable(&tombstones_path, &mixed).unwrap();
+        assert_eq!(read_sstable(&tombstones_path).unwrap(), mixed);
+
+        // a kind that isn't a value or a tombstone is corruption
+        let mut bytes = fs::read(&tombstones_path).unwrap();
+        bytes[1 + 4 + 4] = 9;
+        fs::write(&tombstones_path, &bytes).unwrap();
+        let err = read_sstable(&tombstones_path).unwrap_err();
+        assert!(matches!(err, LsmError::Corruption(_)), "{err}");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
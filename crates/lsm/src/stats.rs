@@ -0,0 +1,84 @@
+//! reporting types for tuning the tree
+
+use std::time::Duration;
+
+/// min/max/mean/median of a set of sizes, in bytes
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SizeStats {
+    pub min: usize,
+    pub max: usize,
+    pub mean: f64,
+
+    // average of the two middle sizes when there's an even number of them
+    pub median: f64,
+}
+
+impl SizeStats {
+    // usize -> f64 only loses precision past 2^52 bytes, which we're not going to hit
+    #[allow(clippy::cast_precision_loss)]
+    pub(crate) fn from_sizes(mut sizes: Vec<usize>) -> Self {
+        if sizes.is_empty() {
+            return SizeStats::default();
+        }
+
+        sizes.sort_unstable();
+        let n = sizes.len();
+        let mid = n / 2;
+        let median = if n.is_multiple_of(2) {
+            (sizes[mid - 1] + sizes[mid]) as f64 / 2.0
+        } else {
+            sizes[mid] as f64
+        };
+
+        SizeStats {
+            min: sizes[0],
+            max: sizes[n - 1],
+            mean: sizes.iter().sum::<usize>() as f64 / n as f64,
+            median,
+        }
+    }
+}
+
+/// key and value sizes across every live entry in the tree
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SizeDistribution {
+    pub count: usize,
+    pub keys: SizeStats,
+    pub values: SizeStats,
+}
+
+/// what's physically stored in a single level, shadowed versions included
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct LevelStat {
+    pub index: usize,
+
+    // entries with a value
+    pub live: usize,
+    pub tombstones: usize,
+
+    // key + value bytes
+    pub bytes: usize,
+
+    // entries the level can hold before it cascades into the next one
+    pub capacity: usize,
+}
+
+/// everything there is to report about the tree, in one place
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Stats {
+    pub sizes: SizeDistribution,
+    pub levels: Vec<LevelStat>,
+
+    // entries waiting in the memtable
+    pub memtable: usize,
+
+    // writes that hit the `LSMTree::with_write_stall` limit, blocked or rejected
+    pub stall_count: u64,
+
+    // time the blocked ones spent waiting on compaction. a rejected write doesn't wait
+    pub total_stall_duration: Duration,
+}
@@ -0,0 +1,100 @@
+//! async ingest, for loading from a stream without collecting it first
+
+use futures::{Stream, StreamExt};
+
+use crate::LSMTree;
+
+impl LSMTree {
+    /// apply every `(key, value)` from `stream` as it arrives, same as calling `insert` for each
+    ///
+    /// flushes happen inline, like with `insert`. after each one we yield back to the runtime, so
+    /// a long ingest doesn't starve other tasks on the same thread
+    pub async fn ingest_stream<S: Stream<Item = (Vec<u8>, Option<Vec<u8>>)>>(&mut self, stream: S) {
+        let mut stream = std::pin::pin!(stream);
+        while let Some((key, value)) = stream.next().await {
+            self.insert(key, value);
+
+            // the memtable only ends up empty right after a flush
+            if self.memtable.is_empty() {
+                tokio::task::yield_now().await;
+            }
+        }
+    }
+
+    /// `for_each_live`, but yields back to the runtime every `rows` pairs, so a big scan doesn't
+    /// hold up every other task on the same thread until it's done. 0 is treated as 1
+    pub async fn for_each_live_yielding<F: FnMut(&[u8], &[u8])>(&self, rows: usize, mut f: F) {
+        let rows = rows.max(1);
+        let mut seen = 0;
+        for (key, record) in self.merged() {
+            let Some(value) = &record.value else {
+                continue;
+            };
+            f(key, value);
+            seen += 1;
+            if seen % rows == 0 {
+                tokio::task::yield_now().await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[tokio::test]
+    async fn test_ingest_stream() {
+        let mut lsm = LSMTree::new(4);
+        let items = (0..50u32).map(|i| {
+            let value = (i % 5 != 0).then(|| i.to_le_bytes().to_vec());
+            (i.to_be_bytes().to_vec(), value)
+        });
+        lsm.ingest_stream(futures::stream::iter(items)).await;
+
+        assert_eq!(lsm.iter_live().count(), 40);
+        assert_eq!(
+            lsm.get(&7u32.to_be_bytes()),
+            Some(7u32.to_le_bytes().to_vec())
+        );
+        assert_eq!(lsm.get(&10u32.to_be_bytes()), None);
+        assert!(lsm.levels.iter().flatten().count() > 0);
+
+        // an empty stream is a no-op
+        lsm.ingest_stream(futures::stream::empty()).await;
+        assert_eq!(lsm.iter_live().count(), 40);
+    }
+
+    #[tokio::test]
+    async fn test_for_each_live_yielding() {
+        let mut lsm = LSMTree::new(64);
+        for i in 0..10_000u32 {
+            lsm.insert(i.to_be_bytes().to_vec(), Some(vec![1]));
+        }
+
+        let scanned = Cell::new(0);
+        let scan = lsm.for_each_live_yielding(100, |_, _| scanned.set(scanned.get() + 1));
+        let fast = async {
+            tokio::task::yield_now().await;
+            let value = lsm.get(&7u32.to_be_bytes());
+            (value, scanned.get())
+        };
+
+        // both run on the same task, so the fast query only gets a turn when the scan yields
+        let ((), (value, scanned_before_fast)) = tokio::join!(scan, fast);
+        assert_eq!(value, Some(vec![1]));
+        assert!(
+            scanned_before_fast <= 200,
+            "fast query waited for {scanned_before_fast} rows"
+        );
+        assert_eq!(scanned.get(), 10_000);
+    }
+
+    #[test]
+    fn test_for_each_live_yielding_is_send() {
+        fn assert_send<T: Send>(_: &T) {}
+        let lsm = LSMTree::new(4);
+        assert_send(&lsm.for_each_live_yielding(10, |_, _| {}));
+    }
+}
@@ -0,0 +1,130 @@
+//! a typed layer over the byte-level tree - keys as anything that's bytes, values encoded with
+//! serde (as json)
+
+use std::marker::PhantomData;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::LSMTree;
+
+/// an `LSMTree` with typed keys and values. keys are stored as their bytes, so they sort the way
+/// their bytes do, whatever `K`'s own `Ord` says
+pub struct TypedLSM<K, V> {
+    tree: LSMTree,
+    _types: PhantomData<fn(K) -> V>,
+}
+
+impl<K, V> TypedLSM<K, V>
+where
+    K: AsRef<[u8]> + Ord,
+    V: Serialize + DeserializeOwned,
+{
+    #[must_use]
+    pub fn new(tree: LSMTree) -> Self {
+        TypedLSM {
+            tree,
+            _types: PhantomData,
+        }
+    }
+
+    /// # Errors
+    ///
+    /// if `value` can't be encoded, e.g. a map whose keys aren't strings. nothing is written
+    // keys are taken by value like in `LSMTree::insert`, even though we only need their bytes
+    #[allow(clippy::needless_pass_by_value)]
+    pub fn insert(&mut self, key: K, value: &V) -> Result<(), serde_json::Error> {
+        let value = serde_json::to_vec(value)?;
+        self.tree.insert(key.as_ref().to_vec(), Some(value));
+        Ok(())
+    }
+
+    #[allow(clippy::needless_pass_by_value)]
+    pub fn delete(&mut self, key: K) {
+        self.tree.delete(key.as_ref().to_vec());
+    }
+
+    /// # Errors
+    ///
+    /// if the stored bytes don't decode as a `V` - they were written through the byte-level tree,
+    /// or by a `TypedLSM` with a different value type
+    pub fn get(&self, key: &K) -> Result<Option<V>, serde_json::Error> {
+        self.tree
+            .get(key.as_ref())
+            .map(|value| serde_json::from_slice(&value))
+            .transpose()
+    }
+
+    /// the byte-level tree underneath
+    #[must_use]
+    pub fn tree(&self) -> &LSMTree {
+        &self.tree
+    }
+
+    #[must_use]
+    pub fn into_inner(self) -> LSMTree {
+        self.tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        age: u32,
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn test_typed_round_trip() {
+        let mut users: TypedLSM<String, User> = TypedLSM::new(LSMTree::new(2));
+        let ada = User {
+            name: "ada".to_string(),
+            age: 36,
+            tags: vec!["math".to_string()],
+        };
+        let grace = User {
+            name: "grace".to_string(),
+            age: 85,
+            tags: vec![],
+        };
+        users.insert("user:1".to_string(), &ada).unwrap();
+        users.insert("user:2".to_string(), &grace).unwrap();
+        users.insert("user:3".to_string(), &ada).unwrap();
+        users.delete("user:3".to_string());
+
+        // user:1 and user:2 were flushed into a level, the rest is still in the memtable
+        assert!(users.tree().memtable.len() < 3);
+        assert_eq!(users.get(&"user:1".to_string()).unwrap(), Some(ada));
+        assert_eq!(users.get(&"user:2".to_string()).unwrap(), Some(grace));
+        assert_eq!(users.get(&"user:3".to_string()).unwrap(), None);
+        assert_eq!(users.get(&"user:4".to_string()).unwrap(), None);
+
+        let tree = users.into_inner();
+        assert_eq!(tree.iter_live().count(), 2);
+    }
+
+    #[test]
+    fn test_typed_bad_values() {
+        // bytes written through the byte-level tree, that were never json
+        let mut tree = LSMTree::new(4);
+        tree.insert(b"raw".to_vec(), Some(vec![0xff, 0x00]));
+        tree.insert(b"number".to_vec(), Some(b"7".to_vec()));
+        let users: TypedLSM<&str, User> = TypedLSM::new(tree);
+        assert!(users.get(&"raw").is_err());
+        assert!(users.get(&"number").is_err());
+        assert_eq!(users.get(&"missing").unwrap(), None);
+
+        // a map with non-string keys has no json encoding, and nothing gets written
+        let mut maps: TypedLSM<&str, BTreeMap<Vec<u8>, u32>> = TypedLSM::new(users.into_inner());
+        let map = BTreeMap::from([(vec![1], 1)]);
+        assert!(maps.insert("map", &map).is_err());
+        assert_eq!(maps.tree().get(b"map"), None);
+        assert_eq!(maps.get(&"map").unwrap(), None);
+    }
+}